@@ -0,0 +1,113 @@
+use actix_web::{delete, get, HttpResponse, post, web};
+use serde::Deserialize;
+
+use crate::api::api_error::ApiError;
+use crate::api::authentication::AdminUser;
+use crate::api::stripe_hook;
+use crate::data_structs::requests::promotion_request::{NewCoupon, NewPromotionCode};
+use crate::stripe_util::RedeemedPromotionCode;
+use crate::SharedResources;
+
+#[post("/admin/coupons")]
+async fn create_coupon(data: web::Data<SharedResources>, admin: AdminUser, info: web::Json<NewCoupon>) -> Result<HttpResponse, ApiError> {
+    let coupon = info.into_inner();
+    match (coupon.percent_off, coupon.amount_off) {
+        (Some(percent_off), None) if percent_off > 0.0 && percent_off <= 100.0 => {},
+        (None, Some(amount_off)) if amount_off > 0 => {},
+        _ => return Err(ApiError::bad_request("Coupons need either a percent_off between 0 and 100 or a positive amount_off"))
+    }
+
+    let coupon = data.stripe_handler.create_coupon(&coupon).await?;
+    println!("{} created coupon {}", admin.kerberos_username, coupon.id);
+    Ok(HttpResponse::Ok().json(coupon))
+}
+
+#[get("/admin/coupons")]
+async fn list_coupons(data: web::Data<SharedResources>, _admin: AdminUser) -> Result<HttpResponse, ApiError> {
+    let coupons = data.stripe_handler.list_coupons().await?;
+    Ok(HttpResponse::Ok().json(coupons))
+}
+
+/// Stops the coupon, and with it every promotion code for it, from being redeemed again
+#[delete("/admin/coupons/{coupon_id}")]
+async fn deactivate_coupon(data: web::Data<SharedResources>, admin: AdminUser, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let coupon_id = path.into_inner();
+    data.stripe_handler.delete_coupon(&coupon_id).await?;
+    println!("{} deactivated coupon {}", admin.kerberos_username, coupon_id);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/admin/promotion-codes")]
+async fn create_promotion_code(data: web::Data<SharedResources>, admin: AdminUser, info: web::Json<NewPromotionCode>) -> Result<HttpResponse, ApiError> {
+    let promotion_code = info.into_inner();
+    if promotion_code.code.is_empty() || promotion_code.code.len() > 64
+            || !promotion_code.code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(ApiError::bad_request("Promotion codes must be 1 to 64 letters, numbers, dashes or underscores"));
+    }
+    if promotion_code.campaign.as_ref().is_some_and(|campaign| campaign.is_empty() || campaign.len() > 64) {
+        return Err(ApiError::bad_request("Campaign names must be between 1 and 64 characters long"));
+    }
+
+    let promotion_code = data.stripe_handler.create_promotion_code(&promotion_code).await?;
+    data.database.save_promotion_code(
+        &RedeemedPromotionCode::from(promotion_code.clone()),
+        Some(&admin.kerberos_username)
+    ).await?;
+    println!("{} created promotion code {}", admin.kerberos_username, promotion_code.code);
+    Ok(HttpResponse::Ok().json(promotion_code))
+}
+
+#[get("/admin/promotion-codes")]
+async fn list_promotion_codes(data: web::Data<SharedResources>, _admin: AdminUser) -> Result<HttpResponse, ApiError> {
+    let promotion_codes = data.stripe_handler.list_promotion_codes().await?;
+    Ok(HttpResponse::Ok().json(promotion_codes))
+}
+
+#[delete("/admin/promotion-codes/{promotion_code_id}")]
+async fn deactivate_promotion_code(data: web::Data<SharedResources>, admin: AdminUser, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let promotion_code = data.stripe_handler.deactivate_promotion_code(&path.into_inner()).await?;
+    data.database.save_promotion_code(&RedeemedPromotionCode::from(promotion_code.clone()), None).await?;
+    println!("{} deactivated promotion code {}", admin.kerberos_username, promotion_code.code);
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Redemptions of the promotion codes in successful checkouts, per campaign
+#[get("/admin/campaigns")]
+async fn campaign_reports(data: web::Data<SharedResources>, _admin: AdminUser) -> Result<HttpResponse, ApiError> {
+    let reports = data.database.get_campaign_reports().await?;
+    Ok(HttpResponse::Ok().json(reports))
+}
+
+#[derive(Deserialize)]
+struct WebhookEventsQuery {
+    /// only return events whose last attempt failed
+    failed: Option<bool>,
+    limit: Option<u32>
+}
+
+/// The most recently received stripe webhook events and how processing them went
+#[get("/admin/webhook-events")]
+async fn webhook_events(data: web::Data<SharedResources>, _admin: AdminUser, info: web::Query<WebhookEventsQuery>) -> Result<HttpResponse, ApiError> {
+    let limit = info.limit.unwrap_or(50).clamp(1, 200);
+    let events = data.database.get_webhook_events(info.failed.unwrap_or(false), limit).await?;
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Processes a stored webhook event again, even if it was processed before
+#[post("/admin/webhook-events/{event_id}/replay")]
+async fn replay_webhook_event(data: web::Data<SharedResources>, admin: AdminUser, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let event_id = path.into_inner();
+    data.database.reset_webhook_event(&event_id).await?;
+    println!("{} replayed webhook event {}", admin.kerberos_username, event_id);
+    stripe_hook::process_webhook_event(&data, &event_id).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Retries every webhook event that ran out of attempts
+#[post("/admin/webhook-events/replay-failed")]
+async fn replay_failed_webhook_events(data: web::Data<SharedResources>, admin: AdminUser) -> Result<HttpResponse, ApiError> {
+    let count = data.database.reset_failed_webhook_events().await?;
+    println!("{} replayed {} failed webhook events", admin.kerberos_username, count);
+    stripe_hook::retry_webhook_events(&data).await?;
+    Ok(HttpResponse::Ok().finish())
+}
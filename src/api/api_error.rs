@@ -0,0 +1,159 @@
+use std::fmt::{Display, Formatter};
+
+use actix_web::{HttpRequest, HttpResponse, ResponseError, web};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use rand::Rng;
+
+use crate::data_structs::responses::error_response::{ErrorCode, ErrorResponse, SignedErrorResponse};
+use crate::database::DatabaseError;
+use crate::encrypted_signing::Ed25519SecretKey;
+use crate::SharedResources;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The error type returned by every api handler. It is rendered as an [ErrorResponse] so that
+/// all failures share one json shape, and carries a random request id which is also logged so a
+/// reported error can be matched up with the server logs.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    response: ErrorResponse,
+}
+
+impl ApiError {
+
+    pub fn new(status: StatusCode, code: ErrorCode, message: impl Into<String>) -> ApiError {
+        let response = ErrorResponse::new(
+            code,
+            message.into(),
+            generate_request_id(),
+            chrono::Local::now().timestamp()
+        );
+        return ApiError { status, response };
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> ApiError {
+        Self::new(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> ApiError {
+        Self::new(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> ApiError {
+        Self::new(StatusCode::FORBIDDEN, ErrorCode::Forbidden, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> ApiError {
+        Self::new(StatusCode::NOT_FOUND, ErrorCode::NotFound, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> ApiError {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, message)
+    }
+
+    /// Renders this error signed with the server's ed25519 key, in the same shape as the other
+    /// signed responses of the app api.
+    pub fn signed_response(&self, private_key: &Ed25519SecretKey) -> HttpResponse {
+        let signature = private_key.sign(&self.response);
+        HttpResponse::build(self.status)
+            .insert_header((REQUEST_ID_HEADER, self.response.request_id.as_str()))
+            .json(SignedErrorResponse {
+                data: self.response.clone(),
+                signature
+            })
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {:?}: {}", self.response.request_id, self.response.code, self.response.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status)
+            .insert_header((REQUEST_ID_HEADER, self.response.request_id.as_str()))
+            .json(&self.response)
+    }
+}
+
+impl From<stripe::StripeError> for ApiError {
+    fn from(err: stripe::StripeError) -> Self {
+        let api_error = match &err {
+            // stripe rejected the request itself, e.g. a promotion code that already exists
+            stripe::StripeError::Stripe(request_error) => ApiError::bad_request(
+                request_error.message.clone().unwrap_or_else(|| "Stripe rejected the request".to_string())),
+            _ => ApiError::new(StatusCode::BAD_GATEWAY, ErrorCode::ServiceUnavailable,
+                               "Stripe is temporarily unavailable, please try again")
+        };
+        eprintln!("[{}] Stripe error: {}", api_error.response.request_id, err);
+        api_error
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(err: DatabaseError) -> Self {
+        // don't leak driver internals to the client
+        let api_error = match &err {
+            DatabaseError::NotFound(message) => ApiError::not_found(message.as_str()),
+            DatabaseError::Forbidden(_) => ApiError::forbidden("You do not have access to this resource"),
+            DatabaseError::InsufficientCredits(message) => ApiError::new(StatusCode::PAYMENT_REQUIRED, ErrorCode::InsufficientCredits,
+                                                                         message.as_str()),
+            DatabaseError::Conflict(_) => ApiError::new(StatusCode::CONFLICT, ErrorCode::Conflict,
+                                                        "The resource already exists"),
+            DatabaseError::ConstraintViolation(_) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, ErrorCode::InvalidRequest,
+                                                                   "The request references data that does not exist"),
+            DatabaseError::ConnectionLost(_) => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, ErrorCode::ServiceUnavailable,
+                                                              "The database is temporarily unavailable, please try again"),
+            DatabaseError::Unavailable(_) => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, ErrorCode::ServiceUnavailable,
+                                                           "A service we depend on is temporarily unavailable, please try again"),
+            DatabaseError::Migration(_) | DatabaseError::Query(_) => ApiError::internal("An internal database error occurred"),
+        };
+        eprintln!("[{}] {}", api_error.response.request_id, err);
+        api_error
+    }
+}
+
+/// Middleware for the app api scope which re-renders any [ApiError] as a signed response, so the
+/// desktop client can trust error replies the same way it trusts successful ones.
+pub async fn sign_error_responses(req: ServiceRequest, next: Next<impl MessageBody + 'static>)
+        -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let data = req.app_data::<web::Data<SharedResources>>().cloned();
+    let res = next.call(req).await?;
+
+    let api_error = res.response().error().and_then(|err| err.as_error::<ApiError>());
+    let signed_response = match (api_error, data) {
+        (Some(api_error), Some(data)) => Some(api_error.signed_response(&data.private_key)),
+        _ => None
+    };
+
+    Ok(match signed_response {
+        Some(signed_response) => res.into_response(signed_response).map_into_right_body(),
+        None => res.map_into_left_body()
+    })
+}
+
+/// Used by the json extractor so malformed request bodies get the same error envelope.
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::bad_request(format!("Invalid request body: {}", err)).into()
+}
+
+/// Used by the query extractor so malformed query strings get the same error envelope.
+pub fn query_error_handler(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::bad_request(format!("Invalid query string: {}", err)).into()
+}
+
+fn generate_request_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..16).map(|_| format!("{:x}", rng.gen_range(0..16))).collect()
+}
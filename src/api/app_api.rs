@@ -1,14 +1,16 @@
 use actix_web::{get, HttpRequest, HttpResponse, post, Responder, web};
-use crate::data_structs::app_config::UserApplicationSettings;
+use actix_web::http::StatusCode;
 
+use crate::api::api_error::ApiError;
+use crate::api::authentication::{AppSession, SESSION_TOKEN_HEADER};
 use crate::data_structs::device_meta::DeviceMeta;
-use crate::data_structs::grant_level::GrantLevel;
 use crate::data_structs::requests::application_start::ApplicationStart;
 use crate::data_structs::requests::application_stopped::ApplicationStopped;
 use crate::data_structs::requests::email_send_request::EmailSendRequest;
 use crate::data_structs::requests::registration_notification::RegistrationNotification;
 use crate::data_structs::requests::session_ping::SessionPing;
 use crate::data_structs::responses::app_start_permission::{ApplicationStartPermission, SignedApplicationStartPermission};
+use crate::data_structs::responses::error_response::ErrorCode;
 use crate::data_structs::responses::status_response::{SignedStatusResponse, StatusResponse};
 use crate::SharedResources;
 
@@ -24,25 +26,24 @@ async fn debug_ping() -> impl Responder {
 //  3. if they buy premium that same token becomes premium
 
 #[post("/app-started")]
-pub async fn app_start(data: web::Data<SharedResources>, req: HttpRequest, payload: web::Json<ApplicationStart>) -> impl Responder {
+pub async fn app_start(data: web::Data<SharedResources>, req: HttpRequest, payload: web::Json<ApplicationStart>) -> Result<HttpResponse, ApiError> {
     let mut start_data: ApplicationStart = payload.into_inner();
     let database = &data.get_ref().database;
 
-    let opt_kerberos_username = database.is_authenticated(
-        &start_data.license_key
-    ).await;
+    let license_key = database.find_license_key(&start_data.license_key).await?
+        .ok_or_else(|| ApiError::unauthorized("Invalid license key"))?;
 
-    if opt_kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Unauthorized"); //todo, fix this
-    }
-
-    let kerberos_username = opt_kerberos_username.unwrap();
+    let kerberos_username = license_key.kerberos_username.clone();
 
     // add client ip to the request
     start_data.device_meta.ip = Option::from(req.connection_info().realip_remote_addr().unwrap().to_string()); //todo test
 
+    if !database.record_license_key_use(&license_key, &start_data.device_meta.fingerprint()).await? {
+        return Err(ApiError::forbidden("This license key is bound to a different device"));
+    }
+
     // check if there is another running session first
-    let active_session = database.has_active_session(&kerberos_username).await;
+    let active_session = database.has_active_session(&kerberos_username).await?;
     if active_session.is_some() {
         let device: DeviceMeta = active_session.unwrap();
         let mut message = format!("You already have an active session running on your {} device", device.os).to_string();
@@ -54,145 +55,103 @@ pub async fn app_start(data: web::Data<SharedResources>, req: HttpRequest, paylo
         message.push_str(to_append.as_str());
         message.push_str(" If you believe this is an error, please wait up to 1 minute and try \
                                  again. Otherwise, please contact us for support.");
-        return HttpResponse::BadRequest().json(message);  //todo, fix this
+        return Err(ApiError::new(StatusCode::CONFLICT, ErrorCode::SessionAlreadyActive, message));
     }
 
-    let user = database.get_user(&kerberos_username).await.unwrap();
-
-    let grant_type = {
-        if user.current_credits == 0 && user.demo_expired_at.is_some() {
-            GrantLevel::Expired
-        } else if user.current_credits == 0 {
-            GrantLevel::Demo
-        } else {
-            GrantLevel::Full //todo: redo with credits now
-        }
-    };
-
     // grab settings
-    let settings = match database.get_user_application_settings(&user.kerberos_username).await {
-        Some(settings) => settings,
-        None => UserApplicationSettings::default()
-    };
-    // create session
-    let session_id = data.database.create_session(&start_data, &kerberos_username, &grant_type, !settings.real_registrations).await;
+    let settings = database.get_user_application_settings(&kerberos_username).await?;
+    // create session, this decides what the session is granted as well
+    let session = data.database.create_session(&start_data, &kerberos_username, license_key.license_key_id, !settings.real_registrations, &data.demo_policy).await?;
+
+    let session_token = data.jwt_secret.issue_session_token(&kerberos_username, session.session_id);
 
     let response = ApplicationStartPermission::new(
         kerberos_username,
-        grant_type,
         settings,
-        session_id,
+        session,
+        session_token,
         chrono::Local::now().timestamp()
     );
 
     let signed_str = data.private_key.sign(&response);
 
-    HttpResponse::Ok().json(SignedApplicationStartPermission {
+    Ok(HttpResponse::Ok().json(SignedApplicationStartPermission {
         data: response,
         signature: signed_str
-    })
+    }))
 }
 
 #[post("/app-stopped")]
-async fn app_stop(data: web::Data<SharedResources>, payload: web::Json<ApplicationStopped>) -> impl Responder {
+async fn app_stop(data: web::Data<SharedResources>, session: AppSession, payload: web::Json<ApplicationStopped>) -> Result<HttpResponse, ApiError> {
     let stop_data: ApplicationStopped = payload.into_inner();
+    session.ensure_session(stop_data.session_id)?;
 
-    let opt_kerberos_username = data.database.is_authenticated(
-        &stop_data.license_key
-    ).await;
-
-    if opt_kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Unauthorized"); //todo, fix this
-    }
+    data.database.end_session(&session.kerberos_username, &stop_data).await?;
 
-    return match data.database.end_session(&stop_data).await {
-        Ok(_) => {
-            let response = StatusResponse::new(
-                opt_kerberos_username.unwrap(),
-                "OK".to_string(),
-                chrono::Local::now().timestamp()
-            );
-            let signed_str = data.private_key.sign(&response);
-            HttpResponse::Ok().json(SignedStatusResponse {
-                data: response,
-                signature: signed_str
-            })
-        },
-        Err(_) => HttpResponse::BadRequest().json("Invalid session id") //todo, fix this
-    };
+    let response = StatusResponse::new(
+        session.kerberos_username,
+        "OK".to_string(),
+        chrono::Local::now().timestamp()
+    );
+    let signed_str = data.private_key.sign(&response);
+    return Ok(HttpResponse::Ok().json(SignedStatusResponse {
+        data: response,
+        signature: signed_str
+    }));
 }
 
 #[post("/ping")]
-async fn ping(data: web::Data<SharedResources>, payload: web::Json<SessionPing>) -> impl Responder {
+async fn ping(data: web::Data<SharedResources>, session: AppSession, payload: web::Json<SessionPing>) -> Result<HttpResponse, ApiError> {
     let ping_data: SessionPing = payload.into_inner();
+    session.ensure_session(ping_data.session_id)?;
 
-    let opt_kerberos_username = data.database.is_authenticated(
-        &ping_data.license_key
-    ).await;
+    data.database.session_ping(&session.kerberos_username, &ping_data).await?;
 
-    if opt_kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Unauthorized");
-    }
+    // the session is still alive, so hand out a renewed token to keep it going
+    let session_token = data.jwt_secret.issue_session_token(&session.kerberos_username, session.session_id);
 
-    return match data.database.session_ping(&ping_data).await {
-        Ok(_) => {
-            let response = StatusResponse::new(
-                opt_kerberos_username.unwrap(),
-                "OK".to_string(),
-                chrono::Local::now().timestamp()
-            );
-            let signed_str = data.private_key.sign(&response);
-            HttpResponse::Ok().json(SignedStatusResponse {
-                data: response,
-                signature: signed_str
-            })
-        },
-        Err(_) => HttpResponse::BadRequest().json("Invalid session id") //todo, fix this
-    };
+    let response = StatusResponse::new(
+        session.kerberos_username,
+        "OK".to_string(),
+        chrono::Local::now().timestamp()
+    );
+    let signed_str = data.private_key.sign(&response);
+    return Ok(HttpResponse::Ok()
+        .insert_header((SESSION_TOKEN_HEADER, session_token))
+        .json(SignedStatusResponse {
+            data: response,
+            signature: signed_str
+        }));
 }
 
 #[post("/course-registered")]
-async fn course_registered(data: web::Data<SharedResources>, payload: web::Json<RegistrationNotification>) -> impl Responder {
+async fn course_registered(data: web::Data<SharedResources>, session: AppSession, payload: web::Json<RegistrationNotification>) -> Result<HttpResponse, ApiError> {
     let reg_notif_data: RegistrationNotification = payload.into_inner();
     let database = &data.get_ref().database;
+    session.ensure_session(reg_notif_data.session_id)?;
 
-    let opt_kerberos_username = data.database.is_authenticated(
-        &reg_notif_data.license_key
-    ).await;
+    let kerberos_username = session.kerberos_username;
 
-    if opt_kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Unauthorized"); //todo, fix this
+    if reg_notif_data.idempotency_key.as_ref().is_some_and(|key| key.is_empty() || key.len() > 64) {
+        return Err(ApiError::bad_request("Idempotency keys must be between 1 and 64 characters long"));
     }
 
-    let kerberos_username = opt_kerberos_username.unwrap();
-
-    let user = database.get_user(&kerberos_username).await.unwrap();
-
-    return match database.mark_course_registered(
+    // note: credits or the trial are used up in the mark_course_registered method
+    let registration = database.mark_course_registered(
         &kerberos_username,
-        reg_notif_data.session_id,
-        reg_notif_data.timestamp,
-        reg_notif_data.course_id, reg_notif_data.course_section.as_str())
-        .await {
-            true => {
-                // first if this is a demo user, mark demo over
-                if user.demo_expired_at.is_none() {
-                    database.mark_demo_over(&kerberos_username).await;
-                }
-
-                // note: credits are subtracted in the mark_course_registered method
-
-                let response = StatusResponse::new(
-                    kerberos_username,
-                    "OK".to_string(),
-                    chrono::Local::now().timestamp()
-                );
-                let signed_str = data.private_key.sign(&response);
-                HttpResponse::Ok().json(SignedStatusResponse {
-                    data: response,
-                    signature: signed_str
-                })
-            },
-            false => HttpResponse::BadRequest().json("Invalid session id") //todo, fix this
-        };
+        &reg_notif_data,
+        chrono::Local::now().timestamp())
+        .await?;
+
+    // a retried notification gets the exact same signed response as the original one
+    let response = StatusResponse::new(
+        kerberos_username,
+        "OK".to_string(),
+        registration.response_timestamp
+    );
+    let signed_str = data.private_key.sign(&response);
+    return Ok(HttpResponse::Ok().json(SignedStatusResponse {
+        data: response,
+        signature: signed_str
+    }));
 }
\ No newline at end of file
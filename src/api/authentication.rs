@@ -0,0 +1,136 @@
+use actix_web::{FromRequest, HttpRequest, web};
+use actix_web::dev::Payload;
+use futures::future::{LocalBoxFuture, ready, Ready};
+
+use crate::api::api_error::ApiError;
+use crate::api::session_cookies::{ACCESS_TOKEN_COOKIE, verify_csrf};
+use crate::encrypted_signing::JwtClaims;
+use crate::SharedResources;
+
+/// A web dashboard user whose access token was verified and has not been revoked.
+/// Taking this as a handler argument makes the route require a valid, unexpired token.
+///
+/// The token is normally read from the access token cookie, in which case state changing
+/// requests must also pass the csrf check. Api clients may instead send it in the
+/// `Authorization` header, which a browser never attaches on its own, so no csrf check is needed.
+#[derive(Debug)]
+pub struct AuthenticatedUser {
+    pub kerberos_username: String,
+    pub claims: JwtClaims,
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { authenticate(&req).await })
+    }
+}
+
+async fn authenticate(req: &HttpRequest) -> Result<AuthenticatedUser, ApiError> {
+    let data = req.app_data::<web::Data<SharedResources>>()
+        .ok_or_else(|| ApiError::internal("Shared resources are not configured"))?;
+
+    let token = match req.headers().get("Authorization") {
+        Some(auth_header) => {
+            let auth_str = auth_header.to_str()
+                .map_err(|_| ApiError::unauthorized("Invalid authorization token"))?;
+            // accept the token with or without the bearer scheme
+            auth_str.strip_prefix("Bearer ").unwrap_or(auth_str).to_string()
+        },
+        None => {
+            let cookie = req.cookie(ACCESS_TOKEN_COOKIE)
+                .ok_or_else(|| ApiError::unauthorized("No authorization key supplied"))?;
+            verify_csrf(req)?;
+            cookie.value().to_string()
+        }
+    };
+
+    let claims = data.jwt_secret.verify_token(&token)
+        .ok_or_else(|| ApiError::unauthorized("Invalid or expired authorization token"))?;
+
+    if data.database.is_token_revoked(&claims).await? {
+        return Err(ApiError::unauthorized("This session has been logged out"));
+    }
+
+    Ok(AuthenticatedUser {
+        kerberos_username: claims.sub.clone(),
+        claims,
+    })
+}
+
+/// An [AuthenticatedUser] who is listed under `admin-users` in the config
+#[derive(Debug)]
+pub struct AdminUser {
+    pub kerberos_username: String,
+}
+
+impl FromRequest for AdminUser {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let user = authenticate(&req).await?;
+            let data = req.app_data::<web::Data<SharedResources>>()
+                .ok_or_else(|| ApiError::internal("Shared resources are not configured"))?;
+            if !data.admin_users.contains(&user.kerberos_username) {
+                return Err(ApiError::forbidden("Only admins can do this"));
+            }
+            Ok(AdminUser {
+                kerberos_username: user.kerberos_username
+            })
+        })
+    }
+}
+
+pub const SESSION_TOKEN_HEADER: &str = "X-Session-Token";
+
+/// A desktop app launch session, authenticated by the session token `/app-started` handed out
+/// in the `X-Session-Token` header. Verifying it needs no database lookup.
+#[derive(Debug)]
+pub struct AppSession {
+    pub kerberos_username: String,
+    pub session_id: i64,
+}
+
+impl AppSession {
+
+    /// Rejects requests about a session other than the one the token was issued for
+    pub fn ensure_session(&self, session_id: i64) -> Result<(), ApiError> {
+        if self.session_id != session_id {
+            return Err(ApiError::forbidden("The session token was not issued for this session"));
+        }
+        Ok(())
+    }
+}
+
+impl FromRequest for AppSession {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate_session(req))
+    }
+}
+
+fn authenticate_session(req: &HttpRequest) -> Result<AppSession, ApiError> {
+    let data = req.app_data::<web::Data<SharedResources>>()
+        .ok_or_else(|| ApiError::internal("Shared resources are not configured"))?;
+
+    let token = req.headers().get(SESSION_TOKEN_HEADER)
+        .ok_or_else(|| ApiError::unauthorized("No session token supplied"))?
+        .to_str()
+        .map_err(|_| ApiError::unauthorized("Invalid session token"))?;
+
+    let claims = data.jwt_secret.verify_session_token(token)
+        .ok_or_else(|| ApiError::unauthorized("Invalid or expired session token"))?;
+
+    Ok(AppSession {
+        kerberos_username: claims.sub,
+        session_id: claims.sid,
+    })
+}
@@ -1,3 +1,8 @@
+pub mod api_error;
+pub mod authentication;
+pub mod replay_guard;
+pub mod session_cookies;
+pub mod admin_api;
 pub mod app_api;
 pub mod web_api;
 pub mod stripe_hook;
\ No newline at end of file
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::web;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::api::api_error::ApiError;
+use crate::api::authentication::SESSION_TOKEN_HEADER;
+use crate::data_structs::responses::error_response::ErrorCode;
+use crate::encrypted_signing::constant_time_eq;
+use crate::SharedResources;
+
+pub const REQUEST_TIMESTAMP_HEADER: &str = "X-Request-Timestamp";
+pub const REQUEST_NONCE_HEADER: &str = "X-Request-Nonce";
+pub const REQUEST_SIGNATURE_HEADER: &str = "X-Request-Signature";
+
+// the app start is where the app gets its signing key, so it's the one request that can't be signed
+const APP_START_PATH: &str = "/app-started";
+
+/// How strictly the replay checks are applied, so they can be rolled out before every client
+/// sends the headers
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy)]
+pub enum ReplayProtectionMode {
+    Off,
+    /// requests that fail a check are logged but still let through
+    Report,
+    Enforce,
+}
+
+impl ReplayProtectionMode {
+    pub fn parse(mode: &str) -> Option<ReplayProtectionMode> {
+        match mode {
+            "off" => Some(ReplayProtectionMode::Off),
+            "report" => Some(ReplayProtectionMode::Report),
+            "enforce" => Some(ReplayProtectionMode::Enforce),
+            _ => None
+        }
+    }
+}
+
+/// Rejects app api requests that are stale or were already seen.
+///
+/// Every request carries a unix `X-Request-Timestamp` which must be within the window of the
+/// server's clock, and a random `X-Request-Nonce` which is remembered for as long as the
+/// timestamp is acceptable, so a captured request can't be sent again. Every request after the
+/// app start must also send an `X-Request-Signature`, the hex HMAC-SHA256 of
+/// `method\npath\ntimestamp\nnonce\nbody` keyed with the session's `request_signing_key`, which
+/// stops the timestamp and nonce from being swapped out on a captured body. The key is only ever
+/// sent once, inside the signed start permission, so it can't be read off a captured request the
+/// way the session token can.
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct ReplayGuard {
+    mode: ReplayProtectionMode,
+    window: i64,
+    /// nonce -> the time after which its request would be rejected as stale anyway
+    nonces: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl ReplayGuard {
+
+    /// `window` is how many seconds a request timestamp may be off from the server clock
+    pub fn new(mode: ReplayProtectionMode, window: i64) -> ReplayGuard {
+        return ReplayGuard {
+            mode,
+            window,
+            nonces: Arc::new(Mutex::new(HashMap::new()))
+        };
+    }
+
+    /// Forgets nonces whose requests would be rejected for their timestamp by now
+    pub fn prune_nonces(&self) {
+        let now = chrono::Local::now().timestamp();
+        self.nonces.lock().unwrap().retain(|_, expires_at| *expires_at >= now);
+    }
+
+    async fn check(&self, data: &SharedResources, req: &mut ServiceRequest) -> Result<(), ApiError> {
+        let timestamp = header_str(req, REQUEST_TIMESTAMP_HEADER)
+            .ok_or_else(|| ApiError::unauthorized("Missing request timestamp"))?
+            .parse::<i64>()
+            .map_err(|_| ApiError::unauthorized("Invalid request timestamp"))?;
+        let now = chrono::Local::now().timestamp();
+        if (now - timestamp).abs() > self.window {
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, ErrorCode::RequestReplayed,
+                                     "The request timestamp is outside of the allowed window"));
+        }
+
+        let nonce = header_str(req, REQUEST_NONCE_HEADER)
+            .ok_or_else(|| ApiError::unauthorized("Missing request nonce"))?;
+        if nonce.len() < 16 || nonce.len() > 128 || !nonce.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(ApiError::unauthorized("Invalid request nonce"));
+        }
+
+        if !req.path().ends_with(APP_START_PATH) {
+            Self::check_signature(data, req, timestamp, &nonce).await?;
+        }
+
+        // only remember the nonce once everything else checked out
+        let mut nonces = self.nonces.lock().unwrap();
+        if nonces.contains_key(&nonce) {
+            return Err(ApiError::new(StatusCode::CONFLICT, ErrorCode::RequestReplayed,
+                                     "This request has already been processed"));
+        }
+        nonces.insert(nonce, timestamp + self.window);
+        Ok(())
+    }
+
+    async fn check_signature(data: &SharedResources, req: &mut ServiceRequest, timestamp: i64, nonce: &str) -> Result<(), ApiError> {
+        let signature = header_str(req, REQUEST_SIGNATURE_HEADER)
+            .ok_or_else(|| ApiError::unauthorized("Missing request signature"))?;
+        let session_token = header_str(req, SESSION_TOKEN_HEADER)
+            .ok_or_else(|| ApiError::unauthorized("Signed requests must include a session token"))?;
+        let claims = data.jwt_secret.verify_session_token(&session_token)
+            .ok_or_else(|| ApiError::unauthorized("Invalid or expired session token"))?;
+        let signing_key = data.database.get_request_signing_key(claims.sid).await?
+            .ok_or_else(|| ApiError::unauthorized("This session can't sign requests, please restart the app"))?;
+
+        // the body has to be read to sign it, put it back for the handler afterwards
+        let body = req.extract::<web::Bytes>().await
+            .map_err(|_| ApiError::bad_request("Could not read the request body"))?;
+        req.set_payload(body.clone().into());
+
+        let expected = sign_request(&signing_key, req.method().as_str(), req.path(), timestamp, nonce, &body);
+        if !constant_time_eq(expected.as_bytes(), signature.to_lowercase().as_bytes()) {
+            return Err(ApiError::unauthorized("Invalid request signature"));
+        }
+        Ok(())
+    }
+}
+
+/// The hex HMAC-SHA256 a request has to carry in its `X-Request-Signature` header
+pub fn sign_request(signing_key: &str, method: &str, path: &str, timestamp: i64, nonce: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes()).unwrap();
+    mac.update(format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).as_bytes());
+    mac.update(body);
+    mac.finalize().into_bytes().iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn header_str(req: &ServiceRequest, name: &str) -> Option<String> {
+    req.headers().get(name)
+        .and_then(|header| header.to_str().ok())
+        .map(|header| header.to_string())
+}
+
+/// Middleware for the app api scope applying the [ReplayGuard] checks to every state changing request
+pub async fn reject_replayed_requests(mut req: ServiceRequest, next: Next<impl MessageBody + 'static>)
+        -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let data = match req.app_data::<web::Data<SharedResources>>() {
+        Some(data) if data.replay_guard.mode != ReplayProtectionMode::Off => data.clone(),
+        _ => return next.call(req).await.map(|res| res.map_into_left_body())
+    };
+    let guard = &data.replay_guard;
+    if req.method() == Method::GET || req.method() == Method::HEAD || req.method() == Method::OPTIONS {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    }
+
+    if let Err(err) = guard.check(&data, &mut req).await {
+        if guard.mode == ReplayProtectionMode::Enforce {
+            return Ok(req.error_response(err).map_into_right_body());
+        }
+        println!("Replay protection (report only) would have rejected {} {}: {}", req.method(), req.path(), err);
+    }
+
+    next.call(req).await.map(|res| res.map_into_left_body())
+}
@@ -0,0 +1,113 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::cookie::time::Duration;
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+
+use crate::api::api_error::ApiError;
+use crate::encrypted_signing::{constant_time_eq, generate_random_token};
+
+pub const ACCESS_TOKEN_COOKIE: &str = "access_token";
+pub const REFRESH_TOKEN_COOKIE: &str = "refresh_token";
+pub const CSRF_TOKEN_COOKIE: &str = "csrf_token";
+pub const OAUTH_STATE_COOKIE: &str = "oauth_state";
+pub const CSRF_TOKEN_HEADER: &str = "X-CSRF-Token";
+
+// every web dashboard route lives under this path, the tokens aren't needed anywhere else
+const COOKIE_PATH: &str = "/api/web/v1";
+// the csrf token has to be readable by the frontend's scripts, which aren't served from the api
+const CSRF_COOKIE_PATH: &str = "/";
+
+/// Builds the cookies that carry a web dashboard login.
+///
+/// The access and refresh tokens are `HttpOnly` so scripts on the page can never read them.
+/// Alongside them a csrf token is set in a cookie the frontend *can* read, which it has to echo
+/// back in the `X-CSRF-Token` header on state changing requests (double submit). Another site
+/// can make the browser send our cookies but can't read them, so it can't produce the header.
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct SessionCookies {
+    secure: bool,
+    refresh_token_lifetime: i64,
+}
+
+impl SessionCookies {
+
+    /// `refresh_token_lifetime` is how many seconds a refresh token stays valid for. `secure`
+    /// should only ever be turned off for local development over plain http.
+    pub fn new(secure: bool, refresh_token_lifetime: i64) -> SessionCookies {
+        return SessionCookies {
+            secure,
+            refresh_token_lifetime
+        };
+    }
+
+    pub fn refresh_token_lifetime(&self) -> i64 {
+        self.refresh_token_lifetime
+    }
+
+    /// The cookies to set after logging in or refreshing. A new csrf token is generated each time.
+    pub fn login_cookies(&self, access_token: &str, access_token_lifetime: i64, refresh_token: &str) -> Vec<Cookie<'static>> {
+        return vec![
+            self.build_cookie(ACCESS_TOKEN_COOKIE, access_token.to_string(), access_token_lifetime, true),
+            self.build_cookie(REFRESH_TOKEN_COOKIE, refresh_token.to_string(), self.refresh_token_lifetime, true),
+            self.build_cookie(CSRF_TOKEN_COOKIE, generate_random_token(32), self.refresh_token_lifetime, false),
+        ];
+    }
+
+    /// Cookies that remove every login cookie from the browser
+    pub fn logout_cookies(&self) -> Vec<Cookie<'static>> {
+        return [ACCESS_TOKEN_COOKIE, REFRESH_TOKEN_COOKIE, CSRF_TOKEN_COOKIE].into_iter()
+            .map(|name| {
+                let mut cookie = self.build_cookie(name, String::new(), 0, true);
+                cookie.make_removal();
+                cookie
+            })
+            .collect();
+    }
+
+    /// Holds the signed oauth state between `/oauth-url` and `/register`. This one is `Lax` since
+    /// the login is started by a top level navigation to google and back.
+    pub fn oauth_state_cookie(&self, signed_state: &str, max_age: i64) -> Cookie<'static> {
+        let mut cookie = self.build_cookie(OAUTH_STATE_COOKIE, signed_state.to_string(), max_age, true);
+        cookie.set_same_site(SameSite::Lax);
+        cookie
+    }
+
+    /// The oauth state can only be used once, so it's removed as soon as it's been checked
+    pub fn oauth_state_removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.oauth_state_cookie("", 0);
+        cookie.make_removal();
+        cookie
+    }
+
+    fn build_cookie(&self, name: &'static str, value: String, max_age: i64, http_only: bool) -> Cookie<'static> {
+        let path = if name == CSRF_TOKEN_COOKIE { CSRF_COOKIE_PATH } else { COOKIE_PATH };
+        Cookie::build(name, value)
+            .path(path)
+            .http_only(http_only)
+            .secure(self.secure)
+            .same_site(SameSite::Strict)
+            .max_age(Duration::seconds(max_age))
+            .finish()
+    }
+}
+
+/// Rejects state changing requests whose `X-CSRF-Token` header doesn't match the csrf cookie.
+/// Safe methods are let through since they must not change anything anyway.
+pub fn verify_csrf(req: &HttpRequest) -> Result<(), ApiError> {
+    let method = req.method();
+    if method == Method::GET || method == Method::HEAD || method == Method::OPTIONS {
+        return Ok(());
+    }
+
+    let cookie = req.cookie(CSRF_TOKEN_COOKIE)
+        .ok_or_else(|| ApiError::forbidden("Missing csrf cookie"))?;
+    let header = req.headers().get(CSRF_TOKEN_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .ok_or_else(|| ApiError::forbidden("Missing csrf token header"))?;
+
+    if cookie.value().is_empty() || !constant_time_eq(cookie.value().as_bytes(), header.as_bytes()) {
+        return Err(ApiError::forbidden("Invalid csrf token"));
+    }
+    Ok(())
+}
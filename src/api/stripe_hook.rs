@@ -1,77 +1,252 @@
 use actix_web::{HttpRequest, HttpResponse, post, web};
-use stripe::{CheckoutSession, CheckoutSessionPaymentStatus, EventObject, EventType, Webhook};
+use stripe::{Charge, CheckoutSession, CheckoutSessionPaymentStatus, Customer, Dispute, DisputeStatus, Event, EventObject, EventType, Expandable, Webhook};
 
+use crate::api::api_error::ApiError;
+use crate::database::DatabasePool;
 use crate::SharedResources;
+use crate::stripe_util::StripeHandler;
 
+/// Events that change something on our side, the rest are acknowledged and ignored
+const HANDLED_EVENTS: [EventType; 9] = [
+    EventType::CheckoutSessionCompleted,
+    EventType::CheckoutSessionExpired,
+    EventType::CheckoutSessionAsyncPaymentSucceeded,
+    EventType::CheckoutSessionAsyncPaymentFailed,
+    EventType::ChargeRefunded,
+    EventType::ChargeDisputeCreated,
+    EventType::ChargeDisputeClosed,
+    EventType::CustomerUpdated,
+    EventType::CustomerDeleted,
+];
+
+/// Receives stripe's webhook events. Anything that isn't acknowledged with a 2xx is retried by
+/// stripe for days, so events are acknowledged as soon as they're safely in the inbox and
+/// retried from there should processing them fail. That includes refunds and disputes of a
+/// purchase whose checkout hasn't completed yet, stripe doesn't promise any order.
 #[post("webhook")]
-pub async fn webhook_handler(data: web::Data<SharedResources>, req: HttpRequest, payload: web::Bytes) -> HttpResponse {
+pub async fn webhook_handler(data: web::Data<SharedResources>, req: HttpRequest, payload: web::Bytes) -> Result<HttpResponse, ApiError> {
     let signing_secret = data.get_ref().stripe_handler.get_webhook_signing_secret();
-    let payload_str = std::str::from_utf8(payload.as_ref()).unwrap();
+    let payload_str = std::str::from_utf8(payload.as_ref())
+        .map_err(|_| ApiError::bad_request("Webhook payload is not valid utf-8"))?;
     let stripe_signature = get_header_value(&req, "Stripe-Signature").unwrap_or_default();
 
     if let Ok(event) = Webhook::construct_event(payload_str, stripe_signature, signing_secret.as_str()) {
-        match event.type_ {
-            EventType::CheckoutSessionCompleted => {
-                if let EventObject::CheckoutSession(session) = event.data.object {
-                    handle_checkout_complete(data, session).await;
-                    return HttpResponse::Ok().finish();
-                }
-                return HttpResponse::BadRequest().finish();
-            }
-            EventType::CheckoutSessionExpired => {
-                if let EventObject::CheckoutSession(session) = event.data.object {
-                    handle_checkout_expired(data, session).await;
-                    return HttpResponse::Ok().finish();
-                }
-                return HttpResponse::BadRequest().finish();
-            }
-            EventType::CheckoutSessionAsyncPaymentSucceeded => {
-                if let EventObject::CheckoutSession(session) = event.data.object {
-                    handle_checkout_complete(data, session).await;
-                    return HttpResponse::Ok().finish();
-                }
-                return HttpResponse::BadRequest().finish();
-            }
-            EventType::CheckoutSessionAsyncPaymentFailed => {
-                if let EventObject::CheckoutSession(session) = event.data.object {
-                    handle_checkout_expired(data, session).await;
-                    return HttpResponse::Ok().finish();
-                }
-                return HttpResponse::BadRequest().finish();
-            }
-            _ => {
-                println!("Unknown event encountered in webhook: {:?}", event.type_);
-                HttpResponse::InternalServerError().finish()
-            }
+        let event_id = event.id.to_string();
+        if !data.database.store_webhook_event(&event_id, &event.type_.to_string(), payload_str).await? {
+            println!("Webhook event {} was already received", event_id);
+        }
+        // the event was stored, a failure is retried from the inbox rather than by stripe
+        if let Err(err) = process_webhook_event(&data, &event_id).await {
+            eprintln!("Error processing webhook event {}: {}", event_id, err);
         }
+        Ok(HttpResponse::Ok().finish())
     } else {
         println!("Failed to construct webhook event, ensure your webhook secret is correct.");
-        HttpResponse::Unauthorized().finish()
+        Err(ApiError::unauthorized("Invalid webhook signature"))
     }
 }
 
+/// Processes a stored event unless it was already processed or is being processed elsewhere.
+/// A failure is recorded on the event so it's retried later. Returns whether the event was
+/// processed by this call.
+pub async fn process_webhook_event(data: &SharedResources, event_id: &str) -> Result<bool, ApiError> {
+    let database = &data.database;
+    let payload = match database.claim_webhook_event(event_id).await? {
+        Some(payload) => payload,
+        None => return Ok(false)
+    };
+
+    // the signature was checked when the event was received
+    let result = match serde_json::from_str::<Event>(&payload) {
+        Ok(event) => handle_event(data, event).await,
+        Err(err) => Err(ApiError::bad_request(format!("Stored webhook event can't be read: {}", err)))
+    };
+    match result {
+        Ok(()) => {
+            database.finish_webhook_event(event_id).await?;
+            Ok(true)
+        }
+        Err(err) => {
+            database.fail_webhook_event(event_id, &err.to_string()).await?;
+            Err(err)
+        }
+    }
+}
+
+/// Processes every stored event that is due for another attempt
+pub async fn retry_webhook_events(data: &SharedResources) -> Result<(), ApiError> {
+    for event_id in data.database.get_due_webhook_events(50).await? {
+        match process_webhook_event(data, &event_id).await {
+            Ok(true) => println!("Processed webhook event {} from the inbox", event_id),
+            Ok(false) => {}
+            Err(err) => eprintln!("Error retrying webhook event {}: {}", event_id, err)
+        }
+    }
+    Ok(())
+}
+
+async fn handle_event(data: &SharedResources, event: Event) -> Result<(), ApiError> {
+    match (event.type_, event.data.object) {
+        (EventType::CheckoutSessionCompleted, EventObject::CheckoutSession(session))
+        | (EventType::CheckoutSessionAsyncPaymentSucceeded, EventObject::CheckoutSession(session)) => {
+            handle_checkout_complete(&data.database, &data.stripe_handler, session, data.referral_bonus_credits).await?;
+        }
+        (EventType::CheckoutSessionExpired, EventObject::CheckoutSession(session))
+        | (EventType::CheckoutSessionAsyncPaymentFailed, EventObject::CheckoutSession(session)) => {
+            handle_checkout_expired(&data.database, session, data.referral_bonus_credits).await?;
+        }
+        (EventType::ChargeRefunded, EventObject::Charge(charge)) => {
+            handle_charge_refunded(data, charge).await?;
+        }
+        (EventType::ChargeDisputeCreated, EventObject::Dispute(dispute)) => {
+            handle_dispute_created(data, dispute).await?;
+        }
+        (EventType::ChargeDisputeClosed, EventObject::Dispute(dispute)) => {
+            handle_dispute_closed(data, dispute).await?;
+        }
+        (EventType::CustomerUpdated, EventObject::Customer(customer)) => {
+            handle_customer_updated(data, customer).await?;
+        }
+        (EventType::CustomerDeleted, EventObject::Customer(customer)) => {
+            handle_customer_deleted(data, customer).await?;
+        }
+        (event_type, _) if HANDLED_EVENTS.contains(&event_type) => {
+            return Err(ApiError::bad_request(format!("Unexpected event object for {}", event_type)));
+        }
+        (event_type, _) => {
+            println!("Ignoring webhook event {}", event_type);
+        }
+    }
+    Ok(())
+}
+
 fn get_header_value<'b>(req: &'b HttpRequest, key: &'b str) -> Option<&'b str> {
     req.headers().get(key)?.to_str().ok()
 }
 
-async fn handle_checkout_complete(data: web::Data<SharedResources>, session: CheckoutSession) {
+pub(crate) async fn handle_checkout_complete(database: &DatabasePool, stripe_handler: &StripeHandler, session: CheckoutSession, referral_bonus_credits: u32) -> Result<(), ApiError> {
     println!("Checkout session completed/paid: {:?}", session);
-    let database = &data.database;
 
     if session.payment_status == CheckoutSessionPaymentStatus::Paid {
-        let success = database.close_purchase_session(session.id.as_str(), true, session.amount_total.map(|o| o as f64/100.0), None).await;
-        if !success {
-            eprintln!("Error, no such session id!");
+        // only look the discount up when there was one, it takes another request to stripe
+        let discounted = session.total_details.as_ref().is_some_and(|total_details| total_details.amount_discount > 0);
+        let discount = if discounted {
+            stripe_handler.get_checkout_discount(&session.id).await
+                .unwrap_or_else(|err| {
+                    eprintln!("Error looking up the discount of checkout session {}: {}", session.id, err);
+                    None
+                })
+        } else {
+            None
+        };
+
+        // refunds and disputes only know the payment intent, not the checkout session
+        if let Some(payment_intent) = &session.payment_intent {
+            // the card is only needed to catch referral abuse, that isn't worth failing the purchase over
+            let payment_fingerprint = stripe_handler.get_payment_fingerprint(payment_intent.id().as_str()).await
+                .unwrap_or_else(|err| {
+                    eprintln!("Error looking up the card of payment intent {}: {}", payment_intent.id(), err);
+                    None
+                });
+            database.set_purchase_payment_intent(session.id.as_str(), payment_intent.id().as_str(), payment_fingerprint.as_deref()).await?;
         }
-    }
 
+        database.close_purchase_session(session.id.as_str(), true, session.amount_total.map(|o| o as f64/100.0), discount.as_ref(), referral_bonus_credits).await?;
+    }
+    Ok(())
 }
 
-async fn handle_checkout_expired(data: web::Data<SharedResources>, session: CheckoutSession) {
+pub(crate) async fn handle_checkout_expired(database: &DatabasePool, session: CheckoutSession, referral_bonus_credits: u32) -> Result<(), ApiError> {
     println!("Checkout session expired/failed: {:?}", session);
-    let database = &data.database;
-    let success = database.close_purchase_session(session.id.as_str(), false, None, None).await;
-    if !success {
-        eprintln!("Error, no such session id!");
+    database.close_purchase_session(session.id.as_str(), false, None, None, referral_bonus_credits).await?;
+    Ok(())
+}
+
+async fn handle_charge_refunded(data: &SharedResources, charge: Charge) -> Result<(), ApiError> {
+    let payment_intent = match &charge.payment_intent {
+        Some(payment_intent) => payment_intent.id(),
+        None => {
+            println!("Refunded charge {} has no payment intent, ignoring it", charge.id);
+            return Ok(());
+        }
+    };
+
+    match data.database.refund_purchase(payment_intent.as_str(), charge.amount, charge.amount_refunded, charge.refunded).await? {
+        Some(credits) => println!("Charge {} was refunded, took back {} credits", charge.id, credits),
+        // stripe doesn't deliver events in order, the checkout may not have completed yet
+        None => return Err(ApiError::not_found(format!("Refunded charge {} doesn't belong to a known purchase yet", charge.id)))
+    }
+    Ok(())
+}
+
+/// The payment intent of a disputed charge, which is how the purchase is found
+fn dispute_payment_intent(dispute: &Dispute) -> Option<String> {
+    if let Some(payment_intent) = &dispute.payment_intent {
+        return Some(payment_intent.id().to_string());
+    }
+    match &dispute.charge {
+        Expandable::Object(charge) => charge.payment_intent.as_ref().map(|payment_intent| payment_intent.id().to_string()),
+        Expandable::Id(_) => None
+    }
+}
+
+async fn handle_dispute_created(data: &SharedResources, dispute: Dispute) -> Result<(), ApiError> {
+    let payment_intent = match dispute_payment_intent(&dispute) {
+        Some(payment_intent) => payment_intent,
+        None => {
+            println!("Dispute {} has no payment intent, ignoring it", dispute.id);
+            return Ok(());
+        }
+    };
+
+    match data.database.open_purchase_dispute(&payment_intent, dispute.id.as_str()).await? {
+        Some(credits) => println!("Dispute {} opened, holding {} credits", dispute.id, credits),
+        None => return Err(ApiError::not_found(format!("Dispute {} doesn't belong to a known purchase yet", dispute.id)))
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+async fn handle_dispute_closed(data: &SharedResources, dispute: Dispute) -> Result<(), ApiError> {
+    let payment_intent = match dispute_payment_intent(&dispute) {
+        Some(payment_intent) => payment_intent,
+        None => {
+            println!("Dispute {} has no payment intent, ignoring it", dispute.id);
+            return Ok(());
+        }
+    };
+
+    // an inquiry that closed without turning into a dispute costs the user nothing either
+    let won = matches!(dispute.status, DisputeStatus::Won | DisputeStatus::WarningClosed);
+    match data.database.close_purchase_dispute(&payment_intent, won).await? {
+        Some(credits) => println!("Dispute {} closed as {}, returned {} credits", dispute.id, dispute.status, credits),
+        None => return Err(ApiError::not_found(format!("Dispute {} doesn't belong to a known purchase yet", dispute.id)))
+    }
+    Ok(())
+}
+
+async fn handle_customer_updated(data: &SharedResources, customer: Customer) -> Result<(), ApiError> {
+    // names are pushed to stripe from here, so there's nothing to take over, but a customer
+    // that isn't ours means something is off with the stripe account
+    if data.database.get_username_by_stripe_id(customer.id.as_str()).await?.is_none() {
+        println!("Updated stripe customer {} doesn't belong to any user", customer.id);
+    }
+    Ok(())
+}
+
+async fn handle_customer_deleted(data: &SharedResources, customer: Customer) -> Result<(), ApiError> {
+    let database = &data.database;
+    let kerberos_username = match database.get_username_by_stripe_id(customer.id.as_str()).await? {
+        Some(kerberos_username) => kerberos_username,
+        None => return Ok(())
+    };
+
+    // the user can't check out without a customer, so give them a new one straight away
+    let user = database.get_user(&kerberos_username).await?;
+    let settings = database.get_user_application_settings(&kerberos_username).await?;
+    let full_name = format!("{} {}", user.given_name, user.family_name);
+    let customer_id = data.stripe_handler.create_new_stripe_customer(&full_name, settings.email.as_deref()).await;
+    database.set_stripe_id(&kerberos_username, customer_id.as_str()).await?;
+
+    println!("Stripe customer {} of {} was deleted, replaced it with {}", customer.id, kerberos_username, customer_id);
+    Ok(())
+}
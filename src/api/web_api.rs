@@ -1,18 +1,25 @@
 use std::collections::HashMap;
 
 use actix_web::{delete, get, HttpRequest, HttpResponse, post, Responder, web};
-use actix_web::cookie::Cookie;
-use actix_web::cookie::time::Duration;
+use actix_web::http::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use stripe::CheckoutSession;
 
+use crate::api::api_error::ApiError;
+use crate::api::authentication::AuthenticatedUser;
+use crate::api::session_cookies::{OAUTH_STATE_COOKIE, REFRESH_TOKEN_COOKIE, verify_csrf};
 use crate::data_structs::app_config::UserApplicationSettings;
 use crate::data_structs::bu_course::{BUCourseSection, CourseSection};
+use crate::data_structs::responses::error_response::ErrorCode;
+use crate::data_structs::responses::new_license_key_response::NewLicenseKeyResponse;
 use crate::data_structs::responses::web_register_response::WebRegisterResponse;
 use crate::data_structs::semester::Semester;
 use crate::data_structs::user::User;
-use crate::google_oauth::{GoogleAuthCode, GoogleClientSecret};
+use crate::database::{DatabaseError, RefreshTokenRotation};
+use crate::encrypted_signing::{constant_time_eq, JWTSecretKey};
+use crate::google_oauth::{GoogleAuthCode, OAuthState, TokenExchangeError};
+use crate::grant_policy::TrialState;
 use crate::SharedResources;
 
 #[get("/ping")]
@@ -22,119 +29,284 @@ async fn debug_ping() -> impl Responder {
 }
 
 #[post("/register")]
-async fn oauth_register(data: web::Data<SharedResources>, info: web::Json<GoogleAuthCode>) -> impl Responder {
+async fn oauth_register(data: web::Data<SharedResources>, req: HttpRequest, info: web::Json<GoogleAuthCode>) -> Result<HttpResponse, ApiError> {
     let client_secrets = &data.get_ref().google_client_secret;
     let database = &data.get_ref().database;
     let jwt_secret = &data.get_ref().jwt_secret;
+    let session_cookies = &data.get_ref().session_cookies;
     let stripe_handler = &data.get_ref().stripe_handler;
 
+    let oauth_state = verify_oauth_state(&req, jwt_secret, &info.state)?;
+
     let code = &info.code;
-    // just to test that the server is running
-    let access_token = client_secrets.get_access_token(code).await; //todo error handling for wrong code
-    let user_info = client_secrets.get_user_info(access_token.access_token.as_str()).await;
+    let access_token = client_secrets.get_access_token(code, &oauth_state.code_verifier).await
+        .map_err(|err| {
+            eprintln!("Error exchanging the google authorization code: {}", err);
+            match err {
+                TokenExchangeError::Rejected(_) => ApiError::new(StatusCode::BAD_REQUEST, ErrorCode::InvalidAuthorizationCode,
+                                                                 "The google login is invalid or expired, please sign in again"),
+                TokenExchangeError::Unavailable(_) => ApiError::new(StatusCode::BAD_GATEWAY, ErrorCode::ServiceUnavailable,
+                                                                    "Google could not be reached, please try again")
+            }
+        })?;
+    let user_info = data.google_id_token_verifier.verify(&access_token.id_token).await
+        .map_err(|err| {
+            eprintln!("Rejected google id token: {}", err);
+            ApiError::unauthorized("Could not verify the google login")
+        })?;
+
+    if !user_info.is_allowed_domain(&data.allowed_google_domains) {
+        return Err(ApiError::forbidden(format!("Only verified {} accounts can sign up",
+                                               data.allowed_google_domains.join(", "))));
+    }
 
     // todo: if /register happens multiple times before first call finishes error happen
     // mutex needed
 
-    let user = database.create_or_update_user(&user_info, &access_token, &stripe_handler).await;
-    let jwt_user_token = jwt_secret.encrypt_jwt_token(user.kerberos_username.clone());
+    let user = database.create_or_update_user(&user_info, &access_token, &stripe_handler, oauth_state.referral_code.as_deref()).await
+        .map_err(|err| match err {
+            DatabaseError::Conflict(_) => ApiError::new(StatusCode::CONFLICT, ErrorCode::Conflict,
+                                                        "Your google login couldn't be matched to an account, please try again"),
+            err => err.into()
+        })?;
+    let token_generation = database.get_token_generation(&user.kerberos_username).await?;
+    let jwt_user_token = jwt_secret.issue_token(&user.kerberos_username, token_generation);
+    let refresh_token = database.create_refresh_token(
+        &user.kerberos_username,
+        session_cookies.refresh_token_lifetime()
+    ).await?;
 
-    HttpResponse::Ok().json(WebRegisterResponse {
-        jwt_cookie: jwt_user_token.as_str().to_string(),
+    let mut response = HttpResponse::Ok();
+    for cookie in session_cookies.login_cookies(&jwt_user_token, jwt_secret.token_lifetime(), &refresh_token) {
+        response.cookie(cookie);
+    }
+    response.cookie(session_cookies.oauth_state_removal_cookie());
+    Ok(response.json(WebRegisterResponse {
         user: user
-    })
+    }))
+}
+
+/// Exchanges the refresh token cookie for a new access token and a new refresh token.
+/// A refresh token that was already used revokes every token of that login.
+#[post("/refresh")]
+async fn refresh(data: web::Data<SharedResources>, req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let database = &data.get_ref().database;
+    let jwt_secret = &data.get_ref().jwt_secret;
+    let session_cookies = &data.get_ref().session_cookies;
+
+    verify_csrf(&req)?;
+    let refresh_token = req.cookie(REFRESH_TOKEN_COOKIE)
+        .ok_or_else(|| ApiError::unauthorized("No refresh token supplied"))?;
+
+    let rotation = database.rotate_refresh_token(
+        refresh_token.value(),
+        session_cookies.refresh_token_lifetime()
+    ).await?;
+
+    return match rotation {
+        RefreshTokenRotation::Rotated { kerberos_username, refresh_token } => {
+            let token_generation = database.get_token_generation(&kerberos_username).await?;
+            let jwt_user_token = jwt_secret.issue_token(&kerberos_username, token_generation);
+
+            let mut response = HttpResponse::Ok();
+            for cookie in session_cookies.login_cookies(&jwt_user_token, jwt_secret.token_lifetime(), &refresh_token) {
+                response.cookie(cookie);
+            }
+            Ok(response.finish())
+        },
+        RefreshTokenRotation::Reused { kerberos_username } => {
+            println!("Refresh token reuse detected for {}, revoked the token family", kerberos_username);
+            Err(ApiError::unauthorized("This session has been logged out"))
+        },
+        RefreshTokenRotation::Invalid => Err(ApiError::unauthorized("Invalid or expired refresh token"))
+    };
+}
+
+/// Checks the state google sent back against the signed state cookie set by `/oauth-url`,
+/// returning the state so its PKCE verifier can be used to redeem the code
+fn verify_oauth_state(req: &HttpRequest, jwt_secret: &JWTSecretKey, state: &str) -> Result<OAuthState, ApiError> {
+    let cookie = req.cookie(OAUTH_STATE_COOKIE)
+        .ok_or_else(|| ApiError::bad_request("No login is in progress, please start the login again"))?;
+    let oauth_state = jwt_secret.decrypt_jwt_token::<OAuthState>(cookie.value())
+        .ok_or_else(|| ApiError::bad_request("Invalid login state, please start the login again"))?
+        .claims().clone();
+
+    if oauth_state.is_expired() {
+        return Err(ApiError::bad_request("The login has expired, please start the login again"));
+    }
+    if !constant_time_eq(oauth_state.state.as_bytes(), state.as_bytes()) {
+        return Err(ApiError::bad_request("The login state does not match, please start the login again"));
+    }
+    Ok(oauth_state)
+}
+
+#[derive(Deserialize)]
+struct OAuthUrlQuery {
+    /// referral links point here with the referrer's code, it's kept in the state until the login finishes
+    referral_code: Option<String>
 }
 
 #[get("/oauth-url")]
-async fn oauth_url(data: web::Data<SharedResources>) -> impl Responder {
+async fn oauth_url(data: web::Data<SharedResources>, info: web::Query<OAuthUrlQuery>) -> impl Responder {
     let client_secrets = &data.get_ref().google_client_secret;
-    let oauth_url = client_secrets.create_oauth_uri();
+    let jwt_secret = &data.get_ref().jwt_secret;
+    let session_cookies = &data.get_ref().session_cookies;
+
+    // referral codes are short, anything else isn't worth carrying through the login
+    let referral_code = info.into_inner().referral_code
+        .filter(|code| !code.is_empty() && code.len() <= 16 && code.chars().all(|c| c.is_ascii_alphanumeric()));
+    let oauth_state = OAuthState::generate(referral_code);
+    let oauth_url = client_secrets.create_oauth_uri(&oauth_state);
+    let signed_state = jwt_secret.encrypt_jwt_token(oauth_state);
+
     HttpResponse::TemporaryRedirect()
+        .cookie(session_cookies.oauth_state_cookie(signed_state.as_str(), OAuthState::lifetime()))
         .header("Location", oauth_url)
         .finish()
 }
 
-#[get("/logout")]
-async fn logout(data: web::Data<SharedResources>) -> impl Responder {
-    let client_secrets: &GoogleClientSecret = &data.get_ref().google_client_secret;
-    let oauth_uri: String = client_secrets.create_oauth_uri();
-    HttpResponse::Ok()
-        .cookie(Cookie::build("jwt-token", "").max_age(Duration::new(0, 0)).finish())
-        .header("Location", "/")
-        .finish()
+/// Revokes the access token used to make this request along with its refresh token
+#[post("/logout")]
+async fn logout(data: web::Data<SharedResources>, user: AuthenticatedUser, req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    data.database.revoke_token(&user.claims).await?;
+    if let Some(refresh_token) = req.cookie(REFRESH_TOKEN_COOKIE) {
+        data.database.revoke_refresh_token_family(refresh_token.value()).await?;
+    }
+
+    let mut response = HttpResponse::Ok();
+    for cookie in data.session_cookies.logout_cookies() {
+        response.cookie(cookie);
+    }
+    Ok(response.finish())
+}
+
+/// Revokes every token ever issued to the user, logging them out on all of their devices
+#[post("/logout-everywhere")]
+async fn logout_everywhere(data: web::Data<SharedResources>, user: AuthenticatedUser) -> Result<HttpResponse, ApiError> {
+    data.database.revoke_all_tokens(&user.kerberos_username).await?;
+
+    let mut response = HttpResponse::Ok();
+    for cookie in data.session_cookies.logout_cookies() {
+        response.cookie(cookie);
+    }
+    Ok(response.finish())
 }
 
 #[get("/profile-info")]
-async fn profile_info(data: web::Data<SharedResources>, req: HttpRequest) -> impl Responder {
-    let jwt_secret = &data.get_ref().jwt_secret;
+async fn profile_info(data: web::Data<SharedResources>, user: AuthenticatedUser) -> Result<HttpResponse, ApiError> {
     let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
+    let mut user = database.get_user(&user.kerberos_username).await?;
+    user.trial_allowance = Some(data.demo_policy.trial_allowance(&TrialState {
+        registration_timestamp: user.registration_timestamp,
+        registrations_used: user.trial_registrations_used,
+        demo_expired_at: user.demo_expired_at
+    }, chrono::Local::now().timestamp()));
+    Ok(HttpResponse::Ok().json(user))
+}
 
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
+/// Revokes every license key of the user and replaces them with a single new default key
+#[post("/reset-app-token")]
+async fn reset_app_token(data: web::Data<SharedResources>, user: AuthenticatedUser) -> Result<HttpResponse, ApiError> {
+    let database = &data.get_ref().database;
+    let kerberos_username = user.kerberos_username;
+    let license_key = database.reset_authentication_key(&kerberos_username).await?;
+    let mut user = database.get_user(&kerberos_username).await?;
+    // the new key is only shown this once
+    user.license_key = Some(license_key);
+
+    return Ok(HttpResponse::Ok()
+        .json(user));
+}
 
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
+#[derive(Deserialize)]
+struct NewLicenseKey {
+    label: String,
+    /// bind the key to the first device it's used on
+    #[serde(default)]
+    device_bound: bool
+}
+
+#[derive(Deserialize)]
+struct LicenseKeyLabel {
+    label: String
+}
 
-    return if kerberos_username.is_none() {
-        HttpResponse::Unauthorized().json("Invalid")
-    } else {
-        let unwrapped_user = database.get_user(&kerberos_username.unwrap().claims()).await.unwrap();
-        HttpResponse::Ok().json(unwrapped_user)
+fn validate_license_key_label(label: &str) -> Result<String, ApiError> {
+    let label = label.trim();
+    if label.is_empty() || label.chars().count() > 64 {
+        return Err(ApiError::bad_request("License key labels must be between 1 and 64 characters long"));
     }
+    Ok(label.to_string())
+}
 
+#[get("/license-keys")]
+async fn get_license_keys(data: web::Data<SharedResources>, user: AuthenticatedUser) -> Result<HttpResponse, ApiError> {
+    let license_keys = data.database.get_license_keys(&user.kerberos_username).await?;
+    Ok(HttpResponse::Ok().json(license_keys))
 }
 
-#[get("/reset-app-token")]
-async fn reset_app_token(data: web::Data<SharedResources>, req: HttpRequest) -> impl Responder {
-    let jwt_secret = &data.get_ref().jwt_secret;
-    let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
+/// Creates a new license key. The response is the only time the key itself is shown.
+#[post("/license-keys")]
+async fn create_license_key(data: web::Data<SharedResources>, user: AuthenticatedUser, info: web::Json<NewLicenseKey>) -> Result<HttpResponse, ApiError> {
+    let label = validate_license_key_label(&info.label)?;
+    let (license_key, key) = data.database.create_license_key(
+        &user.kerberos_username,
+        &label,
+        info.device_bound
+    ).await?;
+
+    Ok(HttpResponse::Ok().json(NewLicenseKeyResponse {
+        license_key,
+        key
+    }))
+}
 
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
+#[post("/license-keys/{license_key_id}/label")]
+async fn rename_license_key(data: web::Data<SharedResources>, user: AuthenticatedUser, path: web::Path<u32>, info: web::Json<LicenseKeyLabel>) -> Result<HttpResponse, ApiError> {
+    let label = validate_license_key_label(&info.label)?;
+    data.database.rename_license_key(&user.kerberos_username, path.into_inner(), &label).await?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
+#[delete("/license-keys/{license_key_id}")]
+async fn revoke_license_key(data: web::Data<SharedResources>, user: AuthenticatedUser, path: web::Path<u32>) -> Result<HttpResponse, ApiError> {
+    data.database.revoke_license_key(&user.kerberos_username, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
+/// The user's referral code and how their referrals worked out
+#[get("/referrals")]
+async fn referral_stats(data: web::Data<SharedResources>, user: AuthenticatedUser) -> Result<HttpResponse, ApiError> {
+    let stats = data.database.get_referral_stats(&user.kerberos_username).await?;
+    Ok(HttpResponse::Ok().json(stats))
+}
 
-    let kerberos_username = kerberos_username.unwrap().claims().to_owned();
-    database.reset_authentication_key(&kerberos_username).await;
-    let user = database.get_user(&kerberos_username).await;
+#[derive(Deserialize)]
+struct CreditHistoryQuery {
+    /// only return entries older than this entry id
+    before: Option<u64>,
+    limit: Option<u32>
+}
 
-    return HttpResponse::Ok()
-        .json(user);
+/// Every change to the user's credits, newest first
+#[get("/credit-history")]
+async fn credit_history(data: web::Data<SharedResources>, user: AuthenticatedUser, info: web::Query<CreditHistoryQuery>) -> Result<HttpResponse, ApiError> {
+    let limit = info.limit.unwrap_or(50).clamp(1, 200);
+    let entries = data.database.get_credit_history(&user.kerberos_username, info.before, limit).await?;
+    Ok(HttpResponse::Ok().json(entries))
 }
 
 #[derive(Deserialize)]
 struct Quantity(u64);
 #[post("/create-checkout-session")]
-pub async fn create_checkout_session(data: web::Data<SharedResources>, req: HttpRequest, info: web::Json<Quantity>) -> impl Responder {
-    let jwt_secret = &data.get_ref().jwt_secret;
+pub async fn create_checkout_session(data: web::Data<SharedResources>, user: AuthenticatedUser, info: web::Json<Quantity>) -> Result<HttpResponse, ApiError> {
     let stripe_handler = &data.get_ref().stripe_handler;
     let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
-
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
-
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
-
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
 
     let mut quantity = info.into_inner().0;
     // make sure quantity is between 1-16 and if its not min/max it
     quantity = quantity.max(1).min(16);
-    let user = database.get_user(&kerberos_username.unwrap().claims().to_owned()).await.unwrap();
+    let user = database.get_user(&user.kerberos_username).await?;
 
     let checkout_session: CheckoutSession = stripe_handler.create_stripe_checkout_session(
         &data.get_ref().base_url,
@@ -148,30 +320,16 @@ pub async fn create_checkout_session(data: web::Data<SharedResources>, req: Http
         quantity,
         stripe_handler.get_unit_price(quantity),
         checkout_session.id.as_str()
-    ).await;
+    ).await?;
 
-    HttpResponse::Ok().json(checkout_session.url)
+    Ok(HttpResponse::Ok().json(checkout_session.url))
 }
 
 #[post("/custom-course")]
-pub async fn add_custom_course(data: web::Data<SharedResources>, req: HttpRequest, info: web::Json<BUCourseSection>) -> impl Responder {
+pub async fn add_custom_course(data: web::Data<SharedResources>, _user: AuthenticatedUser, info: web::Json<BUCourseSection>) -> Result<HttpResponse, ApiError> {
     let database = &data.get_ref().database;
-    let jwt_secret = &data.get_ref().jwt_secret;
     let course = info.into_inner();
 
-    let auth_header = req.headers().get("Authorization");
-
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
-
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
-
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
-
     let added_course = database.add_course(
         course.course.semester.clone(),
         course.course.to_full_course_code_str(),
@@ -181,39 +339,21 @@ pub async fn add_custom_course(data: web::Data<SharedResources>, req: HttpReques
                 ..CourseSection::default()
             }
         ]
-    ).await;
+    ).await?;
 
-    HttpResponse::Ok().json(&added_course[0])
+    Ok(HttpResponse::Ok().json(&added_course[0]))
 }
 
 #[post("/user-app-settings")]
-pub async fn update_user_app_settings(data: web::Data<SharedResources>, req: HttpRequest, info: web::Json<HashMap<String, Value>>) -> impl Responder {
-    let jwt_secret = &data.get_ref().jwt_secret;
+pub async fn update_user_app_settings(data: web::Data<SharedResources>, user: AuthenticatedUser, info: web::Json<HashMap<String, Value>>) -> Result<HttpResponse, ApiError> {
     let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
-
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
-
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
-
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
-
-    let token = kerberos_username.unwrap();
-    let kerberos_username = token.claims();
+    let kerberos_username = &user.kerberos_username;
 
     // todo: this middle processing you having to first fetch existing settings is very inefficient
     //  and instead the create_or_update_user_application_settings should be rewritten
 
     // get the current application settings
-    let current_settings = match database.get_user_application_settings(&kerberos_username).await {
-        Some(settings) => settings,
-        None => UserApplicationSettings::default()
-    };
+    let current_settings = database.get_user_application_settings(&kerberos_username).await?;
 
     // Update fields dynamically based on the provided JSON
     let mut updated_settings_map = info.into_inner();
@@ -224,11 +364,12 @@ pub async fn update_user_app_settings(data: web::Data<SharedResources>, req: Htt
     }
     // convert back into settings
     let json_str = serde_json::to_string(&field_map).unwrap();
-    let updated_settings = serde_json::from_str::<UserApplicationSettings>(json_str.as_str()).unwrap();
+    let updated_settings = serde_json::from_str::<UserApplicationSettings>(json_str.as_str())
+        .map_err(|err| ApiError::bad_request(format!("Invalid application settings: {}", err)))?;
 
-    database.create_or_update_user_application_settings(&kerberos_username, &updated_settings).await;
+    database.create_or_update_user_application_settings(&kerberos_username, &updated_settings).await?;
 
-    return HttpResponse::Ok().finish();
+    return Ok(HttpResponse::Ok().finish());
 }
 
 #[derive(Deserialize)]
@@ -239,113 +380,51 @@ struct CourseReference {
 }
 
 #[delete("course-update")]
-pub async fn del_course(data: web::Data<SharedResources>, req: HttpRequest, info: web::Json<CourseReference>) -> impl Responder {
+pub async fn del_course(data: web::Data<SharedResources>, user: AuthenticatedUser, info: web::Json<CourseReference>) -> Result<HttpResponse, ApiError> {
 
-    let jwt_secret = &data.get_ref().jwt_secret;
     let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
-
     let info = info.into_inner();
     let course_id = info.course_id;
     let section_id = &info.section_id;
 
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
-
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
-
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
-
-    let token = kerberos_username.unwrap();
-    let kerberos_username = token.claims();
+    let kerberos_username = &user.kerberos_username;
 
     // todo: return status?
-    database.user_course_settings_delete_course(kerberos_username, course_id, section_id).await;
+    database.user_course_settings_delete_course(kerberos_username, course_id, section_id).await?;
 
-    return HttpResponse::Ok().finish();
+    return Ok(HttpResponse::Ok().finish());
 }
 
 #[post("course-update")]
-pub async fn add_course(data: web::Data<SharedResources>, req: HttpRequest, info: web::Json<CourseReference>) -> impl Responder {
+pub async fn add_course(data: web::Data<SharedResources>, user: AuthenticatedUser, info: web::Json<CourseReference>) -> Result<HttpResponse, ApiError> {
 
-    let jwt_secret = &data.get_ref().jwt_secret;
     let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
-
     let info = info.into_inner();
     let course_id = info.course_id;
     let section_id = &info.section_id;
 
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
-
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
-
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
-
-    let token = kerberos_username.unwrap();
-    let kerberos_username = token.claims();
+    let kerberos_username = &user.kerberos_username;
 
     // todo: return status?
-    database.user_course_settings_add_course(kerberos_username, course_id, section_id).await;
+    database.user_course_settings_add_course(kerberos_username, course_id, section_id).await?;
 
-    return HttpResponse::Ok().finish();
+    return Ok(HttpResponse::Ok().finish());
 }
 
 #[get("/user-app-settings")]
-pub async fn get_user_app_settings(data: web::Data<SharedResources>, req: HttpRequest) -> impl Responder {
-    let jwt_secret = &data.get_ref().jwt_secret;
+pub async fn get_user_app_settings(data: web::Data<SharedResources>, user: AuthenticatedUser) -> Result<HttpResponse, ApiError> {
     let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
+    let kerberos_username = &user.kerberos_username;
+    let settings = database.get_user_application_settings(&kerberos_username).await?;
 
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
-
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
-
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
-
-    let token = kerberos_username.unwrap();
-    let kerberos_username = token.claims();
-    let settings = match database.get_user_application_settings(&kerberos_username).await {
-        Some(settings) => settings,
-        None => UserApplicationSettings::default()
-    };
-
-    return HttpResponse::Ok()
-        .json(settings);
+    return Ok(HttpResponse::Ok()
+        .json(settings));
 }
 
 #[get("/active-semesters")]
-pub async fn get_active_semesters(data: web::Data<SharedResources>, req: HttpRequest) -> impl Responder {
-    let jwt_secret = &data.get_ref().jwt_secret;
+pub async fn get_active_semesters(data: web::Data<SharedResources>, _user: AuthenticatedUser) -> Result<HttpResponse, ApiError> {
     let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
-
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
-
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
-
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
-
-    let semesters = database.get_semesters_in_db().await;
+    let semesters = database.get_semesters_in_db().await?;
     let active_semesters = Semester::get_current_and_upcoming_semesters();
 
     // find the intersection of both methods
@@ -353,31 +432,17 @@ pub async fn get_active_semesters(data: web::Data<SharedResources>, req: HttpReq
         active_semesters.contains(semester)
     }).collect();
 
-    return HttpResponse::Ok()
-        .json(semesters);
+    return Ok(HttpResponse::Ok()
+        .json(semesters));
 }
 
 #[get("/get-available-courses")]
-pub async fn get_available_courses(data: web::Data<SharedResources>, req: HttpRequest, info: web::Query<Semester>) -> impl Responder {
-    let jwt_secret = &data.get_ref().jwt_secret;
+pub async fn get_available_courses(data: web::Data<SharedResources>, _user: AuthenticatedUser, info: web::Query<Semester>) -> Result<HttpResponse, ApiError> {
     let database = &data.get_ref().database;
-    let auth_header = req.headers().get("Authorization");
+    let courses = database.get_courses(&info.into_inner()).await?;
 
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().json("No authorization key supplied");
-    }
-
-    let user_auth_str = auth_header.unwrap().to_str().unwrap();
-    let kerberos_username = jwt_secret.decrypt_jwt_token::<String>(user_auth_str);
-
-    if kerberos_username.is_none() {
-        return HttpResponse::Unauthorized().json("Invalid");
-    }
-
-    let courses = database.get_courses(&info.into_inner()).await;
-
-    HttpResponse::Ok()
-        .json(courses)
+    Ok(HttpResponse::Ok()
+        .json(courses))
 }
 
 #[post("/contact-request")]
@@ -10,7 +10,13 @@ const BU_URL: &str = "https://www.bu.edu";
 
 pub async fn discover_summer_courses(database: &DatabasePool) {
     // todo get depts from here: https://www.bu.edu/summer/registration/course-codes-numbers/
-    let departments = database.get_all_course_departments().await;
+    let departments = match database.get_all_course_departments().await {
+        Ok(departments) => departments,
+        Err(err) => {
+            println!("Error loading course departments: {}", err);
+            return;
+        }
+    };
     for department in departments {
         for session in vec!["SUM1", "SUM2"] {
             get_summer_sites_for_department(database, session, department.clone()).await;
@@ -112,7 +118,9 @@ async fn get_summer_sites_for_department(database: &DatabasePool, summer_session
         }
 
         for future in db_futures {
-            future.await;
+            if let Err(err) = future.await {
+                println!("Error saving summer course for department={}: {}", department, err);
+            }
         }
 
     } else {
@@ -222,7 +230,9 @@ pub async fn discover_semester_courses(database: &DatabasePool, semester_key: &S
             }
         };
         let semester = Semester::from_course_catalog_key(semester_key);
-        database.add_course(semester, course_code, course_name, course_credits, true, sections).await;
+        if let Err(err) = database.add_course(semester, course_code.clone(), course_name, course_credits, true, sections).await {
+            println!("Error saving course {}: {}", course_code, err);
+        }
     }
 
 }
@@ -0,0 +1,89 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Why a user's credit balance changed
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone, Copy)]
+pub enum CreditEntryType {
+    Purchase,
+    RegistrationSpend,
+    Refund,
+    AdminGrant,
+    ReferralBonus,
+    /// credits held back while a purchase is disputed, and given back if the dispute is won
+    Dispute,
+    Demo,
+    /// the balance a user had when the ledger was introduced
+    OpeningBalance,
+}
+
+impl CreditEntryType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CreditEntryType::Purchase => "Purchase",
+            CreditEntryType::RegistrationSpend => "RegistrationSpend",
+            CreditEntryType::Refund => "Refund",
+            CreditEntryType::AdminGrant => "AdminGrant",
+            CreditEntryType::ReferralBonus => "ReferralBonus",
+            CreditEntryType::Dispute => "Dispute",
+            CreditEntryType::Demo => "Demo",
+            CreditEntryType::OpeningBalance => "OpeningBalance"
+        }
+    }
+}
+
+impl FromStr for CreditEntryType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Purchase" => Ok(CreditEntryType::Purchase),
+            "RegistrationSpend" => Ok(CreditEntryType::RegistrationSpend),
+            "Refund" => Ok(CreditEntryType::Refund),
+            "AdminGrant" => Ok(CreditEntryType::AdminGrant),
+            "ReferralBonus" => Ok(CreditEntryType::ReferralBonus),
+            "Dispute" => Ok(CreditEntryType::Dispute),
+            "Demo" => Ok(CreditEntryType::Demo),
+            "OpeningBalance" => Ok(CreditEntryType::OpeningBalance),
+            _ => Err(format!("{} is not a valid credit entry type", s))
+        }
+    }
+}
+
+/// One change to a user's credit balance. Entries are only ever appended, never updated.
+#[derive(Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct CreditLedgerEntry {
+    pub entry_id: u64,
+    pub entry_type: CreditEntryType,
+    /// how many credits were added, negative if credits were taken away
+    pub amount: i64,
+    /// the user's balance right after this entry
+    pub balance_after: i64,
+    /// the stripe checkout session of the purchase or refund this entry is for
+    pub purchase_session_id: Option<String>,
+    /// the application launch session the credits were spent in
+    pub launch_session_id: Option<i64>,
+    pub note: Option<String>,
+    pub created_at: i64,
+}
+
+/// A credit ledger entry that hasn't been written yet
+#[derive(Debug)]
+pub struct NewCreditEntry<'a> {
+    pub kerberos_username: &'a str,
+    pub entry_type: CreditEntryType,
+    pub amount: i64,
+    pub purchase_session_id: Option<&'a str>,
+    pub launch_session_id: Option<i64>,
+    pub note: Option<&'a str>,
+}
+
+impl<'a> NewCreditEntry<'a> {
+    pub fn new(kerberos_username: &'a str, entry_type: CreditEntryType, amount: i64) -> Self {
+        Self { kerberos_username, entry_type, amount, purchase_session_id: None, launch_session_id: None, note: None }
+    }
+}
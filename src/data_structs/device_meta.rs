@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::encrypted_signing::sha256_hex;
+
 #[derive(Debug, PartialEq)]
 #[derive(Deserialize, Serialize)]
 pub struct DeviceMeta {
@@ -9,4 +11,18 @@ pub struct DeviceMeta {
     pub name: Option<String>,
     pub os: String,
     pub ip: Option<String>, // client doesn't send this field, server adds it
-}
\ No newline at end of file
+}
+
+impl DeviceMeta {
+
+    /// Identifies the machine by its hardware and os, so a license key can be bound to it.
+    /// The ip is left out since it changes whenever the device changes networks.
+    pub fn fingerprint(&self) -> String {
+        sha256_hex(&format!("{}|{}|{}|{}|{}",
+                            self.core_count,
+                            self.cpu_speed,
+                            self.system_arch,
+                            self.name.as_deref().unwrap_or(""),
+                            self.os))
+    }
+}
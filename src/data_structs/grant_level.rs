@@ -1,16 +1,56 @@
 use serde::{Deserialize, Serialize};
 use sqlx::Decode;
 
+/// How much of the application a session may use, see [crate::grant_policy::decide_grant]
 #[derive(Debug, PartialEq, Eq)]
 #[derive(Deserialize, Serialize, Decode)]
+#[derive(Clone, Copy)]
 pub enum GrantLevel {
+    /// every targeted section may be registered
     Full,
+    /// only some of the targeted sections may be registered, there aren't enough credits for all
     Partial,
+    /// the user hasn't bought credits yet and may register what's left of their trial
     Demo,
+    /// the demo is used up and there are no credits left
     Expired,
+    /// the session has nothing it could do
     Error
 }
 
+/// The limits a session has to stick to, signed along with its grant level
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone, Copy)]
+pub struct SessionCapabilities {
+    /// how many course sections the session may target
+    pub max_sections: u32,
+    /// whether the session may register for real, otherwise it may only plan
+    pub real_registrations: bool,
+    /// how many course sections may be registered during the session
+    pub allowed_registrations: u32,
+}
+
+impl SessionCapabilities {
+
+    pub fn none() -> SessionCapabilities {
+        return SessionCapabilities {
+            max_sections: 0,
+            real_registrations: false,
+            allowed_registrations: 0
+        };
+    }
+
+    /// planned registrations are free, so every section may be planned
+    pub fn planner(section_count: u32) -> SessionCapabilities {
+        return SessionCapabilities {
+            max_sections: section_count,
+            real_registrations: false,
+            allowed_registrations: section_count
+        };
+    }
+}
+
 impl GrantLevel {
     pub fn to_string(&self) -> String {
         Self::as_str(self).to_string()
@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+/// One of the license keys a user can launch the app with. The key itself is never stored, only
+/// its prefix and a hash of it.
+#[derive(Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct LicenseKey {
+    pub license_key_id: u32,
+    pub kerberos_username: String,
+    pub label: String,
+    pub key_prefix: String,
+    /// whether the key binds itself to the first device that launches the app with it
+    pub device_bound: bool,
+    /// the fingerprint of the device the key is bound to, see [crate::data_structs::device_meta::DeviceMeta::fingerprint]
+    pub device_fingerprint: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// Where a referral is at. A referral is pending until the referred user first buys credits,
+/// at which point the referrer is either rewarded or the referral is rejected as abuse.
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone, Copy)]
+pub enum ReferralStatus {
+    Pending,
+    Rewarded,
+    Rejected,
+}
+
+impl ReferralStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ReferralStatus::Pending => "Pending",
+            ReferralStatus::Rewarded => "Rewarded",
+            ReferralStatus::Rejected => "Rejected"
+        }
+    }
+}
+
+/// How a user's referrals have worked out so far
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct ReferralStats {
+    /// the code new users sign up with to be counted as referred by this user
+    pub referral_code: String,
+    pub pending: u32,
+    pub rewarded: u32,
+    pub rejected: u32,
+    /// the bonus credits this user got for their referrals
+    pub credits_earned: i64,
+}
@@ -3,7 +3,6 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, PartialEq)]
 #[derive(Deserialize, Serialize)]
 pub struct ApplicationStopped {
-    pub license_key: String,
     pub session_id: i64,
     pub did_finish: bool,
     pub unknown_crash_occurred: Option<bool>,
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+/// An admin's request for a new stripe coupon. Exactly one of `percent_off` and `amount_off`
+/// has to be set.
+#[derive(Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+pub struct NewCoupon {
+    pub name: Option<String>,
+    pub percent_off: Option<f64>,
+    /// in cents
+    pub amount_off: Option<i64>,
+    pub max_redemptions: Option<i64>,
+    /// unix timestamp after which the coupon can no longer be redeemed
+    pub redeem_by: Option<i64>,
+}
+
+/// An admin's request for a new customer facing code for an existing coupon
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct NewPromotionCode {
+    pub coupon_id: String,
+    /// what customers type in at checkout
+    pub code: String,
+    /// groups codes in the redemption reports, e.g. one code per flyer of the same campaign
+    pub campaign: Option<String>,
+    pub max_redemptions: Option<i64>,
+    pub expires_at: Option<i64>,
+}
@@ -3,9 +3,10 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, PartialEq, Eq)]
 #[derive(Deserialize, Serialize)]
 pub struct RegistrationNotification {
-    pub license_key: String,
     pub session_id: i64,
     pub course_id: u32,
     pub course_section: String,
     pub timestamp: i64,
+    /// lets a retried notification be recognised even if the client lost track of what it sent
+    pub idempotency_key: Option<String>,
 }
\ No newline at end of file
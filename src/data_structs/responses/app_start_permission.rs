@@ -1,8 +1,9 @@
 use serde::{Deserialize, Serialize};
 use crate::data_structs::app_config::UserApplicationSettings;
 
-use crate::data_structs::grant_level::GrantLevel;
+use crate::data_structs::grant_level::{GrantLevel, SessionCapabilities};
 use crate::data_structs::responses::signable_data::SignableData;
+use crate::database::LaunchSession;
 
 #[derive(Debug)]
 #[derive(Deserialize, Serialize)]
@@ -11,6 +12,12 @@ pub struct ApplicationStartPermission {
     membership_level: GrantLevel,
     user_app_settings: UserApplicationSettings,
     session_id: i64,
+    /// authenticates the rest of the app's requests for this session, see [crate::api::authentication::AppSession]
+    session_token: String,
+    /// signs the rest of the app's requests for this session, see [crate::api::replay_guard::ReplayGuard].
+    /// Unlike the session token it is never sent again
+    request_signing_key: String,
+    capabilities: SessionCapabilities,
     response_timestamp: i64,
 }
 
@@ -22,8 +29,17 @@ pub struct SignedApplicationStartPermission {
 }
 
 impl ApplicationStartPermission {
-    pub fn new(kerberos_username: String, membership_level: GrantLevel, user_app_settings: UserApplicationSettings, session_id: i64, response_timestamp: i64) -> Self {
-        Self { kerberos_username, membership_level, user_app_settings, session_id, response_timestamp }
+    pub fn new(kerberos_username: String, user_app_settings: UserApplicationSettings, session: LaunchSession, session_token: String, response_timestamp: i64) -> Self {
+        Self {
+            kerberos_username,
+            membership_level: session.grant.level,
+            user_app_settings,
+            session_id: session.session_id,
+            session_token,
+            request_signing_key: session.request_signing_key,
+            capabilities: session.grant.capabilities,
+            response_timestamp
+        }
     }
 }
 
@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+/// How often a promotion code was redeemed in a successful checkout and what it brought in
+#[derive(Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct PromotionCodeReport {
+    pub promotion_code_id: String,
+    pub code: String,
+    pub coupon_id: String,
+    pub active: bool,
+    pub redemptions: u32,
+    pub credits_sold: i64,
+    /// in dollars, after the discount
+    pub revenue: f64,
+}
+
+/// The redemptions of every promotion code of a campaign added up
+#[derive(Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct CampaignReport {
+    /// `None` for codes that were created without a campaign
+    pub campaign: Option<String>,
+    pub redemptions: u32,
+    pub credits_sold: i64,
+    pub revenue: f64,
+    pub promotion_codes: Vec<PromotionCodeReport>,
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use crate::data_structs::responses::signable_data::SignableData;
+
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+    pub request_id: String,
+    pub response_timestamp: i64,
+}
+
+#[derive(Debug)]
+#[derive(Deserialize, Serialize)]
+pub struct SignedErrorResponse {
+    pub data: ErrorResponse,
+    pub signature: String
+}
+
+/// Machine readable reason for a failed request. Clients should branch on this rather than on
+/// the human readable message, which may change at any time.
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    /// the google authorization code of a login is invalid, expired or was already used
+    InvalidAuthorizationCode,
+    SessionAlreadyActive,
+    InsufficientCredits,
+    RequestReplayed,
+    ServiceUnavailable,
+    InternalError,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, message: String, request_id: String, response_timestamp: i64) -> Self {
+        Self { code, message, request_id, response_timestamp }
+    }
+}
+
+impl SignableData for ErrorResponse {
+
+}
@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+
+use crate::data_structs::license_key::LicenseKey;
+
+/// Returned when a license key is created. This is the only time the full key is shown.
+#[derive(Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+pub struct NewLicenseKeyResponse {
+    pub license_key: LicenseKey,
+    pub key: String
+}
@@ -5,6 +5,5 @@ use crate::data_structs::user::User;
 #[derive(Debug, PartialEq)]
 #[derive(Deserialize, Serialize)]
 pub struct WebRegisterResponse {
-    pub(crate) jwt_cookie: String,
     pub(crate) user: User
 }
\ No newline at end of file
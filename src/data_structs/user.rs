@@ -9,9 +9,28 @@ pub struct User {
     pub stripe_id: CustomerId,
     pub given_name: String,
     pub family_name: String,
-    pub authentication_key: String,
+    /// the full default license key. only a hash of it is stored, so this is only set in the
+    /// response that created or reset the key and can't be shown again afterwards.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license_key: Option<String>,
     pub profile_image_url: String,
     pub current_credits: i64,
     pub demo_expired_at: Option<i64>,
+    /// how many sections the user registered during their trial
+    pub trial_registrations_used: u32,
+    /// what's left of the user's trial under the current demo policy, only filled in where the
+    /// policy is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trial_allowance: Option<TrialAllowance>,
     pub registration_timestamp: i64
 }
+
+/// What's left of a user's trial, see [crate::grant_policy::DemoPolicy]
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy)]
+pub struct TrialAllowance {
+    pub registrations_remaining: u32,
+    /// when the trial runs out, `None` if it doesn't
+    pub ends_at: Option<i64>,
+}
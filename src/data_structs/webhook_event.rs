@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// A stripe webhook event as it sits in the inbox. Events are stored when they're received and
+/// processed from there, so one that failed can be retried or replayed later.
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct WebhookEventStatus {
+    pub event_id: String,
+    pub event_type: String,
+    pub received_at: i64,
+    pub attempts: u32,
+    /// why the last attempt failed, cleared once the event was processed
+    pub last_error: Option<String>,
+    /// when the event is retried next, `None` once it was processed or retries gave up
+    pub next_attempt_at: Option<i64>,
+    pub processed_at: Option<i64>,
+}
@@ -2,23 +2,76 @@ use std::fmt::Debug;
 use std::time::Duration;
 
 use rand::Rng;
-use sqlx::{Error, Executor, MySql, Pool, Row};
-use sqlx::mysql::{MySqlPoolOptions, MySqlQueryResult, MySqlRow};
+use sqlx::{MySql, Pool, Row};
+use sqlx::mysql::{MySqlPoolOptions, MySqlRow};
 
 use std::str::FromStr;
 use crate::data_structs::app_config::UserApplicationSettings;
 use crate::data_structs::bu_course::{BUCourse, BUCourseSection};
 use crate::data_structs::bu_course::CourseSection;
+use crate::data_structs::credit_ledger::{CreditEntryType, NewCreditEntry};
 use crate::data_structs::device_meta::DeviceMeta;
 use crate::data_structs::grant_level::GrantLevel;
+use crate::data_structs::license_key::LicenseKey;
 use crate::data_structs::requests::application_start::ApplicationStart;
 use crate::data_structs::requests::application_stopped::ApplicationStopped;
+use crate::data_structs::requests::registration_notification::RegistrationNotification;
 use crate::data_structs::requests::session_ping::SessionPing;
 use crate::data_structs::semester::{Semester, SemesterSeason};
 use crate::data_structs::user::User;
+use crate::encrypted_signing::{constant_time_eq, generate_random_token, JwtClaims, sha256_hex};
 use crate::google_oauth::{GoogleAccessToken, GoogleUserInfo};
+use crate::grant_policy::{decide_grant, DemoPolicy, Grant, GrantRequest, TrialState};
 use crate::stripe_util::StripeHandler;
 
+pub use error::DatabaseError;
+
+mod credit_ledger;
+mod error;
+pub mod migrations;
+mod promotions;
+pub mod purchases;
+mod referrals;
+#[cfg(test)]
+pub(crate) mod test_database;
+mod webhook_events;
+
+// how many leading characters of a license key are stored in plaintext to look it up by
+const LICENSE_KEY_PREFIX_LENGTH: usize = 8;
+const DEFAULT_LICENSE_KEY_LABEL: &str = "Default";
+
+/// The outcome of presenting a refresh token to [DatabasePool::rotate_refresh_token]
+#[derive(Debug)]
+pub enum RefreshTokenRotation {
+    /// The token was valid and has been exchanged for a new one in the same family
+    Rotated { kerberos_username: String, refresh_token: String },
+    /// The token had already been exchanged before, so it was most likely stolen. Its whole
+    /// family has been revoked.
+    Reused { kerberos_username: String },
+    /// The token is unknown, expired or revoked
+    Invalid,
+}
+
+/// The outcome of [DatabasePool::create_session]
+#[derive(Debug)]
+pub struct LaunchSession {
+    pub session_id: i64,
+    pub grant: Grant,
+    /// the key the app signs the rest of its requests for this session with, see
+    /// [crate::api::replay_guard::ReplayGuard]. It is only handed out once, in the start permission
+    pub request_signing_key: String,
+}
+
+/// The outcome of [DatabasePool::mark_course_registered]
+#[derive(Debug)]
+pub struct CourseRegistration {
+    /// the timestamp of the response to the notification that first registered the course, so
+    /// that a retried notification can be answered with an identical signed response
+    pub response_timestamp: i64,
+    /// whether the course had already been registered before this notification
+    pub duplicate: bool,
+}
+
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct DatabasePool {
@@ -27,133 +80,346 @@ pub struct DatabasePool {
 }
 impl DatabasePool {
 
-    pub async fn new(host: &str, port: i16, user: &str, pass: &str, database: &str) -> Self {
+    /// Connects to the database. Any failure to connect is reported as
+    /// [DatabaseError::ConnectionLost], whatever the driver's reason was.
+    pub async fn new(host: &str, port: i16, user: &str, pass: &str, database: &str) -> Result<Self, DatabaseError> {
         let connection_url = format!("mysql://{user}:{pass}@{host}:{port}/{database}");
 
-        let pool = match MySqlPoolOptions::new()
+        let pool = MySqlPoolOptions::new()
             .max_connections(5)
             .min_connections(5)
             .acquire_timeout(Duration::from_secs(5))
-            .connect(&connection_url).await {
-                    Ok(res) => res,
-                    Err(_) => panic!("Unable to connect to the database")
-                };
+            .connect(&connection_url).await
+            .map_err(DatabaseError::ConnectionLost)?;
 
-        DatabasePool { pool: pool, connection_url}
+        Ok(DatabasePool { pool: pool, connection_url})
     }
 
-    pub async fn init(&self) {
-        Self::create_tables(&self).await;
+    pub async fn init(&self) -> Result<(), DatabaseError> {
+        self.run_migrations().await
     }
 
-    pub async fn get_all_course_departments(&self) -> Vec<String> {
+    pub async fn get_all_course_departments(&self) -> Result<Vec<String>, DatabaseError> {
         let results = sqlx::query("SELECT DISTINCT department FROM course_catalog;")
-            .fetch_all(&self.pool).await.expect("Error fetching rows for the get_all_course_departments query");
+            .fetch_all(&self.pool).await?;
         let mut departments: Vec<String> = Vec::new();
         for result in &results {
             let department = result.get_unchecked::<String, &str>("department");
             departments.push(department);
         }
-        return departments;
+        return Ok(departments);
     }
 
-    pub async fn is_authenticated(&self, auth_key: &String) -> Option<String> {
-        let result = sqlx::query("SELECT kerberos_username from users WHERE authentication_key=?")
-            .bind(&auth_key)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the is_authenticated query");
-        if !result.is_empty() {
-            let row = result.get(0).unwrap();
-            let kerberos_username = row.get_unchecked::<String, &str>("kerberos_username");
-            return Option::from(kerberos_username);
-        } else {
-            return None;
+    /// Looks the license key up by its public prefix and compares the hash of the key against
+    /// every match in constant time. Revoked keys are never returned.
+    pub async fn find_license_key(&self, auth_key: &str) -> Result<Option<LicenseKey>, DatabaseError> {
+        // the key comes straight from the client, it may be too short or not even ascii
+        let key_prefix = match auth_key.get(..LICENSE_KEY_PREFIX_LENGTH) {
+            Some(key_prefix) => key_prefix,
+            None => return Ok(None)
+        };
+
+        let result = sqlx::query("SELECT * from license_keys WHERE key_prefix=? AND revoked_at IS NULL")
+            .bind(key_prefix)
+            .fetch_all(&self.pool).await?;
+
+        let key_hash = sha256_hex(auth_key);
+        for row in result {
+            let stored_hash = row.get_unchecked::<String, &str>("key_hash");
+            if constant_time_eq(stored_hash.as_bytes(), key_hash.as_bytes()) {
+                return Ok(Some(Self::license_key_from_row(&row)));
+            }
+        }
+        return Ok(None);
+    }
+
+    /// Creates a new license key for the user, returning it along with the key itself. This is
+    /// the only time the key is available in plaintext.
+    pub async fn create_license_key(&self, kerberos_username: &str, label: &str, device_bound: bool) -> Result<(LicenseKey, String), DatabaseError> {
+        let auth_key: String = self.generate_new_key();
+        let key_prefix: String = auth_key.chars().take(LICENSE_KEY_PREFIX_LENGTH).collect();
+        let created_at = chrono::Local::now().timestamp();
+
+        let result = sqlx::query(r#"
+            INSERT INTO license_keys
+            (kerberos_username, label, key_prefix, key_hash, device_bound, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+        "#)
+            .bind(kerberos_username)
+            .bind(label)
+            .bind(&key_prefix)
+            .bind(sha256_hex(&auth_key))
+            .bind(device_bound)
+            .bind(created_at)
+            .execute(&self.pool).await?;
+
+        let license_key = LicenseKey {
+            license_key_id: result.last_insert_id() as u32,
+            kerberos_username: kerberos_username.to_string(),
+            label: label.to_string(),
+            key_prefix,
+            device_bound,
+            device_fingerprint: None,
+            created_at,
+            last_used_at: None,
+            revoked_at: None
+        };
+        return Ok((license_key, auth_key));
+    }
+
+    /// Every license key of the user, including revoked ones, newest first
+    pub async fn get_license_keys(&self, kerberos_username: &str) -> Result<Vec<LicenseKey>, DatabaseError> {
+        let result = sqlx::query("SELECT * from license_keys WHERE kerberos_username=? ORDER BY created_at DESC, license_key_id DESC")
+            .bind(kerberos_username)
+            .fetch_all(&self.pool).await?;
+        return Ok(result.iter().map(Self::license_key_from_row).collect());
+    }
+
+    pub async fn rename_license_key(&self, kerberos_username: &str, license_key_id: u32, label: &str) -> Result<(), DatabaseError> {
+        let result = sqlx::query("UPDATE license_keys SET label=? WHERE license_key_id=? AND kerberos_username=?")
+            .bind(label)
+            .bind(license_key_id)
+            .bind(kerberos_username)
+            .execute(&self.pool).await?;
+
+        if result.rows_affected() == 0 {
+            // mysql doesn't count rows whose value didn't change, so make sure the key exists
+            self.get_license_key(kerberos_username, license_key_id).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn revoke_license_key(&self, kerberos_username: &str, license_key_id: u32) -> Result<(), DatabaseError> {
+        let result = sqlx::query(r#"
+            UPDATE license_keys SET revoked_at=?
+            WHERE license_key_id=? AND kerberos_username=? AND revoked_at IS NULL
+        "#)
+            .bind(chrono::Local::now().timestamp())
+            .bind(license_key_id)
+            .bind(kerberos_username)
+            .execute(&self.pool).await?;
+
+        if result.rows_affected() == 0 {
+            return Err(DatabaseError::NotFound("License key not found or already revoked".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn get_license_key(&self, kerberos_username: &str, license_key_id: u32) -> Result<LicenseKey, DatabaseError> {
+        let result = sqlx::query("SELECT * from license_keys WHERE license_key_id=? AND kerberos_username=?")
+            .bind(license_key_id)
+            .bind(kerberos_username)
+            .fetch_optional(&self.pool).await?;
+
+        return match result {
+            Some(row) => Ok(Self::license_key_from_row(&row)),
+            None => Err(DatabaseError::NotFound("License key not found".to_string()))
+        };
+    }
+
+    /// Records that the key was used to launch the app. A device bound key that isn't bound yet
+    /// is bound to the device it was used on. Returns false if the key is bound to another device.
+    pub async fn record_license_key_use(&self, license_key: &LicenseKey, device_fingerprint: &str) -> Result<bool, DatabaseError> {
+        if license_key.device_bound {
+            // only the first device to use the key gets to bind it
+            sqlx::query("UPDATE license_keys SET device_fingerprint=? WHERE license_key_id=? AND device_fingerprint IS NULL")
+                .bind(device_fingerprint)
+                .bind(license_key.license_key_id)
+                .execute(&self.pool).await?;
+        }
+
+        let result = sqlx::query(r#"
+            UPDATE license_keys SET last_used_at=?
+            WHERE license_key_id=? AND (device_bound=0 OR device_fingerprint=?)
+        "#)
+            .bind(chrono::Local::now().timestamp())
+            .bind(license_key.license_key_id)
+            .bind(device_fingerprint)
+            .execute(&self.pool).await?;
+
+        return Ok(result.rows_affected() > 0);
+    }
+
+    fn license_key_from_row(row: &MySqlRow) -> LicenseKey {
+        LicenseKey {
+            license_key_id: row.get_unchecked::<u32, &str>("license_key_id"),
+            kerberos_username: row.get_unchecked::<String, &str>("kerberos_username"),
+            label: row.get_unchecked::<String, &str>("label"),
+            key_prefix: row.get_unchecked::<String, &str>("key_prefix"),
+            device_bound: row.get_unchecked::<bool, &str>("device_bound"),
+            device_fingerprint: row.get_unchecked::<Option<String>, &str>("device_fingerprint"),
+            created_at: row.get_unchecked::<i64, &str>("created_at"),
+            last_used_at: row.get_unchecked::<Option<i64>, &str>("last_used_at"),
+            revoked_at: row.get_unchecked::<Option<i64>, &str>("revoked_at"),
         }
     }
 
-    pub async fn mark_demo_over(&self, kerberos_username: &String) {
+    pub async fn mark_demo_over(&self, kerberos_username: &String) -> Result<(), DatabaseError> {
         sqlx::query("UPDATE users SET demo_expired_at=? WHERE kerberos_username=?")
             .bind(&chrono::Local::now().timestamp())
             .bind(&kerberos_username)
-            .execute(&self.pool).await
-            .expect("Error executing the mark_demo_over query");
+            .execute(&self.pool).await?;
+        Ok(())
     }
 
-    pub async fn session_ping(&self, session_ping: &SessionPing) -> Result<&str, &str> {
+    pub async fn session_ping(&self, kerberos_username: &str, session_ping: &SessionPing) -> Result<(), DatabaseError> {
 
-        // first some sanity checks to ensure the session is still active
-        if !Self::is_session_alive(&self, session_ping.session_id).await {
-            return Err("Session not found or is no longer alive")
-        }
+        // first some sanity checks to ensure the session is still active and belongs to the caller
+        self.ensure_session_owner(kerberos_username, session_ping.session_id).await?;
 
         // now update ping
         sqlx::query("UPDATE application_launch_session SET last_ping=? WHERE session_id=?")
             .bind(&session_ping.timestamp)
             .bind(&session_ping.session_id)
-            .execute(&self.pool).await
-            .expect("Error executing the session_ping query");
+            .execute(&self.pool).await?;
 
-        return Ok("Pong!")
+        return Ok(())
     }
 
 
-    pub async fn mark_course_registered(&self, kerberos_username: &String, session_id: i64, registration_timestamp: i64, course_id: u32, course_section: &str) -> bool {
-        // sanity check to ensure session is alive
-        if !Self::is_session_alive(&self, session_id).await {
-            return false;
+    /// Marks the course section of the session as registered and charges a credit for it, both in
+    /// one transaction. Each (session, course, section) is only ever charged once: notifying about
+    /// it again, or reusing the idempotency key, returns the original registration instead.
+    pub async fn mark_course_registered(&self, kerberos_username: &str, notification: &RegistrationNotification, response_timestamp: i64) -> Result<CourseRegistration, DatabaseError> {
+        let session_id = notification.session_id;
+        let course_id = notification.course_id;
+        let course_section = notification.course_section.as_str();
+        let idempotency_key = notification.idempotency_key.as_deref();
+
+        // sanity check to ensure session is alive and belongs to the caller, so credits can't be
+        // spent against someone else's session
+        self.ensure_session_owner(kerberos_username, session_id).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        if let Some(idempotency_key) = idempotency_key {
+            let result = sqlx::query(r#"
+                SELECT course_id, course_section, register_timestamp, response_timestamp
+                FROM app_session_courses
+                WHERE session_id=? AND idempotency_key=?
+                FOR UPDATE
+            "#)
+                .bind(&session_id)
+                .bind(idempotency_key)
+                .fetch_optional(&mut *tx).await?;
+
+            if let Some(row) = result {
+                if row.get_unchecked::<u32, &str>("course_id") != course_id
+                        || row.get_unchecked::<String, &str>("course_section") != course_section {
+                    return Err(DatabaseError::Conflict("The idempotency key was already used for a different course".to_string()));
+                }
+                let register_timestamp = row.get_unchecked::<Option<i64>, &str>("register_timestamp");
+                let original_response_timestamp = row.get_unchecked::<Option<i64>, &str>("response_timestamp");
+                return Ok(CourseRegistration {
+                    response_timestamp: original_response_timestamp.or(register_timestamp).unwrap_or(response_timestamp),
+                    duplicate: true
+                });
+            }
+        }
+
+        // lock the course row so concurrent retries wait for this one to finish
+        let result = sqlx::query(r#"
+            SELECT register_timestamp, response_timestamp
+            FROM app_session_courses
+            WHERE session_id=? AND course_id=? AND course_section=?
+            FOR UPDATE
+        "#)
+            .bind(&session_id)
+            .bind(&course_id)
+            .bind(course_section)
+            .fetch_optional(&mut *tx).await?;
+
+        let row = match result {
+            Some(row) => row,
+            None => return Err(DatabaseError::NotFound("This course is not part of the session".to_string()))
+        };
+        if let Some(register_timestamp) = row.get_unchecked::<Option<i64>, &str>("register_timestamp") {
+            let original_response_timestamp = row.get_unchecked::<Option<i64>, &str>("response_timestamp");
+            return Ok(CourseRegistration {
+                // registrations from before response timestamps were stored fall back to the registration time
+                response_timestamp: original_response_timestamp.unwrap_or(register_timestamp),
+                duplicate: true
+            });
+        }
+
+        // lock the session so parallel notifications can't both take the last registration
+        let result = sqlx::query(r#"
+            SELECT planner_session, grant_type, allowed_registrations, reserved_credits,
+                (SELECT COUNT(*) FROM app_session_courses
+                 WHERE session_id=? AND register_timestamp IS NOT NULL) AS registrations
+            FROM application_launch_session WHERE session_id=? FOR UPDATE
+        "#)
+            .bind(&session_id)
+            .bind(&session_id)
+            .fetch_optional(&mut *tx).await?;
+
+        let row = match result {
+            Some(row) => row,
+            None => {
+                eprintln!("Error, session not found but this should never happen!");
+                return Err(DatabaseError::NotFound("Session not found".to_string()));
+            }
+        };
+        let planner_session = row.get_unchecked::<bool, &str>("planner_session");
+        let grant_type = row.get_unchecked::<String, &str>("grant_type");
+        let allowed_registrations = row.get_unchecked::<i64, &str>("allowed_registrations");
+        let reserved_credits = row.get_unchecked::<i64, &str>("reserved_credits");
+        if row.get_unchecked::<i64, &str>("registrations") >= allowed_registrations {
+            return Err(DatabaseError::InsufficientCredits(
+                format!("This session only allows {} registrations", allowed_registrations)));
         }
 
         sqlx::query(r#"
             UPDATE app_session_courses
-            SET register_timestamp=?
+            SET register_timestamp=?, response_timestamp=?, idempotency_key=?
             WHERE session_id=?
             AND course_id=?
             AND course_section=?
         "#)
-            .bind(&registration_timestamp)
+            .bind(&notification.timestamp)
+            .bind(&response_timestamp)
+            .bind(idempotency_key)
             .bind(&session_id)
             .bind(&course_id)
             .bind(course_section)
-            .execute(&self.pool).await
-            .expect("Error executing the mark_course_registered query 1");
-
-        // check if this was a planner session and only subtract credits if this was a real registration
-        let result: Vec<MySqlRow> = sqlx::query(r#"
-            SELECT planner_session from application_launch_session WHERE session_id=?
-        "#).bind(&session_id)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the mark_course_registered query 2");
+            .execute(&mut *tx).await?;
 
-        if result.is_empty() {
-            eprintln!("Error, session not found but this should never happen!");
-            return false;
+        // only real registrations cost credits, the credit spent is the one held since the session started
+        if !planner_session && reserved_credits > 0 {
+            sqlx::query("UPDATE application_launch_session SET reserved_credits=reserved_credits-1 WHERE session_id=?")
+                .bind(&session_id)
+                .execute(&mut *tx).await?;
+
+            Self::record_credit_entry(&mut tx, NewCreditEntry {
+                launch_session_id: Some(session_id),
+                ..NewCreditEntry::new(kerberos_username, CreditEntryType::RegistrationSpend, -1)
+            }).await?;
+        } else if !planner_session && grant_type == GrantLevel::Demo.as_str() {
+            // demo registrations are paid for with the trial instead
+            sqlx::query("UPDATE users SET trial_registrations_used=trial_registrations_used+1 WHERE kerberos_username=?")
+                .bind(kerberos_username)
+                .execute(&mut *tx).await?;
         }
 
-        let planner_session: bool = result.get(0).unwrap().get_unchecked::<bool, &str>("planner_session");
-        if planner_session {
-            return true;
-        }
+        tx.commit().await?;
 
-        sqlx::query(r#"
-            UPDATE users SET current_credits=GREATEST(0,current_credits-1) WHERE kerberos_username=?;
-        "#)
-            .bind(kerberos_username)
-            .execute(&self.pool).await
-            .expect("Error executing the mark_course_registered query 3");
+        return Ok(CourseRegistration {
+            response_timestamp,
+            duplicate: false
+        });
+    }
 
-        return true;
+    pub async fn end_session(&self, kerberos_username: &str, session_data: &ApplicationStopped) -> Result<(), DatabaseError> {
+        // sanity check to ensure session is alive and belongs to the caller
+        self.ensure_session_owner(kerberos_username, session_data.session_id).await?;
+        return self.terminate_session(session_data).await;
     }
 
-    pub async fn end_session(&self, session_data: &ApplicationStopped) -> Result<&str, &str> {
-        // sanity check to ensure session is alive
-        if !Self::is_session_alive(&self, session_data.session_id).await {
-            return Err("Session not found or is no longer alive")
-        }
-        // update the session to inactive
-        sqlx::query("UPDATE application_launch_session SET is_active=0 WHERE session_id=?")
+    async fn terminate_session(&self, session_data: &ApplicationStopped) -> Result<(), DatabaseError> {
+        // update the session to inactive and release the credits it didn't use
+        sqlx::query("UPDATE application_launch_session SET is_active=0, reserved_credits=0 WHERE session_id=?")
             .bind(&session_data.session_id)
-            .execute(&self.pool).await
-            .expect("Error executing the end_session query");
+            .execute(&self.pool).await?;
 
         // write the session terminate data to the database
         sqlx::query(r#"
@@ -172,36 +438,68 @@ impl DatabasePool {
             .bind(&session_data.avg_sleep_time)
             .bind(&session_data.std_sleep_time)
             .bind(&session_data.timestamp)
-            .execute(&self.pool).await
-            .expect("Error executing the end_session query");
+            .execute(&self.pool).await?;
 
-        return Ok("OK")
+        return Ok(())
     }
 
-    pub async fn create_session(&self, session_data: &ApplicationStart, kerberos_username: &String, grant_level: &GrantLevel, planner_session: bool) -> i64 {
+    /// Starts a session for the user's current target courses with the grant decided by
+    /// [decide_grant] under the demo policy. The credits the grant reserves are held until the session ends, so the
+    /// client can't register more sections than the user paid for. Credits for sections that
+    /// were registered are spent, the rest are released again.
+    pub async fn create_session(&self, session_data: &ApplicationStart, kerberos_username: &String, license_key_id: u32, planner_session: bool, demo_policy: &DemoPolicy) -> Result<LaunchSession, DatabaseError> {
+        let courses = self.get_user_application_courses(kerberos_username).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let available_credits = Self::available_credits(&mut tx, kerberos_username).await?;
+        let row = sqlx::query(r#"
+            SELECT registration_timestamp, trial_registrations_used, demo_expired_at
+            FROM users WHERE kerberos_username=?
+        "#)
+            .bind(kerberos_username)
+            .fetch_one(&mut *tx).await?;
+
+        let grant = decide_grant(demo_policy, &GrantRequest {
+            available_credits,
+            trial: TrialState {
+                registration_timestamp: row.get_unchecked::<i64, &str>("registration_timestamp"),
+                registrations_used: row.get_unchecked::<u32, &str>("trial_registrations_used"),
+                demo_expired_at: row.get_unchecked::<Option<i64>, &str>("demo_expired_at")
+            },
+            planner_session,
+            section_count: courses.len() as u32,
+            now: chrono::Local::now().timestamp()
+        });
+
+        let request_signing_key = generate_random_token(64);
+
         // write the session data to the database and return the session_id key
         let result = sqlx::query(
             r#"INSERT INTO application_launch_session
-                (kerberos_username, device_ip, device_name, device_os, system_arch,
-                device_cores, device_clock_speed, grant_type, planner_session, launch_time)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#)
+                (kerberos_username, license_key_id, device_ip, device_name, device_os, system_arch,
+                device_cores, device_clock_speed, device_fingerprint, grant_type, planner_session,
+                launch_time, allowed_registrations, reserved_credits, request_signing_key)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#)
             .bind(kerberos_username)
+            .bind(license_key_id)
             .bind(&session_data.device_meta.ip)
             .bind(&session_data.device_meta.name)
             .bind(&session_data.device_meta.os)
             .bind(&session_data.device_meta.system_arch)
             .bind(&session_data.device_meta.core_count)
             .bind(&session_data.device_meta.cpu_speed)
-            .bind(&grant_level.to_string())
+            .bind(session_data.device_meta.fingerprint())
+            .bind(grant.level.as_str())
             .bind(&planner_session)
             .bind(chrono::Local::now().timestamp())
-            .execute(&self.pool).await
-            .expect("Error executing the create_session query");
+            .bind(&grant.capabilities.allowed_registrations)
+            .bind(&grant.reserved_credits)
+            .bind(&request_signing_key)
+            .execute(&mut *tx).await?;
 
         let session_id = result.last_insert_id() as i64;
 
-        let courses = self.get_user_application_courses(kerberos_username).await;
-
         // write the courses to the database as well
         for bu_course_section in &courses {
             sqlx::query(r#"
@@ -212,22 +510,26 @@ impl DatabasePool {
                 .bind(&session_id)
                 .bind(&bu_course_section.course.course_id)
                 .bind(&bu_course_section.section.section)
-                .execute(&self.pool).await
-                .expect("Error executing the create_session query");
+                .execute(&mut *tx).await?;
         }
 
-        return session_id;
+        tx.commit().await?;
+
+        return Ok(LaunchSession {
+            session_id,
+            grant,
+            request_signing_key
+        });
     }
 
     // todo figure out demo credit management...
-    pub async fn get_user(&self, kerberos_username: &String) -> Option<User> {
+    pub async fn get_user(&self, kerberos_username: &String) -> Result<User, DatabaseError> {
         let result: Vec<MySqlRow> = sqlx::query("SELECT * from users WHERE kerberos_username=?")
             .bind(kerberos_username)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the get_user query");
+            .fetch_all(&self.pool).await?;
 
         if result.is_empty() {
-            return None;
+            return Err(DatabaseError::NotFound(format!("User {} not found", kerberos_username)));
         } else {
             let row = result.get(0).unwrap();
             let stripe_id = row.get_unchecked::<String, &str>("stripe_id");
@@ -236,17 +538,19 @@ impl DatabasePool {
                 stripe_id: stripe_id.as_str().parse().unwrap(),
                 given_name: row.get_unchecked::<String, &str>("given_name"),
                 family_name: row.get_unchecked::<String, &str>("family_name"),
-                authentication_key: row.get_unchecked::<String, &str>("authentication_key"),
+                license_key: None,
                 profile_image_url: row.get_unchecked::<String, &str>("profile_image_url"),
                 current_credits: row.get_unchecked::<i64, &str>("current_credits"),
                 demo_expired_at: row.get_unchecked::<Option<i64>, &str>("demo_expired_at"),
+                trial_registrations_used: row.get_unchecked::<u32, &str>("trial_registrations_used"),
+                trial_allowance: None,
                 registration_timestamp: row.get_unchecked::<i64, &str>("registration_timestamp")
             };
-            return Option::from(user);
+            return Ok(user);
         }
     }
 
-    pub async fn create_purchase_session(&self, kerberos_username: &String, quantity: u64, subtotal: f64, session_id: &str) {
+    pub async fn create_purchase_session(&self, kerberos_username: &String, quantity: u64, subtotal: f64, session_id: &str) -> Result<(), DatabaseError> {
         sqlx::query(r#"
             INSERT INTO user_purchase_sessions
             (kerberos_username, session_id, quantity, subtotal, total,
@@ -263,83 +567,104 @@ impl DatabasePool {
             .bind(0)
             .bind(&chrono::Local::now().timestamp())
             .bind(None::<i64>)
-            .execute(&self.pool).await
-            .expect("Error executing the create_purchase_session query");
+            .execute(&self.pool).await?;
+        Ok(())
     }
 
-    pub async fn close_purchase_session(&self, session_id: &str, success: bool, total: Option<f64>, coupon: Option<String>) -> bool {
-        // get quantity
-        let result: Vec<MySqlRow> = sqlx::query("SELECT kerberos_username, quantity from user_purchase_sessions WHERE session_id=?")
-            .bind(&session_id)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the close_purchase_session query");
+    /// The user a stripe customer belongs to, if any
+    pub async fn get_username_by_stripe_id(&self, stripe_id: &str) -> Result<Option<String>, DatabaseError> {
+        let result = sqlx::query("SELECT kerberos_username FROM users WHERE stripe_id=?")
+            .bind(stripe_id)
+            .fetch_optional(&self.pool).await?;
+        return Ok(result.map(|row| row.get_unchecked::<String, &str>("kerberos_username")));
+    }
 
-        if !result.is_empty() {
-            let row = result.get(0).unwrap();
-            let quantity = row.get_unchecked::<i64, &str>("quantity");
-            let kerberos_username = row.get_unchecked::<String, &str>("kerberos_username");
+    pub async fn set_stripe_id(&self, kerberos_username: &str, stripe_id: &str) -> Result<(), DatabaseError> {
+        sqlx::query("UPDATE users SET stripe_id=? WHERE kerberos_username=?")
+            .bind(stripe_id)
+            .bind(kerberos_username)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
 
-            // update
-            sqlx::query(r#"
-                UPDATE user_purchase_sessions
-                SET succeeded=?, processed=1, total=?, coupon=?, finish_timestamp=?
-                WHERE session_id=?
-            "#)
-                .bind(&success)
-                .bind(&total)
-                .bind(&chrono::Local::now().timestamp())
-                .bind(&kerberos_username)
-                .bind(&coupon)
-                .bind(&session_id)
-                .execute(&self.pool).await
-                .expect("Error executing the close_purchase_session query");
-
-            if success {
-                // add credits
-                sqlx::query("UPDATE users SET current_credits=current_credits+? WHERE kerberos_username=?")
-                    .bind(&quantity)
-                    .bind(&kerberos_username)
-                    .execute(&self.pool).await
-                    .expect("Error executing the close_purchase_session query");
-
-                // mark demo over
-                self.mark_demo_over(&kerberos_username).await;
-            }
+    /// An account from before google ids were stored that belongs to `email`. Those accounts
+    /// are named after the local part of the address they signed up with, but any google
+    /// account could sign up back then, so one is only handed over if the address it was
+    /// created with on stripe is the exact same address.
+    async fn find_legacy_user(&self, email: &str, local_part: &str, stripe_handler: &StripeHandler) -> Result<Vec<MySqlRow>, DatabaseError> {
+        let result: Vec<MySqlRow> = sqlx::query("SELECT * from users WHERE google_id IS NULL AND kerberos_username=?")
+            .bind(local_part)
+            .fetch_all(&self.pool).await?;
+        let row = match result.get(0) {
+            Some(row) => row,
+            None => return Ok(result)
+        };
 
-            return true;
+        let kerberos_username = row.get_unchecked::<String, &str>("kerberos_username");
+        let customer_email = stripe_handler.get_customer_email(&row.get_unchecked::<String, &str>("stripe_id")).await
+            .map_err(|err| {
+                eprintln!("Error looking up the stripe customer of {}: {}", kerberos_username, err);
+                DatabaseError::Unavailable(format!("Couldn't confirm who account {} belongs to", kerberos_username))
+            })?;
+        if customer_email.is_some_and(|customer_email| customer_email.to_lowercase() == email) {
+            return Ok(result);
         }
+        return Ok(Vec::new());
+    }
 
-        return false;
+    async fn username_taken(&self, kerberos_username: &str) -> Result<bool, DatabaseError> {
+        let result = sqlx::query("SELECT kerberos_username FROM users WHERE kerberos_username=?")
+            .bind(kerberos_username)
+            .fetch_optional(&self.pool).await?;
+        Ok(result.is_some())
     }
 
     /// Creates a new user in the database and on stripe if they don't already exist, or updates their info if they do
     /// Returns the user object and a bool indicating whether or not a new user was created
-    pub async fn create_or_update_user(&self, user_info: &GoogleUserInfo, google_access_token: &GoogleAccessToken, stripe_handler: &StripeHandler) -> User {
+    /// `referral_code` is only used when the user is new
+    pub async fn create_or_update_user(&self, user_info: &GoogleUserInfo, google_access_token: &GoogleAccessToken, stripe_handler: &StripeHandler, referral_code: Option<&str>) -> Result<User, DatabaseError> {
 
         let registration_timestamp = chrono::Local::now().timestamp();
-        let kerberos_username: &str = user_info.email.split("@").collect::<Vec<&str>>()[0];
-
-        // first check if this user already exists
-        let result: Vec<MySqlRow> = sqlx::query("SELECT * from users WHERE kerberos_username=?")
-            .bind(kerberos_username)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the create_or_update_user query");
+        let email = user_info.email.to_lowercase();
+        let local_part: &str = email.split('@').next().unwrap_or_default();
+
+        // first check if this user already exists. accounts are keyed on the google id so that a
+        // renamed email address still maps to the same account
+        let mut result: Vec<MySqlRow> = sqlx::query("SELECT * from users WHERE google_id=?")
+            .bind(&user_info.id)
+            .fetch_all(&self.pool).await?;
+        if result.is_empty() {
+            result = self.find_legacy_user(&email, local_part, stripe_handler).await?;
+        }
 
         // if this user already exists, update the user in db and on stripe and return
         if !result.is_empty() {
 
             // first load the user as is directly from the database
             let row: &MySqlRow = result.get(0).unwrap();
+
+            // keep the google id and the address it currently signs in with on the account
+            if row.get_unchecked::<Option<String>, &str>("google_id").is_none()
+                    || row.get_unchecked::<Option<String>, &str>("email").as_deref() != Some(email.as_str()) {
+                sqlx::query("UPDATE users SET google_id=?, email=? WHERE kerberos_username=?")
+                    .bind(&user_info.id)
+                    .bind(&email)
+                    .bind(row.get_unchecked::<String, &str>("kerberos_username"))
+                    .execute(&self.pool).await?;
+            }
+
             let stripe_id = row.get_unchecked::<String, &str>("stripe_id");
             let mut user = User {
-                kerberos_username: kerberos_username.to_string(),
+                kerberos_username: row.get_unchecked::<String, &str>("kerberos_username"),
                 stripe_id: stripe_id.as_str().parse().unwrap(),
                 given_name: row.get_unchecked::<String, &str>("given_name"),
                 family_name: row.get_unchecked::<String, &str>("family_name"),
-                authentication_key: row.get_unchecked::<String, &str>("authentication_key"),
+                license_key: None,
                 profile_image_url: row.get_unchecked::<String, &str>("profile_image_url"),
                 current_credits: row.get_unchecked::<i64, &str>("current_credits"),
                 demo_expired_at: row.get_unchecked::<Option<i64>, &str>("demo_expired_at"),
+                trial_registrations_used: row.get_unchecked::<u32, &str>("trial_registrations_used"),
+                trial_allowance: None,
                 registration_timestamp: row.get_unchecked::<i64, &str>("registration_timestamp")
             };
 
@@ -363,8 +688,7 @@ impl DatabasePool {
                     .bind(&user.family_name)
                     .bind(&user.profile_image_url)
                     .bind(&user.kerberos_username)
-                    .execute(&self.pool).await
-                    .expect("Error executing the create_or_update_user query");
+                    .execute(&self.pool).await?;
 
                 // update the updated user on stripe if their name changes
                 // since thats the only thing on stripe that can change
@@ -373,42 +697,51 @@ impl DatabasePool {
                 }
             }
 
-            return user; //finally return
+            return Ok(user); //finally return
         }
         else {
-            // else this user doesn't exist, so we create them
+            // else this user doesn't exist, so we create them. usernames are the local part of
+            // the address, unless another domain's user already has it
+            let kerberos_username: &str = if self.username_taken(local_part).await? { email.as_str() } else { local_part };
+            if kerberos_username.is_empty() || kerberos_username.len() > 64 {
+                return Err(DatabaseError::ConstraintViolation(format!("Can't create a username for {}", email)));
+            }
 
             // first we create the user on stripe
             let customer_id = stripe_handler.create_new_stripe_customer(
                 user_info.name.as_str(),
-                user_info.email.as_str()
+                Some(user_info.email.as_str())
             ).await;
 
-            // generate a random authentication key using only alphabetical cased characters
-            let auth_key: String = self.generate_new_key();
-
             // insert user
             sqlx::query(r#"
                 INSERT INTO users
-                    (kerberos_username, stripe_id, given_name, family_name, profile_image_url,
-                    current_credits, authentication_key, registration_timestamp)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                    (kerberos_username, google_id, email, stripe_id, given_name, family_name, profile_image_url,
+                    current_credits, registration_timestamp)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#)
                 .bind(kerberos_username)
+                .bind(&user_info.id)
+                .bind(&email)
                 .bind(customer_id.as_str())
                 .bind(&user_info.given_name)
                 .bind(&user_info.family_name)
                 .bind(&user_info.picture)
-                .bind(1)
-                .bind(&auth_key)
+                .bind(0) // the demo comes from the trial, not from free credits
                 .bind(registration_timestamp)
-                .execute(&self.pool).await
-                .expect("Error executing the create_user query");
+                .execute(&self.pool).await?;
+
+            if let Some(referral_code) = referral_code {
+                self.record_referral(referral_code, kerberos_username).await?;
+            }
+
+            // every user starts out with one license key
+            let (_, auth_key) = self.create_license_key(kerberos_username, DEFAULT_LICENSE_KEY_LABEL, false).await?;
 
             // create default settings and insert settings
             let mut default_settings = UserApplicationSettings::default();
             default_settings.email = Some(user_info.email.clone());
-            self.create_or_update_user_application_settings(kerberos_username, &default_settings).await;
+            self.create_or_update_user_application_settings(kerberos_username, &default_settings).await?;
 
             // now create the user object
             let user = User {
@@ -416,27 +749,203 @@ impl DatabasePool {
                 stripe_id: customer_id,
                 given_name: user_info.given_name.clone(),
                 family_name: user_info.family_name.clone(),
-                authentication_key: auth_key,
+                license_key: Some(auth_key),
                 profile_image_url: user_info.picture.clone(),
                 current_credits: 0,
                 demo_expired_at: None,  // all new users get a demo,
+                trial_registrations_used: 0,
+                trial_allowance: None,
                 registration_timestamp: registration_timestamp
             };
 
-            return user
+            return Ok(user)
         }
 
     }
 
-    pub async fn reset_authentication_key(&self, kerberos_username: &String) -> String {
-        let auth_key: String = self.generate_new_key();
-        sqlx::query("UPDATE users SET authentication_key=? WHERE kerberos_username=?")
-            .bind(&auth_key)
+    /// Revokes every license key of the user and creates a single new one in their place,
+    /// returning the new key. This is the only time the key is available in plaintext.
+    pub async fn reset_authentication_key(&self, kerberos_username: &String) -> Result<String, DatabaseError> {
+        sqlx::query("UPDATE license_keys SET revoked_at=? WHERE kerberos_username=? AND revoked_at IS NULL")
+            .bind(chrono::Local::now().timestamp())
+            .bind(&kerberos_username)
+            .execute(&self.pool).await?;
+
+        let (_, auth_key) = self.create_license_key(kerberos_username, DEFAULT_LICENSE_KEY_LABEL, false).await?;
+        Ok(auth_key)
+    }
+
+    /// The current token generation of the user. Every token issued to the user carries the
+    /// generation it was issued in, bumping it invalidates all of them at once.
+    pub async fn get_token_generation(&self, kerberos_username: &str) -> Result<u32, DatabaseError> {
+        let result = sqlx::query("SELECT token_generation from users WHERE kerberos_username=?")
+            .bind(kerberos_username)
+            .fetch_optional(&self.pool).await?;
+
+        return match result {
+            Some(row) => Ok(row.get_unchecked::<u32, &str>("token_generation")),
+            None => Err(DatabaseError::NotFound(format!("User {} not found", kerberos_username)))
+        };
+    }
+
+    /// Checks whether the token was logged out or issued before the user last logged out everywhere
+    pub async fn is_token_revoked(&self, claims: &JwtClaims) -> Result<bool, DatabaseError> {
+        let result = sqlx::query(r#"
+            SELECT token_generation, EXISTS(SELECT 1 FROM revoked_tokens WHERE jti=?) AS revoked
+            FROM users WHERE kerberos_username=?
+        "#)
+            .bind(&claims.jti)
+            .bind(&claims.sub)
+            .fetch_optional(&self.pool).await?;
+
+        return match result {
+            Some(row) => {
+                let token_generation = row.get_unchecked::<u32, &str>("token_generation");
+                let revoked = row.get_unchecked::<bool, &str>("revoked");
+                Ok(revoked || token_generation != claims.gen)
+            },
+            // the user no longer exists
+            None => Ok(true)
+        };
+    }
+
+    pub async fn revoke_token(&self, claims: &JwtClaims) -> Result<(), DatabaseError> {
+        sqlx::query(r#"
+            INSERT IGNORE INTO revoked_tokens
+            (jti, kerberos_username, expires_at, revoked_at)
+            VALUES (?, ?, ?, ?)
+        "#)
+            .bind(&claims.jti)
+            .bind(&claims.sub)
+            .bind(&claims.exp)
+            .bind(&chrono::Local::now().timestamp())
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Invalidates every access and refresh token that has been issued to the user so far
+    pub async fn revoke_all_tokens(&self, kerberos_username: &str) -> Result<(), DatabaseError> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("UPDATE users SET token_generation=token_generation+1 WHERE kerberos_username=?")
+            .bind(kerberos_username)
+            .execute(&mut *tx).await?;
+
+        sqlx::query("UPDATE refresh_tokens SET revoked_at=? WHERE kerberos_username=? AND revoked_at IS NULL")
+            .bind(&chrono::Local::now().timestamp())
+            .bind(kerberos_username)
+            .execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Revoked tokens only need to be remembered until they would have expired anyway
+    pub async fn prune_revoked_tokens(&self) -> Result<(), DatabaseError> {
+        let now = chrono::Local::now().timestamp();
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < ?")
+            .bind(&now)
+            .execute(&self.pool).await?;
+        sqlx::query("DELETE FROM refresh_tokens WHERE expires_at < ?")
+            .bind(&now)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Starts a new refresh token family for the user, returning the refresh token. Only a hash
+    /// of the token is stored.
+    pub async fn create_refresh_token(&self, kerberos_username: &str, lifetime: i64) -> Result<String, DatabaseError> {
+        let family_id = generate_random_token(32);
+        let refresh_token = generate_random_token(64);
+        let now = chrono::Local::now().timestamp();
+
+        sqlx::query(r#"
+            INSERT INTO refresh_tokens
+            (token_hash, family_id, kerberos_username, issued_at, expires_at)
+            VALUES (?, ?, ?, ?, ?)
+        "#)
+            .bind(sha256_hex(&refresh_token))
+            .bind(&family_id)
+            .bind(kerberos_username)
+            .bind(&now)
+            .bind(&(now + lifetime))
+            .execute(&self.pool).await?;
+
+        Ok(refresh_token)
+    }
+
+    /// Exchanges a refresh token for a new one in the same family. Every refresh token can only
+    /// be used once, presenting one a second time revokes the whole family so that neither the
+    /// legitimate client nor whoever copied the token can keep using it.
+    pub async fn rotate_refresh_token(&self, refresh_token: &str, lifetime: i64) -> Result<RefreshTokenRotation, DatabaseError> {
+        let now = chrono::Local::now().timestamp();
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(r#"
+            SELECT family_id, kerberos_username, expires_at, used_at, revoked_at
+            FROM refresh_tokens WHERE token_hash=? FOR UPDATE
+        "#)
+            .bind(sha256_hex(refresh_token))
+            .fetch_optional(&mut *tx).await?;
+
+        let row = match result {
+            Some(row) => row,
+            None => return Ok(RefreshTokenRotation::Invalid)
+        };
+        let family_id = row.get_unchecked::<String, &str>("family_id");
+        let kerberos_username = row.get_unchecked::<String, &str>("kerberos_username");
+        let expires_at = row.get_unchecked::<i64, &str>("expires_at");
+        let used_at = row.get_unchecked::<Option<i64>, &str>("used_at");
+        let revoked_at = row.get_unchecked::<Option<i64>, &str>("revoked_at");
+
+        if revoked_at.is_some() || expires_at <= now {
+            return Ok(RefreshTokenRotation::Invalid);
+        }
+
+        if used_at.is_some() {
+            sqlx::query("UPDATE refresh_tokens SET revoked_at=? WHERE family_id=? AND revoked_at IS NULL")
+                .bind(&now)
+                .bind(&family_id)
+                .execute(&mut *tx).await?;
+            tx.commit().await?;
+            return Ok(RefreshTokenRotation::Reused { kerberos_username });
+        }
+
+        sqlx::query("UPDATE refresh_tokens SET used_at=? WHERE token_hash=?")
+            .bind(&now)
+            .bind(sha256_hex(refresh_token))
+            .execute(&mut *tx).await?;
+
+        let new_refresh_token = generate_random_token(64);
+        sqlx::query(r#"
+            INSERT INTO refresh_tokens
+            (token_hash, family_id, kerberos_username, issued_at, expires_at)
+            VALUES (?, ?, ?, ?, ?)
+        "#)
+            .bind(sha256_hex(&new_refresh_token))
+            .bind(&family_id)
             .bind(&kerberos_username)
-            .execute(&self.pool).await
-            .expect("Error executing the reset_authentication_key query");
+            .bind(&now)
+            .bind(&(now + lifetime))
+            .execute(&mut *tx).await?;
 
-        auth_key
+        tx.commit().await?;
+        Ok(RefreshTokenRotation::Rotated { kerberos_username, refresh_token: new_refresh_token })
+    }
+
+    /// Revokes the family the refresh token belongs to, i.e. the login it was issued for
+    pub async fn revoke_refresh_token_family(&self, refresh_token: &str) -> Result<(), DatabaseError> {
+        sqlx::query(r#"
+            UPDATE refresh_tokens tokens
+            JOIN (SELECT family_id FROM refresh_tokens WHERE token_hash=?) family
+                ON tokens.family_id = family.family_id
+            SET tokens.revoked_at=?
+            WHERE tokens.revoked_at IS NULL
+        "#)
+            .bind(sha256_hex(refresh_token))
+            .bind(&chrono::Local::now().timestamp())
+            .execute(&self.pool).await?;
+        Ok(())
     }
 
     fn generate_new_key(&self) -> String {
@@ -454,19 +963,19 @@ impl DatabasePool {
         return auth_key;
     }
 
-    pub async fn cleanup_dead_sessions(&self) {
+    pub async fn cleanup_dead_sessions(&self) -> Result<(), DatabaseError> {
 
         let to_update = sqlx::query("SELECT session_id FROM application_launch_session WHERE last_ping < ? AND is_active=1")
             .bind(chrono::Local::now().timestamp() - 45) // close all sessions where no ping was received for 45sec
-            .fetch_all(&self.pool).await
-            .expect("Error executing the selection cleanup_dead_sessions query");
+            .fetch_all(&self.pool).await?;
 
+        let mut pruned = 0;
         for row in &to_update {
             let session_id = row.get_unchecked::<i64, &str>("session_id");
 
-            // first, insert a session terminate entry
-            Self::end_session(&self, &ApplicationStopped {
-                license_key: String::new(), // this field isn't used soo I can just makeup data
+            // this marks the session inactive and inserts the terminate entry. One session failing
+            // shouldn't keep the rest alive, it's picked up again on the next run
+            let result = self.terminate_session(&ApplicationStopped {
                 session_id: session_id,
                 did_finish: false,
                 unknown_crash_occurred: Option::Some(true),
@@ -476,81 +985,84 @@ impl DatabasePool {
                 avg_sleep_time: None,
                 std_sleep_time: None,
                 timestamp: chrono::Local::now().timestamp()
-            }).await.expect("Error executing the cleanup_dead_sessions query");
+            }).await;
 
-            // now update the session to inactive
-            sqlx::query("UPDATE application_launch_session SET is_active=0 WHERE session_id=?")
-                .bind(&session_id)
-                .execute(&self.pool).await
-                .expect("Error executing the cleanup_dead_sessions query");
+            match result {
+                Ok(()) => pruned += 1,
+                Err(err) => eprintln!("Error pruning dead session {}: {}", session_id, err)
+            }
         }
 
-        if to_update.len() != 0 {
-            println!("Pruned {} dead sessions", to_update.len());
+        if pruned != 0 {
+            println!("Pruned {} dead sessions", pruned);
         }
 
+        Ok(())
     }
 
-    pub async fn is_session_alive(&self, session_id: i64) -> bool {
-        let result = sqlx::query("SELECT * from application_launch_session WHERE session_id=? AND is_active=1")
+    /// Fails with [DatabaseError::NotFound] if the session isn't alive and with
+    /// [DatabaseError::Forbidden] if it was started by a different user
+    pub async fn ensure_session_owner(&self, kerberos_username: &str, session_id: i64) -> Result<(), DatabaseError> {
+        let result = sqlx::query("SELECT kerberos_username from application_launch_session WHERE session_id=? AND is_active=1")
             .bind(&session_id)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the session_ping query");
+            .fetch_optional(&self.pool).await?;
+
+        return match result {
+            None => Err(DatabaseError::NotFound("Session not found or is no longer alive".to_string())),
+            Some(row) if row.get_unchecked::<String, &str>("kerberos_username") != kerberos_username => {
+                Err(DatabaseError::Forbidden(format!("Session {} does not belong to {}", session_id, kerberos_username)))
+            },
+            Some(_) => Ok(())
+        };
+    }
 
-        return if result.is_empty() {
-            false
-        } else {
-            true
-        }
+    /// The key requests for a live session are signed with, None if the session isn't alive or
+    /// was started before sessions had keys
+    pub async fn get_request_signing_key(&self, session_id: i64) -> Result<Option<String>, DatabaseError> {
+        let result = sqlx::query("SELECT request_signing_key from application_launch_session WHERE session_id=? AND is_active=1")
+            .bind(&session_id)
+            .fetch_optional(&self.pool).await?;
+
+        return Ok(result.and_then(|row| row.get_unchecked::<Option<String>, &str>("request_signing_key")));
     }
 
-    pub async fn has_active_session(&self, kerberos_username: &String) -> Option<DeviceMeta> {
+    pub async fn has_active_session(&self, kerberos_username: &String) -> Result<Option<DeviceMeta>, DatabaseError> {
         let result = sqlx::query("SELECT * from application_launch_session WHERE kerberos_username=? AND is_active=1")
             .bind(kerberos_username)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the session_ping query");
+            .fetch_all(&self.pool).await?;
 
         return if result.is_empty() {
-            None
+            Ok(None)
         } else {
             let row = result.get(0).unwrap();
-            Option::from(DeviceMeta {
+            Ok(Option::from(DeviceMeta {
                 ip: row.get_unchecked::<Option<String>, &str>("device_ip"),
                 os: row.get_unchecked::<String, &str>("device_os"),
                 name: row.get_unchecked::<Option<String>, &str>("device_name"),
                 system_arch: row.get_unchecked::<String, &str>("system_arch"),
                 core_count: row.get_unchecked::<i16, &str>("device_cores"),
                 cpu_speed: row.get_unchecked::<f32, &str>("device_clock_speed")
-            })
+            }))
         }
     }
 
-    pub async fn get_user_application_settings(&self, kerberos_username: &str) -> Option<UserApplicationSettings> {
+    pub async fn get_user_application_settings(&self, kerberos_username: &str) -> Result<UserApplicationSettings, DatabaseError> {
         let result = sqlx::query("SELECT * from user_application_settings WHERE kerberos_username=?")
             .bind(kerberos_username)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the get_user_application_settings query");
+            .fetch_all(&self.pool).await?;
         if result.is_empty() {
             let mut application_config = UserApplicationSettings::default();
-            application_config.target_courses = self.get_user_application_courses(kerberos_username).await;
-            return Some(application_config);
+            application_config.target_courses = self.get_user_application_courses(kerberos_username).await?;
+            return Ok(application_config);
         } else {
             let row = result.get(0).unwrap();
-            match UserApplicationSettings::decode(row) {
-                Ok(mut application_config) => {
-                    let courses = self.get_user_application_courses(kerberos_username).await;
-                    application_config.target_courses = courses;
-                    return Some(application_config);
-                },
-                Err(err) => {
-                    eprintln!("Error decoding application config: {}", err);
-                    return None;
-                }
-            }
+            let mut application_config = UserApplicationSettings::decode(row)?;
+            application_config.target_courses = self.get_user_application_courses(kerberos_username).await?;
+            return Ok(application_config);
         }
     }
 
-    pub async fn create_or_update_user_application_settings(&self, kerberos_username: &str, course_settings: &UserApplicationSettings) {
+    pub async fn create_or_update_user_application_settings(&self, kerberos_username: &str, course_settings: &UserApplicationSettings) -> Result<(), DatabaseError> {
         // create
         sqlx::query(r#"
                 INSERT INTO user_application_settings
@@ -594,23 +1106,23 @@ impl DatabasePool {
             .bind(&course_settings.custom_driver.enabled)
             .bind(&course_settings.custom_driver.driver_path)
             .bind(&course_settings.debug_mode)
-            .execute(&self.pool).await
-            .expect("Error executing the create_or_update_user_application_settings query");
+            .execute(&self.pool).await?;
+        Ok(())
     }
 
-    pub async fn add_custom_course_and_section(&self, semester: Semester, course_code: String, section: &str) -> BUCourseSection {
+    pub async fn add_custom_course_and_section(&self, semester: Semester, course_code: String, section: &str) -> Result<BUCourseSection, DatabaseError> {
         let course_section = CourseSection {
             section: section.to_string(),
             ..CourseSection::default()
         };
 
         let bu_course_section = self.add_course(semester, course_code, None,
-                                      None, false, vec![course_section]).await;
+                                      None, false, vec![course_section]).await?;
 
-        return bu_course_section[0].clone();
+        return Ok(bu_course_section[0].clone());
     }
 
-    pub async fn user_course_settings_add_course(&self, kerberos_username: &String, course_id: u32, course_section: &String) {
+    pub async fn user_course_settings_add_course(&self, kerberos_username: &String, course_id: u32, course_section: &String) -> Result<(), DatabaseError> {
         sqlx::query(r#"
                 INSERT IGNORE INTO user_application_course_settings
                 (kerberos_username, course_id, course_section)
@@ -619,11 +1131,11 @@ impl DatabasePool {
             .bind(&kerberos_username)
             .bind(course_id)
             .bind(course_section)
-            .execute(&self.pool).await
-            .expect("Error executing the user_course_settings_add_course query");
+            .execute(&self.pool).await?;
+        Ok(())
     }
 
-    pub async fn user_course_settings_delete_course(&self, kerberos_username: &String, course_id: u32, course_section: &str) {
+    pub async fn user_course_settings_delete_course(&self, kerberos_username: &String, course_id: u32, course_section: &str) -> Result<(), DatabaseError> {
         sqlx::query(r#"
                 DELETE FROM user_application_course_settings
                 WHERE kerberos_username=? AND course_id=? AND course_section=?;
@@ -631,11 +1143,11 @@ impl DatabasePool {
             .bind(&kerberos_username)
             .bind(course_id)
             .bind(course_section)
-            .execute(&self.pool).await
-            .expect("Error executing the user_course_settings_delete_course query");
+            .execute(&self.pool).await?;
+        Ok(())
     }
 
-    async fn get_user_application_courses(&self, kerberos_username: &str) -> Vec<BUCourseSection> {
+    async fn get_user_application_courses(&self, kerberos_username: &str) -> Result<Vec<BUCourseSection>, DatabaseError> {
         let result = sqlx::query(r#"
                     SELECT * from user_application_course_settings
                     INNER JOIN course_catalog cc on user_application_course_settings.course_id = cc.course_id
@@ -644,10 +1156,9 @@ impl DatabasePool {
                     WHERE kerberos_username=?
                 "#)
             .bind(kerberos_username)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the get_user_application_courses query");
+            .fetch_all(&self.pool).await?;
         if result.is_empty() {
-            return Vec::new();
+            return Ok(Vec::new());
         } else {
             let mut courses = Vec::new();
             for row in result {
@@ -660,7 +1171,7 @@ impl DatabasePool {
                     }
                 }
             }
-            return courses;
+            return Ok(courses);
         }
     }
 
@@ -668,12 +1179,11 @@ impl DatabasePool {
     /**
         Gets semesters based on courses in the db (which were scraped from bu)
     */
-    pub async fn get_semesters_in_db(&self) -> Vec<Semester> {
+    pub async fn get_semesters_in_db(&self) -> Result<Vec<Semester>, DatabaseError> {
         let result = sqlx::query("SELECT DISTINCT semester_season, semester_year from course_catalog")
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the get_active_semesters query");
+            .fetch_all(&self.pool).await?;
         if result.is_empty() {
-            return Vec::new();
+            return Ok(Vec::new());
         } else {
             let mut semesters = Vec::new();
             for row in result {
@@ -684,12 +1194,12 @@ impl DatabasePool {
                     semester_year: semester_year
                 });
             }
-            return semesters;
+            return Ok(semesters);
         }
     }
 
 
-    pub async fn get_courses(&self, semester: &Semester) -> Vec<BUCourseSection> {
+    pub async fn get_courses(&self, semester: &Semester) -> Result<Vec<BUCourseSection>, DatabaseError> {
         let mut output: Vec<BUCourseSection> = Vec::new();
 
         let results = sqlx::query(r#"
@@ -700,19 +1210,18 @@ impl DatabasePool {
                 "#)
             .bind(&semester.semester_season.to_string())
             .bind(&semester.semester_year)
-            .fetch_all(&self.pool).await
-            .expect("Error fetching rows for the search_course query");
+            .fetch_all(&self.pool).await?;
 
         for result in &results {
-            output.push(BUCourseSection::decode(result).unwrap());
+            output.push(BUCourseSection::decode(result)?);
         }
 
-        return output;
+        return Ok(output);
     }
 
     // course added by the scrapper are "confirmed to exist"
     // todo: make a global list of courses and use that to avoid database calls
-    pub async fn add_course(&self, semester: Semester, course_code: String, course_title: Option<String>, credits: Option<u8>, existence_confirmed: bool, sections: Vec<CourseSection>) -> Vec<BUCourseSection> {
+    pub async fn add_course(&self, semester: Semester, course_code: String, course_title: Option<String>, credits: Option<u8>, existence_confirmed: bool, sections: Vec<CourseSection>) -> Result<Vec<BUCourseSection>, DatabaseError> {
         let (college, department, code) = BUCourse::from_course_code_str(&course_code);
 
         let result = sqlx::query(r#"
@@ -730,8 +1239,7 @@ impl DatabasePool {
             .bind(&credits)
             .bind(&existence_confirmed)
             .bind(&chrono::Local::now().timestamp())
-            .execute(&self.pool).await
-            .expect("Error executing the add_course query");
+            .execute(&self.pool).await?;
 
         // get if any new keys were added
         let inserted = result.last_insert_id() != 0;
@@ -745,8 +1253,7 @@ impl DatabasePool {
             .bind(college)
             .bind(department)
             .bind(code)
-            .fetch_one(&self.pool).await
-            .expect("Error retrieving last insert id");
+            .fetch_one(&self.pool).await?;
 
         for section in &sections {
             let result2 = sqlx::query(r#"
@@ -767,8 +1274,7 @@ impl DatabasePool {
                 .bind(&section.notes)
                 .bind(&existence_confirmed)
                 .bind(&chrono::Local::now().timestamp())
-                .execute(&self.pool).await
-                .expect("Error executing the add_course query");
+                .execute(&self.pool).await?;
 
             // check if a new section was inserted
             let inserted2 = result2.last_insert_id() != 0;
@@ -800,213 +1306,98 @@ impl DatabasePool {
             bu_course_sections.push(bu_course_section);
         }
 
-        bu_course_sections
-    }
-
-    async fn create_tables(&self) {
-        Self::create_user_table(&self).await
-            .expect("An error occurred create the 'users' table");
-        Self::create_course_catalog_table(&self).await
-            .expect("An error occurred create the 'course_catalog' table");
-        Self::create_course_section_catalog_tables(&self).await
-            .expect("An error occurred create the 'course_sections_catalog' table");
-        Self::create_launch_tracker_table(&self).await
-            .expect("An error occurred create the 'application_launch_session' table");
-        Self::create_session_courses_table(&self).await
-            .expect("An error occurred create the 'app_session_courses' table");
-        Self::create_session_end_table(&self).await
-            .expect("An error occurred create the 'application_terminate_session' table");
-        Self::create_user_purchase_sessions_table(&self).await
-            .expect("An error occurred create the 'user_purchase_sessions' table");
-        Self::create_user_application_settings_table(&self).await
-            .expect("An error occurred create the 'user_application_settings' table");
-        Self::create_user_application_course_settings(&self).await
-            .expect("An error occurred create the 'user_application_course_settings' table");
-    }
-
-    async fn create_course_section_catalog_tables(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-            create table if not exists course_sections_catalog
-            (
-                course_id             int unsigned,
-                course_section        varchar(4)   not null,
-                open_seats            smallint     null,
-                instructor            varchar(64)  null,
-                section_type          varchar(6)   null,
-                location              varchar(64)  null,
-                schedule              varchar(64)  null,
-                dates                 varchar(64)  null,
-                notes                 varchar(256) null,
-                section_existence     tinyint(1)   not null,
-                added_timestamp       bigint       not null,
-                foreign key (course_id) references course_catalog (course_id),
-                primary key (course_id, course_section)
-            );
-        "#).await
-    }
-
-    async fn create_course_catalog_table(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-            create table if not exists course_catalog
-                (
-                    course_id             int unsigned auto_increment                    primary key,
-                    semester_season       enum ('Spring', 'Summer 1', 'Summer 2', 'Fall')  not null,
-                    semester_year         smallint unsigned                              not null,
-                    college               char(3)                                     not null,
-                    department            char(2)                                     not null,
-                    course_code           char(3)                                     not null,
-                    title                 varchar(256)                                   null,
-                    credits               tinyint unsigned                               null,
-                    course_existence      tinyint(1)                                     not null,
-                    added_timestamp       bigint                                         not null,
-                    unique key (semester_season, semester_year, college, department, course_code)
-                );
-        "#).await
-    }
-
-    async fn create_user_application_course_settings(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-            create table if not exists user_application_course_settings
-            (
-                kerberos_username varchar(64)                                   not null,
-                course_id         int unsigned                                  not null,
-                course_section    varchar(6)                                    not null,
-                foreign key (course_id, course_section)
-                    references course_sections_catalog (course_id, course_section),
-                foreign key (kerberos_username)
-                    references users (kerberos_username),
-                primary key  (kerberos_username, course_id, course_section)
-            );
-        "#).await
-    }
-
-    async fn create_user_application_settings_table(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-            create table if not exists user_application_settings
-            (
-                kerberos_username          varchar(64)  not null
-                    primary key
-                    references users (kerberos_username),
-                real_registrations         tinyint(1)   not null,
-                keep_trying                tinyint(1)   not null,
-                save_password              tinyint(1)   not null,
-                save_duo_cookies           tinyint(1)   not null,
-                registration_notifications tinyint(1)   not null,
-                register_email_alert       tinyint(1)   not null,
-                register_text_alert        tinyint(1)   not null,
-                register_phone_alert       tinyint(1)   not null,
-                watchdog_notifications     tinyint(1)   not null,
-                watchdog_email_alert       tinyint(1)   not null,
-                watchdog_text_alert        tinyint(1)   not null,
-                watchdog_phone_alert       tinyint(1)   not null,
-                allow_update_emails        tinyint(1)   not null,
-                allow_marketing_emails     tinyint(1)   not null,
-                alert_phone                varchar(16)  null,
-                alert_email                varchar(320) null,
-                console_colors             tinyint(1)   not null,
-                custom_chrome_driver       tinyint(1)   not null,
-                custom_chrome_driver_path  varchar(512) null,
-                debug_mode                 tinyint(1)   not null
-            );
-        "#).await
-    }
-
-    async fn create_user_purchase_sessions_table(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-            create table if not exists user_purchase_sessions
-            (
-                kerberos_username varchar(64)                                   not null
-                    references users (kerberos_username),
-                session_id        varchar(256)                                  null,
-                quantity          int                                           not null,
-                subtotal          float                                         null,
-                total             float                                         null,
-                coupon            varchar(32)                                   null,
-                succeeded         tinyint(1)                                    not null,
-                processed         tinyint(1)                                    not null,
-                begin_timestamp   bigint                                        not null,
-                finish_timestamp  bigint                                        null,
-                primary key (kerberos_username, begin_timestamp),
-                unique key (session_id)
-            );
-        "#).await
-    }
-
-    async fn create_user_table(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-        create table if not exists users (
-            kerberos_username      varchar(64)                                   not null,
-            stripe_id              varchar(32)                                   not null,
-            given_name             varchar(128)                                  not null,
-            family_name            varchar(128)                                  not null,
-            profile_image_url      varchar(256)                                  null,
-            authentication_key     varchar(64)                                   not null,
-            current_credits        int                                           not null,
-            demo_expired_at        bigint                                        null,
-            registration_timestamp bigint      default unix_timestamp()          not null,
-            PRIMARY KEY (kerberos_username),
-            UNIQUE KEY (authentication_key)
-        );
-        "#).await
-    }
-
-    async fn create_launch_tracker_table(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-        create table if not exists application_launch_session
-        (
-            session_id         int auto_increment,
-            kerberos_username  varchar(64)                               not null,
-            device_ip          varchar(16)                               null,
-            device_name        varchar(64)                               null,
-            device_os          varchar(32)                               null,
-            system_arch        varchar(32)                               null,
-            device_cores       smallint                                  null,
-            device_clock_speed float                                     null,
-            grant_type         enum('Full', 'Partial', 'Demo', 'Expired', 'Error')  not null,
-            planner_session    tinyint(1)                                not null,
-            launch_time        bigint                                    not null,
-            is_active          tinyint(1)                                default 1 not null,
-            last_ping          bigint default unix_timestamp()           not null,
-            primary key (session_id),
-            foreign key (kerberos_username) references users (kerberos_username)
-        );
-        "#).await
-    }
-
-    async fn create_session_courses_table(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-        create table if not exists app_session_courses
-        (
-            session_id          int                                           not null,
-            course_id           int unsigned                                  not null,
-            course_section      varchar(6)                                    not null,
-            register_timestamp  bigint                                        null,
-            primary key (session_id, course_id, course_section),
-            foreign key (session_id)
-                references application_launch_session        (session_id),
-            foreign key (course_id, course_section) 
-                references course_sections_catalog           (course_id, course_section)
-        );
-        "#).await
-    }
-
-    async fn create_session_end_table(&self) -> Result<MySqlQueryResult, Error> {
-        self.pool.execute(r#"
-        create table if not exists application_terminate_session
-        (
-            session_id           int auto_increment,
-            did_finish           tinyint(1)   not null,
-            unknown_crash        tinyint(1)   null,
-            reason               varchar(512) not null,
-            avg_cycle_time       float        null,
-            cycle_time_std       float        null,
-            avg_sleep_time       float        null,
-            sleep_time_std       float        null,
-            terminate_timestamp  bigint       not null,
-            primary key (session_id),
-            foreign key (session_id) references application_launch_session (session_id)
-        );
-        "#).await
-    }
-
-}
\ No newline at end of file
+        Ok(bu_course_sections)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_structs::requests::application_stopped::ApplicationStopped;
+    use crate::data_structs::requests::registration_notification::RegistrationNotification;
+    use crate::data_structs::requests::session_ping::SessionPing;
+    use crate::database::DatabaseError;
+    use crate::database::test_database::TestDatabase;
+
+    /// Two users, where the first has a session running the second must not be able to touch
+    async fn users_with_session() -> (TestDatabase, i64) {
+        let test_database = TestDatabase::create().await;
+        test_database.insert_user("alice", 5).await;
+        test_database.insert_user("bob", 5).await;
+        let session_id = test_database.insert_session("alice").await;
+        (test_database, session_id)
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn session_owner_is_checked() {
+        let (test_database, session_id) = users_with_session().await;
+        let database = &test_database.database;
+
+        assert!(database.ensure_session_owner("alice", session_id).await.is_ok());
+        let result = database.ensure_session_owner("bob", session_id).await;
+        assert!(matches!(result, Err(DatabaseError::Forbidden(_))), "{:?}", result);
+
+        test_database.drop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn other_users_cant_ping_a_session() {
+        let (test_database, session_id) = users_with_session().await;
+        let database = &test_database.database;
+        let ping = SessionPing { session_id, timestamp: chrono::Local::now().timestamp() };
+
+        let result = database.session_ping("bob", &ping).await;
+        assert!(matches!(result, Err(DatabaseError::Forbidden(_))), "{:?}", result);
+        assert!(database.session_ping("alice", &ping).await.is_ok());
+
+        test_database.drop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn other_users_cant_stop_a_session() {
+        let (test_database, session_id) = users_with_session().await;
+        let database = &test_database.database;
+        let stopped = ApplicationStopped {
+            session_id,
+            did_finish: true,
+            unknown_crash_occurred: None,
+            reason: "Done".to_string(),
+            avg_cycle_time: None,
+            std_cycle_time: None,
+            avg_sleep_time: None,
+            std_sleep_time: None,
+            timestamp: chrono::Local::now().timestamp(),
+        };
+
+        let result = database.end_session("bob", &stopped).await;
+        assert!(matches!(result, Err(DatabaseError::Forbidden(_))), "{:?}", result);
+        // the session is still running for its owner
+        assert!(database.ensure_session_owner("alice", session_id).await.is_ok());
+
+        test_database.drop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn other_users_cant_register_courses_on_a_session() {
+        let (test_database, session_id) = users_with_session().await;
+        let database = &test_database.database;
+        let notification = RegistrationNotification {
+            session_id,
+            course_id: 1,
+            course_section: "A1".to_string(),
+            timestamp: chrono::Local::now().timestamp(),
+            idempotency_key: None,
+        };
+
+        let result = database.mark_course_registered("bob", &notification, chrono::Local::now().timestamp()).await;
+        assert!(matches!(result, Err(DatabaseError::Forbidden(_))), "{:?}", result);
+        assert_eq!(database.get_user(&"alice".to_string()).await.unwrap().current_credits, 5);
+        assert_eq!(database.get_user(&"bob".to_string()).await.unwrap().current_credits, 5);
+
+        test_database.drop().await;
+    }
+}
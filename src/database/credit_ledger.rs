@@ -0,0 +1,164 @@
+use std::str::FromStr;
+
+use sqlx::mysql::MySqlRow;
+use sqlx::{MySqlConnection, Row};
+
+use crate::data_structs::credit_ledger::{CreditEntryType, CreditLedgerEntry, NewCreditEntry};
+use crate::database::{DatabaseError, DatabasePool};
+
+impl DatabasePool {
+
+    /// Appends an entry to the credit ledger and updates the user's cached balance to match.
+    /// Must be run inside a transaction so the ledger and balance can't drift apart; the user's
+    /// row is locked until the transaction ends. Spending more credits than the user has only
+    /// takes away what's left, the recorded amount is what was actually taken. Returns the
+    /// recorded amount.
+    pub(super) async fn record_credit_entry(conn: &mut MySqlConnection, entry: NewCreditEntry<'_>) -> Result<i64, DatabaseError> {
+        let result = sqlx::query("SELECT current_credits FROM users WHERE kerberos_username=? FOR UPDATE")
+            .bind(entry.kerberos_username)
+            .fetch_optional(&mut *conn).await?;
+
+        let balance = match result {
+            Some(row) => row.get_unchecked::<i64, &str>("current_credits"),
+            None => return Err(DatabaseError::NotFound(format!("User {} not found", entry.kerberos_username)))
+        };
+        let amount = entry.amount.max(-balance);
+        let balance_after = balance + amount;
+
+        sqlx::query("UPDATE users SET current_credits=? WHERE kerberos_username=?")
+            .bind(&balance_after)
+            .bind(entry.kerberos_username)
+            .execute(&mut *conn).await?;
+
+        sqlx::query(r#"
+            INSERT INTO credit_ledger
+            (kerberos_username, entry_type, amount, balance_after, purchase_session_id,
+            launch_session_id, note, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#)
+            .bind(entry.kerberos_username)
+            .bind(entry.entry_type.as_str())
+            .bind(&amount)
+            .bind(&balance_after)
+            .bind(entry.purchase_session_id)
+            .bind(entry.launch_session_id)
+            .bind(entry.note)
+            .bind(chrono::Local::now().timestamp())
+            .execute(&mut *conn).await?;
+
+        Ok(amount)
+    }
+
+    /// The user's credits that aren't held by one of their live sessions. Locks the user's row
+    /// until the transaction ends, the same as [DatabasePool::record_credit_entry].
+    pub(super) async fn available_credits(conn: &mut MySqlConnection, kerberos_username: &str) -> Result<i64, DatabaseError> {
+        let result = sqlx::query(r#"
+            SELECT current_credits, CAST((
+                SELECT COALESCE(SUM(reserved_credits), 0) FROM application_launch_session
+                WHERE kerberos_username=? AND is_active=1
+            ) AS SIGNED) AS reserved_credits
+            FROM users WHERE kerberos_username=? FOR UPDATE
+        "#)
+            .bind(kerberos_username)
+            .bind(kerberos_username)
+            .fetch_optional(&mut *conn).await?;
+
+        return match result {
+            Some(row) => Ok(row.get_unchecked::<i64, &str>("current_credits") - row.get_unchecked::<i64, &str>("reserved_credits")),
+            None => Err(DatabaseError::NotFound(format!("User {} not found", kerberos_username)))
+        };
+    }
+
+    /// Takes credits that were taken back from the user out of what their live sessions hold,
+    /// newest session first, so no session is left with reserved credits that aren't there
+    /// anymore. A session loses one allowed registration for every credit it gives up. Must be
+    /// run in the transaction that took the credits. Returns how many credits were released.
+    pub(super) async fn release_unbacked_reservations(conn: &mut MySqlConnection, kerberos_username: &str) -> Result<i64, DatabaseError> {
+        let mut unbacked = -Self::available_credits(conn, kerberos_username).await?;
+        if unbacked <= 0 {
+            return Ok(0);
+        }
+
+        let sessions: Vec<MySqlRow> = sqlx::query(r#"
+            SELECT session_id, reserved_credits FROM application_launch_session
+            WHERE kerberos_username=? AND is_active=1 AND reserved_credits > 0
+            ORDER BY launch_time DESC
+            FOR UPDATE
+        "#)
+            .bind(kerberos_username)
+            .fetch_all(&mut *conn).await?;
+
+        let mut released = 0;
+        for row in &sessions {
+            if unbacked <= 0 {
+                break;
+            }
+            let session_id = row.get_unchecked::<i64, &str>("session_id");
+            let to_release = unbacked.min(row.get_unchecked::<i64, &str>("reserved_credits"));
+
+            sqlx::query(r#"
+                UPDATE application_launch_session
+                SET reserved_credits=reserved_credits-?, allowed_registrations=allowed_registrations-?
+                WHERE session_id=?
+            "#)
+                .bind(&to_release)
+                .bind(&to_release)
+                .bind(&session_id)
+                .execute(&mut *conn).await?;
+
+            println!("Session {} of {} gave up {} reserved credits that were taken back", session_id, kerberos_username, to_release);
+            unbacked -= to_release;
+            released += to_release;
+        }
+        Ok(released)
+    }
+
+    /// The user's credit history, newest first. `before` pages through older entries by
+    /// only returning entries with a smaller id.
+    pub async fn get_credit_history(&self, kerberos_username: &str, before: Option<u64>, limit: u32) -> Result<Vec<CreditLedgerEntry>, DatabaseError> {
+        let result: Vec<MySqlRow> = sqlx::query(r#"
+            SELECT * FROM credit_ledger
+            WHERE kerberos_username=? AND entry_id < ?
+            ORDER BY entry_id DESC
+            LIMIT ?
+        "#)
+            .bind(kerberos_username)
+            .bind(before.unwrap_or(u64::MAX))
+            .bind(limit)
+            .fetch_all(&self.pool).await?;
+
+        let mut entries = Vec::new();
+        for row in &result {
+            let entry_type = row.get_unchecked::<String, &str>("entry_type");
+            entries.push(CreditLedgerEntry {
+                entry_id: row.get_unchecked::<u64, &str>("entry_id"),
+                entry_type: CreditEntryType::from_str(&entry_type)
+                    .map_err(|err| DatabaseError::Query(sqlx::Error::Decode(err.into())))?,
+                amount: row.get_unchecked::<i64, &str>("amount"),
+                balance_after: row.get_unchecked::<i64, &str>("balance_after"),
+                purchase_session_id: row.get_unchecked::<Option<String>, &str>("purchase_session_id"),
+                launch_session_id: row.get_unchecked::<Option<i64>, &str>("launch_session_id"),
+                note: row.get_unchecked::<Option<String>, &str>("note"),
+                created_at: row.get_unchecked::<i64, &str>("created_at"),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Compares every user's cached balance against the sum of their ledger entries and returns
+    /// the usernames of the users where they disagree. The ledger is the source of truth.
+    pub async fn reconcile_credit_balances(&self) -> Result<Vec<String>, DatabaseError> {
+        let result: Vec<MySqlRow> = sqlx::query(r#"
+            SELECT users.kerberos_username, users.current_credits, COALESCE(SUM(credit_ledger.amount), 0) AS ledger_balance
+            FROM users
+            LEFT JOIN credit_ledger ON credit_ledger.kerberos_username = users.kerberos_username
+            GROUP BY users.kerberos_username, users.current_credits
+            HAVING users.current_credits != ledger_balance
+        "#)
+            .fetch_all(&self.pool).await?;
+
+        Ok(result.iter()
+            .map(|row| row.get_unchecked::<String, &str>("kerberos_username"))
+            .collect())
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt::{Display, Formatter};
+
+use sqlx::error::ErrorKind;
+
+/// Everything that can go wrong while talking to the database. Driver errors are sorted into
+/// the variant that best describes them so that callers (and in turn the api handlers) can
+/// react to a missing row or a duplicate key differently than to the database being down.
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// The requested row does not exist (or is no longer in a usable state)
+    NotFound(String),
+    /// The row exists but belongs to someone other than the caller
+    Forbidden(String),
+    /// The user doesn't have the credits the action needs
+    InsufficientCredits(String),
+    /// The write clashes with a row that already exists (duplicate unique/primary key)
+    Conflict(String),
+    /// A foreign key, not null or check constraint rejected the write
+    ConstraintViolation(String),
+    /// The connection to the database was lost or no connection could be acquired
+    ConnectionLost(sqlx::Error),
+    /// A service the operation depends on besides the database, such as stripe, couldn't be
+    /// reached. Nothing was written, so the operation can be retried
+    Unavailable(String),
+    /// The schema is in a state that doesn't match the migrations compiled into this server
+    Migration(String),
+    /// Any other driver or decoding error
+    Query(sqlx::Error),
+}
+
+impl Display for DatabaseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatabaseError::NotFound(message) => write!(f, "Not found: {}", message),
+            DatabaseError::Forbidden(message) => write!(f, "Forbidden: {}", message),
+            DatabaseError::InsufficientCredits(message) => write!(f, "Insufficient credits: {}", message),
+            DatabaseError::Conflict(message) => write!(f, "Conflict: {}", message),
+            DatabaseError::ConstraintViolation(message) => write!(f, "Constraint violation: {}", message),
+            DatabaseError::ConnectionLost(err) => write!(f, "Database connection lost: {}", err),
+            DatabaseError::Unavailable(message) => write!(f, "Unavailable: {}", message),
+            DatabaseError::Migration(message) => write!(f, "Migration error: {}", message),
+            DatabaseError::Query(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl From<sqlx::Error> for DatabaseError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => DatabaseError::NotFound("No matching row found".to_string()),
+            sqlx::Error::Database(db_err) => match db_err.kind() {
+                ErrorKind::UniqueViolation => DatabaseError::Conflict(db_err.message().to_string()),
+                ErrorKind::ForeignKeyViolation
+                | ErrorKind::NotNullViolation
+                | ErrorKind::CheckViolation => DatabaseError::ConstraintViolation(db_err.message().to_string()),
+                _ => DatabaseError::Query(sqlx::Error::Database(db_err)),
+            },
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => DatabaseError::ConnectionLost(err),
+            _ => DatabaseError::Query(err),
+        }
+    }
+}
@@ -0,0 +1,728 @@
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, MySqlConnection, Row};
+use sqlx::mysql::MySqlRow;
+
+use crate::database::{DatabaseError, DatabasePool};
+
+/// A single, numbered schema change. Migrations are applied in ascending `version` order and
+/// recorded in the `schema_migrations` table along with a checksum of their statements.
+///
+/// Every statement is a single DDL or data statement. MySQL commits each DDL statement on its
+/// own, so a migration can't be applied atomically. Instead every statement is recorded in
+/// `schema_migration_steps` as it's applied, and a migration that failed part way resumes
+/// with the first statement that didn't get through.
+///
+/// Once a migration has been released it must never be edited: the checksum of every applied
+/// migration is verified at startup. Schema changes go into a new migration at the end of the list.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: u32,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: Option<i64>,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        let mut hasher = Sha256::new();
+        for statement in self.statements {
+            hasher.update(statement.as_bytes());
+            hasher.update(b"\n");
+        }
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+// The first nine migrations describe the schema as it was created by the old `create_tables`
+// method, so they use `if not exists` and are safe to apply against an existing database.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_users",
+        statements: &[r#"
+            create table if not exists users (
+                kerberos_username      varchar(64)                                   not null,
+                stripe_id              varchar(32)                                   not null,
+                given_name             varchar(128)                                  not null,
+                family_name            varchar(128)                                  not null,
+                profile_image_url      varchar(256)                                  null,
+                authentication_key     varchar(64)                                   not null,
+                current_credits        int                                           not null,
+                demo_expired_at        bigint                                        null,
+                registration_timestamp bigint      default unix_timestamp()          not null,
+                PRIMARY KEY (kerberos_username),
+                UNIQUE KEY (authentication_key)
+            );
+        "#],
+    },
+    Migration {
+        version: 2,
+        name: "create_course_catalog",
+        statements: &[r#"
+            create table if not exists course_catalog
+            (
+                course_id             int unsigned auto_increment                    primary key,
+                semester_season       enum ('Spring', 'Summer 1', 'Summer 2', 'Fall')  not null,
+                semester_year         smallint unsigned                              not null,
+                college               char(3)                                        not null,
+                department            char(2)                                        not null,
+                course_code           char(3)                                        not null,
+                title                 varchar(256)                                   null,
+                credits               tinyint unsigned                               null,
+                course_existence      tinyint(1)                                     not null,
+                added_timestamp       bigint                                         not null,
+                unique key (semester_season, semester_year, college, department, course_code)
+            );
+        "#],
+    },
+    Migration {
+        version: 3,
+        name: "create_course_sections_catalog",
+        statements: &[r#"
+            create table if not exists course_sections_catalog
+            (
+                course_id             int unsigned,
+                course_section        varchar(4)   not null,
+                open_seats            smallint     null,
+                instructor            varchar(64)  null,
+                section_type          varchar(6)   null,
+                location              varchar(64)  null,
+                schedule              varchar(64)  null,
+                dates                 varchar(64)  null,
+                notes                 varchar(256) null,
+                section_existence     tinyint(1)   not null,
+                added_timestamp       bigint       not null,
+                foreign key (course_id) references course_catalog (course_id),
+                primary key (course_id, course_section)
+            );
+        "#],
+    },
+    Migration {
+        version: 4,
+        name: "create_application_launch_session",
+        statements: &[r#"
+            create table if not exists application_launch_session
+            (
+                session_id         int auto_increment,
+                kerberos_username  varchar(64)                               not null,
+                device_ip          varchar(16)                               null,
+                device_name        varchar(64)                               null,
+                device_os          varchar(32)                               null,
+                system_arch        varchar(32)                               null,
+                device_cores       smallint                                  null,
+                device_clock_speed float                                     null,
+                grant_type         enum('Full', 'Partial', 'Demo', 'Expired', 'Error')  not null,
+                planner_session    tinyint(1)                                not null,
+                launch_time        bigint                                    not null,
+                is_active          tinyint(1)                                default 1 not null,
+                last_ping          bigint default unix_timestamp()           not null,
+                primary key (session_id),
+                foreign key (kerberos_username) references users (kerberos_username)
+            );
+        "#],
+    },
+    Migration {
+        version: 5,
+        name: "create_app_session_courses",
+        statements: &[r#"
+            create table if not exists app_session_courses
+            (
+                session_id          int                                           not null,
+                course_id           int unsigned                                  not null,
+                course_section      varchar(6)                                    not null,
+                register_timestamp  bigint                                        null,
+                primary key (session_id, course_id, course_section),
+                foreign key (session_id)
+                    references application_launch_session        (session_id),
+                foreign key (course_id, course_section)
+                    references course_sections_catalog           (course_id, course_section)
+            );
+        "#],
+    },
+    Migration {
+        version: 6,
+        name: "create_application_terminate_session",
+        statements: &[r#"
+            create table if not exists application_terminate_session
+            (
+                session_id           int auto_increment,
+                did_finish           tinyint(1)   not null,
+                unknown_crash        tinyint(1)   null,
+                reason               varchar(512) not null,
+                avg_cycle_time       float        null,
+                cycle_time_std       float        null,
+                avg_sleep_time       float        null,
+                sleep_time_std       float        null,
+                terminate_timestamp  bigint       not null,
+                primary key (session_id),
+                foreign key (session_id) references application_launch_session (session_id)
+            );
+        "#],
+    },
+    Migration {
+        version: 7,
+        name: "create_user_purchase_sessions",
+        statements: &[r#"
+            create table if not exists user_purchase_sessions
+            (
+                kerberos_username varchar(64)                                   not null
+                    references users (kerberos_username),
+                session_id        varchar(256)                                  null,
+                quantity          int                                           not null,
+                subtotal          float                                         null,
+                total             float                                         null,
+                coupon            varchar(32)                                   null,
+                succeeded         tinyint(1)                                    not null,
+                processed         tinyint(1)                                    not null,
+                begin_timestamp   bigint                                        not null,
+                finish_timestamp  bigint                                        null,
+                primary key (kerberos_username, begin_timestamp),
+                unique key (session_id)
+            );
+        "#],
+    },
+    Migration {
+        version: 8,
+        name: "create_user_application_settings",
+        statements: &[r#"
+            create table if not exists user_application_settings
+            (
+                kerberos_username          varchar(64)  not null
+                    primary key
+                    references users (kerberos_username),
+                real_registrations         tinyint(1)   not null,
+                keep_trying                tinyint(1)   not null,
+                save_password              tinyint(1)   not null,
+                save_duo_cookies           tinyint(1)   not null,
+                registration_notifications tinyint(1)   not null,
+                register_email_alert       tinyint(1)   not null,
+                register_text_alert        tinyint(1)   not null,
+                register_phone_alert       tinyint(1)   not null,
+                watchdog_notifications     tinyint(1)   not null,
+                watchdog_email_alert       tinyint(1)   not null,
+                watchdog_text_alert        tinyint(1)   not null,
+                watchdog_phone_alert       tinyint(1)   not null,
+                allow_update_emails        tinyint(1)   not null,
+                allow_marketing_emails     tinyint(1)   not null,
+                alert_phone                varchar(16)  null,
+                alert_email                varchar(320) null,
+                console_colors             tinyint(1)   not null,
+                custom_chrome_driver       tinyint(1)   not null,
+                custom_chrome_driver_path  varchar(512) null,
+                debug_mode                 tinyint(1)   not null
+            );
+        "#],
+    },
+    Migration {
+        version: 9,
+        name: "create_user_application_course_settings",
+        statements: &[r#"
+            create table if not exists user_application_course_settings
+            (
+                kerberos_username varchar(64)                                   not null,
+                course_id         int unsigned                                  not null,
+                course_section    varchar(6)                                    not null,
+                foreign key (course_id, course_section)
+                    references course_sections_catalog (course_id, course_section),
+                foreign key (kerberos_username)
+                    references users (kerberos_username),
+                primary key  (kerberos_username, course_id, course_section)
+            );
+        "#],
+    },
+    Migration {
+        version: 10,
+        name: "add_jwt_revocation",
+        statements: &[
+            r#"
+            alter table users
+                add column token_generation int unsigned default 0 not null;
+            "#,
+            r#"
+            create table if not exists revoked_tokens
+            (
+                jti               char(32)     not null,
+                kerberos_username varchar(64)  not null,
+                expires_at        bigint       not null,
+                revoked_at        bigint       not null,
+                primary key (jti),
+                foreign key (kerberos_username) references users (kerberos_username)
+            );
+            "#,
+        ],
+    },
+    Migration {
+        version: 11,
+        name: "create_refresh_tokens",
+        statements: &[
+            r#"
+            create table if not exists refresh_tokens
+            (
+                token_hash        char(64)     not null,
+                family_id         char(32)     not null,
+                kerberos_username varchar(64)  not null,
+                issued_at         bigint       not null,
+                expires_at        bigint       not null,
+                used_at           bigint       null,
+                revoked_at        bigint       null,
+                primary key (token_hash),
+                index (family_id),
+                foreign key (kerberos_username) references users (kerberos_username)
+            );
+            "#,
+        ],
+    },
+    Migration {
+        version: 12,
+        name: "add_users_google_id",
+        statements: &[
+            r#"
+            alter table users
+                add column google_id varchar(64) null unique after kerberos_username;
+            "#,
+        ],
+    },
+    Migration {
+        version: 13,
+        name: "hash_license_keys",
+        statements: &[
+            r#"
+            alter table users
+                add column key_prefix char(8)  null after profile_image_url,
+                add column key_hash   char(64) null after key_prefix;
+            "#,
+            r#"
+            update users
+            set key_prefix = left(authentication_key, 8),
+                key_hash   = sha2(authentication_key, 256);
+            "#,
+            r#"
+            alter table users
+                modify column key_prefix char(8)  not null,
+                modify column key_hash   char(64) not null,
+                add index (key_prefix),
+                add unique key (key_hash),
+                drop column authentication_key;
+            "#,
+        ],
+    },
+    Migration {
+        version: 14,
+        name: "create_license_keys",
+        statements: &[
+            r#"
+            create table if not exists license_keys
+            (
+                license_key_id     int unsigned auto_increment,
+                kerberos_username  varchar(64)  not null,
+                label              varchar(64)  not null,
+                key_prefix         char(8)      not null,
+                key_hash           char(64)     not null,
+                device_bound       tinyint(1)   default 0 not null,
+                device_fingerprint char(64)     null,
+                created_at         bigint       not null,
+                last_used_at       bigint       null,
+                revoked_at         bigint       null,
+                primary key (license_key_id),
+                unique key (key_hash),
+                index (key_prefix),
+                foreign key (kerberos_username) references users (kerberos_username)
+            );
+            "#,
+            r#"
+            insert into license_keys (kerberos_username, label, key_prefix, key_hash, created_at)
+            select kerberos_username, 'Default', key_prefix, key_hash, unix_timestamp()
+            from users;
+            "#,
+            r#"
+            alter table users
+                drop column key_prefix,
+                drop column key_hash;
+            "#,
+            r#"
+            alter table application_launch_session
+                add column license_key_id int unsigned null after kerberos_username,
+                add foreign key (license_key_id) references license_keys (license_key_id);
+            "#,
+        ],
+    },
+    Migration {
+        version: 15,
+        name: "add_registration_idempotency",
+        statements: &[
+            r#"
+            alter table app_session_courses
+                add column response_timestamp bigint      null after register_timestamp,
+                add column idempotency_key    varchar(64) null after response_timestamp,
+                add unique key (session_id, idempotency_key);
+            "#,
+        ],
+    },
+    Migration {
+        version: 16,
+        name: "create_credit_ledger",
+        statements: &[
+            r#"
+            create table if not exists credit_ledger
+            (
+                entry_id            bigint unsigned auto_increment,
+                kerberos_username   varchar(64)   not null,
+                entry_type          enum('Purchase', 'RegistrationSpend', 'Refund', 'AdminGrant',
+                                         'ReferralBonus', 'Demo', 'OpeningBalance') not null,
+                amount              int           not null,
+                balance_after       int           not null,
+                purchase_session_id varchar(256)  null,
+                launch_session_id   int           null,
+                note                varchar(256)  null,
+                created_at          bigint        not null,
+                primary key (entry_id),
+                index (kerberos_username, entry_id),
+                foreign key (kerberos_username) references users (kerberos_username),
+                foreign key (purchase_session_id) references user_purchase_sessions (session_id),
+                foreign key (launch_session_id) references application_launch_session (session_id)
+            );
+            "#,
+            r#"
+            insert into credit_ledger (kerberos_username, entry_type, amount, balance_after, note, created_at)
+            select kerberos_username, 'OpeningBalance', current_credits, current_credits,
+                   'Balance from before the credit ledger', unix_timestamp()
+            from users
+            where current_credits != 0;
+            "#,
+        ],
+    },
+    Migration {
+        version: 17,
+        name: "add_session_credit_reservations",
+        statements: &[r#"
+            alter table application_launch_session
+                add column allowed_registrations int not null default 0,
+                add column reserved_credits      int not null default 0;
+        "#],
+    },
+    Migration {
+        version: 18,
+        name: "add_trial_registrations_used",
+        statements: &[r#"
+            alter table users
+                add column trial_registrations_used int not null default 0;
+        "#],
+    },
+    Migration {
+        version: 19,
+        name: "create_referrals",
+        statements: &[
+            r#"
+            alter table users
+                add column referral_code varchar(16) null,
+                add unique key (referral_code);
+            "#,
+            r#"
+            alter table application_launch_session
+                add column device_fingerprint char(64) null,
+                add index (device_fingerprint);
+            "#,
+            r#"
+            create table if not exists referrals
+            (
+                referred_username   varchar(64)   not null,
+                referrer_username   varchar(64)   not null,
+                referral_code       varchar(16)   not null,
+                status              enum('Pending', 'Rewarded', 'Rejected') default 'Pending' not null,
+                rejected_reason     varchar(256)  null,
+                bonus_credits       int           default 0 not null,
+                purchase_session_id varchar(256)  null,
+                created_at          bigint        not null,
+                resolved_at         bigint        null,
+                primary key (referred_username),
+                index (referrer_username),
+                foreign key (referred_username) references users (kerberos_username),
+                foreign key (referrer_username) references users (kerberos_username),
+                foreign key (purchase_session_id) references user_purchase_sessions (session_id)
+            );
+            "#,
+        ],
+    },
+    Migration {
+        version: 20,
+        name: "create_promotion_codes",
+        statements: &[
+            r#"
+            create table if not exists promotion_codes
+            (
+                promotion_code_id varchar(64)  not null,
+                code              varchar(64)  not null,
+                coupon_id         varchar(64)  not null,
+                campaign          varchar(64)  null,
+                active            tinyint(1)   not null,
+                created_by        varchar(64)  null,
+                created_at        bigint       not null,
+                primary key (promotion_code_id),
+                index (campaign)
+            );
+            "#,
+            r#"
+            alter table user_purchase_sessions
+                modify column coupon varchar(64) null,
+                add column promotion_code_id varchar(64) null,
+                add index (promotion_code_id);
+            "#,
+        ],
+    },
+    Migration {
+        version: 21,
+        name: "add_purchase_refunds_and_disputes",
+        statements: &[
+            r#"
+            alter table user_purchase_sessions
+                add column payment_intent_id varchar(64) null,
+                add column credits_refunded  int         default 0 not null,
+                add column credits_disputed  int         default 0 not null,
+                add column dispute_id        varchar(64) null,
+                add column dispute_closed_at bigint      null,
+                add column dispute_won       tinyint(1)  null,
+                add unique key (payment_intent_id);
+            "#,
+            r#"
+            alter table credit_ledger
+                modify column entry_type enum('Purchase', 'RegistrationSpend', 'Refund', 'AdminGrant',
+                                              'ReferralBonus', 'Dispute', 'Demo', 'OpeningBalance') not null;
+            "#,
+        ],
+    },
+    Migration {
+        version: 22,
+        name: "create_stripe_webhook_events",
+        statements: &[
+            r#"
+            create table if not exists stripe_webhook_events
+            (
+                event_id        varchar(64)   not null,
+                event_type      varchar(64)   not null,
+                payload         mediumtext    not null,
+                received_at     bigint        not null,
+                attempts        int           default 0 not null,
+                last_error      varchar(1024) null,
+                next_attempt_at bigint        null,
+                locked_until    bigint        null,
+                processed_at    bigint        null,
+                primary key (event_id),
+                index (processed_at, next_attempt_at)
+            );
+            "#,
+        ],
+    },
+    Migration {
+        version: 23,
+        name: "add_purchase_reconciliation",
+        statements: &[
+            r#"
+            alter table user_purchase_sessions
+                add column reconciled_at        bigint       null,
+                add column reconciliation_issue varchar(512) null,
+                add index (reconciled_at, processed);
+            "#,
+        ],
+    },
+    Migration {
+        version: 24,
+        name: "add_users_email",
+        statements: &[
+            r#"
+            alter table users
+                add column email varchar(320) null after google_id,
+                add unique key (email);
+            "#,
+        ],
+    },
+    Migration {
+        version: 25,
+        name: "add_session_request_signing_key",
+        statements: &[
+            r#"
+            alter table application_launch_session
+                add column request_signing_key char(64) null;
+            "#,
+        ],
+    },
+    Migration {
+        version: 26,
+        name: "add_purchase_payment_fingerprint",
+        statements: &[
+            r#"
+            alter table user_purchase_sessions
+                add column payment_fingerprint varchar(64) null,
+                add index (payment_fingerprint);
+            "#,
+        ],
+    },
+    Migration {
+        version: 27,
+        name: "add_purchase_last_reconciled_at",
+        statements: &[
+            r#"
+            alter table user_purchase_sessions
+                add column last_reconciled_at bigint null,
+                add index (reconciled_at, last_reconciled_at);
+            "#,
+        ],
+    },
+    Migration {
+        version: 28,
+        name: "add_purchase_credits_clawed_back",
+        statements: &[
+            r#"
+            alter table user_purchase_sessions
+                add column credits_clawed_back int default 0 not null;
+            "#,
+        ],
+    },
+];
+
+// name of the mysql advisory lock held while migrating, so that two server instances
+// starting at the same time don't both try to apply the same migration
+const MIGRATION_LOCK: &str = "schema_migrations_lock";
+
+impl DatabasePool {
+
+    /// Applies every migration that hasn't been applied yet, in order. Fails if a previously
+    /// applied migration no longer matches its recorded checksum, since the server can't safely
+    /// run against a schema it doesn't understand.
+    pub(super) async fn run_migrations(&self) -> Result<(), DatabaseError> {
+        let mut conn = self.pool.acquire().await?;
+
+        // 1 if the lock was acquired, 0 if it timed out and NULL if something went wrong
+        let row: MySqlRow = sqlx::query("SELECT GET_LOCK(?, 60) AS acquired")
+            .bind(MIGRATION_LOCK)
+            .fetch_one(&mut *conn).await?;
+        if row.get_unchecked::<Option<i64>, &str>("acquired") != Some(1) {
+            return Err(DatabaseError::Migration("Couldn't acquire the migration lock, another instance may still be migrating".to_string()));
+        }
+
+        let result = Self::apply_pending_migrations(&mut conn).await;
+
+        sqlx::query("SELECT RELEASE_LOCK(?)")
+            .bind(MIGRATION_LOCK)
+            .execute(&mut *conn).await?;
+
+        result
+    }
+
+    /// Lists every known migration along with when it was applied, or `None` if it is still pending.
+    pub async fn list_migrations(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+        let mut conn = self.pool.acquire().await?;
+
+        Self::create_schema_migrations_table(&mut conn).await?;
+        let applied = Self::fetch_applied_migrations(&mut conn).await?;
+
+        Ok(MIGRATIONS.iter().map(|migration| {
+            let applied_at = applied.iter()
+                .find(|status| status.version == migration.version)
+                .and_then(|status| status.applied_at);
+            MigrationStatus {
+                version: migration.version,
+                name: migration.name.to_string(),
+                checksum: migration.checksum(),
+                applied_at,
+            }
+        }).collect())
+    }
+
+    async fn apply_pending_migrations(conn: &mut MySqlConnection) -> Result<(), DatabaseError> {
+        Self::create_schema_migrations_table(conn).await?;
+
+        let applied = Self::fetch_applied_migrations(conn).await?;
+
+        for migration in MIGRATIONS {
+            let checksum = migration.checksum();
+            match applied.iter().find(|status| status.version == migration.version) {
+                Some(status) => {
+                    if status.checksum != checksum {
+                        return Err(DatabaseError::Migration(format!(
+                            "Checksum mismatch for applied migration {:04} ({}). Migrations must not be edited once applied!",
+                            migration.version, migration.name)));
+                    }
+                },
+                None => {
+                    let applied_steps = Self::fetch_applied_steps(conn, migration.version).await?;
+                    if applied_steps.is_empty() {
+                        println!("Applying migration {:04} ({})", migration.version, migration.name);
+                    } else {
+                        println!("Resuming migration {:04} ({}) after step {}", migration.version, migration.name, applied_steps.len());
+                    }
+
+                    for (step, statement) in migration.statements.iter().enumerate() {
+                        let step = step as u32;
+                        if applied_steps.contains(&step) {
+                            continue;
+                        }
+                        conn.execute(*statement).await?;
+                        sqlx::query("INSERT INTO schema_migration_steps (version, step, applied_at) VALUES (?, ?, ?)")
+                            .bind(migration.version)
+                            .bind(step)
+                            .bind(chrono::Local::now().timestamp())
+                            .execute(&mut *conn).await?;
+                    }
+                    sqlx::query("INSERT INTO schema_migrations (version, name, checksum, applied_at) VALUES (?, ?, ?, ?)")
+                        .bind(migration.version)
+                        .bind(migration.name)
+                        .bind(&checksum)
+                        .bind(chrono::Local::now().timestamp())
+                        .execute(&mut *conn).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn create_schema_migrations_table(conn: &mut MySqlConnection) -> Result<(), DatabaseError> {
+        conn.execute(r#"
+            create table if not exists schema_migrations
+            (
+                version     int unsigned  not null,
+                name        varchar(128)  not null,
+                checksum    char(64)      not null,
+                applied_at  bigint        not null,
+                primary key (version)
+            );
+        "#).await?;
+        conn.execute(r#"
+            create table if not exists schema_migration_steps
+            (
+                version     int unsigned  not null,
+                step        int unsigned  not null,
+                applied_at  bigint        not null,
+                primary key (version, step)
+            );
+        "#).await?;
+        Ok(())
+    }
+
+    async fn fetch_applied_steps(conn: &mut MySqlConnection, version: u32) -> Result<Vec<u32>, DatabaseError> {
+        let result: Vec<MySqlRow> = sqlx::query("SELECT step FROM schema_migration_steps WHERE version=? ORDER BY step")
+            .bind(version)
+            .fetch_all(&mut *conn).await?;
+
+        Ok(result.iter().map(|row| row.get_unchecked::<u32, &str>("step")).collect())
+    }
+
+    async fn fetch_applied_migrations(conn: &mut MySqlConnection) -> Result<Vec<MigrationStatus>, DatabaseError> {
+        let result: Vec<MySqlRow> = sqlx::query("SELECT version, name, checksum, applied_at FROM schema_migrations ORDER BY version")
+            .fetch_all(&mut *conn).await?;
+
+        Ok(result.iter().map(|row| MigrationStatus {
+            version: row.get_unchecked::<u32, &str>("version"),
+            name: row.get_unchecked::<String, &str>("name"),
+            checksum: row.get_unchecked::<String, &str>("checksum"),
+            applied_at: Some(row.get_unchecked::<i64, &str>("applied_at")),
+        }).collect())
+    }
+
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+use sqlx::mysql::MySqlRow;
+use sqlx::Row;
+
+use crate::data_structs::responses::campaign_report::{CampaignReport, PromotionCodeReport};
+use crate::database::{DatabaseError, DatabasePool};
+use crate::stripe_util::RedeemedPromotionCode;
+
+impl DatabasePool {
+
+    /// Remembers a stripe promotion code so its redemptions can be reported on. Codes created
+    /// in the stripe dashboard are picked up the first time they're redeemed.
+    pub async fn save_promotion_code(&self, promotion_code: &RedeemedPromotionCode, created_by: Option<&str>) -> Result<(), DatabaseError> {
+        sqlx::query(r#"
+            INSERT INTO promotion_codes
+            (promotion_code_id, code, coupon_id, campaign, active, created_by, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON DUPLICATE KEY UPDATE code=VALUES(code), campaign=VALUES(campaign), active=VALUES(active)
+        "#)
+            .bind(&promotion_code.promotion_code_id)
+            .bind(&promotion_code.code)
+            .bind(&promotion_code.coupon_id)
+            .bind(&promotion_code.campaign)
+            .bind(&promotion_code.active)
+            .bind(created_by)
+            .bind(chrono::Local::now().timestamp())
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Redemptions of every known promotion code in successful checkouts, grouped by campaign
+    pub async fn get_campaign_reports(&self) -> Result<Vec<CampaignReport>, DatabaseError> {
+        let result: Vec<MySqlRow> = sqlx::query(r#"
+            SELECT promotion_codes.promotion_code_id, promotion_codes.code, promotion_codes.coupon_id,
+                promotion_codes.campaign, promotion_codes.active,
+                COUNT(user_purchase_sessions.session_id) AS redemptions,
+                CAST(COALESCE(SUM(user_purchase_sessions.quantity), 0) AS SIGNED) AS credits_sold,
+                CAST(COALESCE(SUM(user_purchase_sessions.total), 0) AS DOUBLE) AS revenue
+            FROM promotion_codes
+            LEFT JOIN user_purchase_sessions
+                ON user_purchase_sessions.promotion_code_id = promotion_codes.promotion_code_id
+                AND user_purchase_sessions.succeeded = 1
+            GROUP BY promotion_codes.promotion_code_id
+            ORDER BY promotion_codes.campaign, promotion_codes.code
+        "#)
+            .fetch_all(&self.pool).await?;
+
+        let mut campaigns: BTreeMap<Option<String>, CampaignReport> = BTreeMap::new();
+        for row in &result {
+            let promotion_code = PromotionCodeReport {
+                promotion_code_id: row.get_unchecked::<String, &str>("promotion_code_id"),
+                code: row.get_unchecked::<String, &str>("code"),
+                coupon_id: row.get_unchecked::<String, &str>("coupon_id"),
+                active: row.get_unchecked::<bool, &str>("active"),
+                redemptions: row.get_unchecked::<i64, &str>("redemptions") as u32,
+                credits_sold: row.get_unchecked::<i64, &str>("credits_sold"),
+                revenue: row.get_unchecked::<f64, &str>("revenue"),
+            };
+
+            let campaign = row.get_unchecked::<Option<String>, &str>("campaign");
+            let report = campaigns.entry(campaign.clone()).or_insert_with(|| CampaignReport {
+                campaign,
+                redemptions: 0,
+                credits_sold: 0,
+                revenue: 0.0,
+                promotion_codes: Vec::new()
+            });
+            report.redemptions += promotion_code.redemptions;
+            report.credits_sold += promotion_code.credits_sold;
+            report.revenue += promotion_code.revenue;
+            report.promotion_codes.push(promotion_code);
+        }
+
+        Ok(campaigns.into_values().collect())
+    }
+}
@@ -0,0 +1,519 @@
+use sqlx::{MySqlConnection, Row};
+use sqlx::mysql::MySqlRow;
+
+use crate::data_structs::credit_ledger::{CreditEntryType, NewCreditEntry};
+use crate::database::{DatabaseError, DatabasePool};
+use crate::stripe_util::CheckoutDiscount;
+
+/// A checkout session locked while its outcome is recorded
+struct PendingPurchase {
+    kerberos_username: String,
+    quantity: i64,
+    processed: bool,
+}
+
+/// How a checkout session ended, written to its row in one go
+struct PurchaseOutcome<'a> {
+    succeeded: bool,
+    /// in dollars, after the discount
+    total: Option<f64>,
+    coupon: Option<&'a str>,
+    promotion_code_id: Option<&'a str>,
+    finish_timestamp: i64,
+}
+
+/// A successful purchase locked for updating, found by its stripe payment intent
+struct LockedPurchase {
+    session_id: String,
+    kerberos_username: String,
+    quantity: i64,
+    credits_refunded: i64,
+    credits_disputed: i64,
+    dispute_id: Option<String>,
+    dispute_closed_at: Option<i64>,
+}
+
+/// A checkout session that hasn't been checked against stripe yet
+#[derive(Debug)]
+pub struct UnreconciledPurchase {
+    pub session_id: String,
+    pub kerberos_username: String,
+    pub quantity: i64,
+    pub total: Option<f64>,
+    pub succeeded: bool,
+    pub processed: bool,
+    pub begin_timestamp: i64,
+}
+
+impl DatabasePool {
+
+    /// Records the outcome of a checkout session. A successful purchase adds the credits bought
+    /// and, if it's the user's first, settles their referral with `referral_bonus_credits`.
+    /// The discount is kept so promotion code redemptions can be reported on. A session that
+    /// was already closed is left as it is, stripe may report the same outcome more than once.
+    pub async fn close_purchase_session(&self, session_id: &str, success: bool, total: Option<f64>, discount: Option<&CheckoutDiscount>, referral_bonus_credits: u32) -> Result<(), DatabaseError> {
+        let promotion_code = discount.and_then(|discount| discount.promotion_code.as_ref());
+        if let Some(promotion_code) = promotion_code {
+            self.save_promotion_code(promotion_code, None).await?;
+        }
+
+        // the purchase and the credits it bought are recorded together or not at all
+        let mut tx = self.pool.begin().await?;
+
+        let purchase = match Self::lock_pending_purchase(&mut tx, session_id).await? {
+            Some(purchase) => purchase,
+            None => return Err(DatabaseError::NotFound(format!("Purchase session {} not found", session_id)))
+        };
+        if purchase.processed {
+            println!("Purchase session {} was already closed, ignoring it", session_id);
+            return Ok(());
+        }
+
+        Self::record_purchase_outcome(&mut tx, session_id, &PurchaseOutcome {
+            succeeded: success,
+            total,
+            coupon: discount.map(|discount| discount.coupon_id.as_str()),
+            promotion_code_id: promotion_code.map(|promotion_code| promotion_code.promotion_code_id.as_str()),
+            finish_timestamp: chrono::Local::now().timestamp(),
+        }).await?;
+
+        if success {
+            Self::record_credit_entry(&mut tx, NewCreditEntry {
+                purchase_session_id: Some(session_id),
+                ..NewCreditEntry::new(&purchase.kerberos_username, CreditEntryType::Purchase, purchase.quantity)
+            }).await?;
+
+            // only the first purchase can settle a referral, after that it's no longer pending
+            Self::reward_referral(&mut tx, &purchase.kerberos_username, session_id, referral_bonus_credits).await?;
+        }
+
+        tx.commit().await?;
+
+        if success {
+            self.mark_demo_over(&purchase.kerberos_username).await?;
+        }
+        Ok(())
+    }
+
+    /// Locks a checkout session so it can't be closed twice at the same time
+    async fn lock_pending_purchase(conn: &mut MySqlConnection, session_id: &str) -> Result<Option<PendingPurchase>, DatabaseError> {
+        let purchase = sqlx::query_as!(PendingPurchase, r#"
+            SELECT kerberos_username, quantity AS `quantity: i64`, processed AS `processed: bool`
+            FROM user_purchase_sessions WHERE session_id=? FOR UPDATE
+        "#, session_id)
+            .fetch_optional(&mut *conn).await?;
+        return Ok(purchase);
+    }
+
+    /// Writes how a checkout session ended to its row. This query and the one locking the row
+    /// are checked at compile time, against the database in `DATABASE_URL` when it's set and
+    /// otherwise against the metadata in `.sqlx`. After changing either of them regenerate that
+    /// with `cargo sqlx prepare` against a migrated database, `query_metadata_matches_the_schema`
+    /// fails for as long as it's out of date.
+    async fn record_purchase_outcome(conn: &mut MySqlConnection, session_id: &str, outcome: &PurchaseOutcome<'_>) -> Result<(), DatabaseError> {
+        // a purchase without a promotion code keeps whatever code it was begun with
+        sqlx::query!(r#"
+            UPDATE user_purchase_sessions
+            SET processed=1, succeeded=?, total=?, coupon=?, finish_timestamp=?,
+                promotion_code_id=COALESCE(?, promotion_code_id)
+            WHERE session_id=?
+        "#,
+            outcome.succeeded,
+            outcome.total,
+            outcome.coupon,
+            outcome.finish_timestamp,
+            outcome.promotion_code_id,
+            session_id
+        )
+            .execute(&mut *conn).await?;
+        Ok(())
+    }
+
+    /// Remembers which payment intent paid for a checkout session, refunds and disputes only
+    /// refer to the charge of the payment intent. The fingerprint of the card it was paid with
+    /// is kept to tell whether two users paid with the same card
+    pub async fn set_purchase_payment_intent(&self, session_id: &str, payment_intent_id: &str, payment_fingerprint: Option<&str>) -> Result<(), DatabaseError> {
+        sqlx::query("UPDATE user_purchase_sessions SET payment_intent_id=?, payment_fingerprint=? WHERE session_id=?")
+            .bind(payment_intent_id)
+            .bind(payment_fingerprint)
+            .bind(session_id)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Closed purchases that weren't checked against stripe yet, and open ones that were begun
+    /// before `stale_before` and may never hear back from the webhook. The ones that were never
+    /// looked at come first, then the ones that were looked at longest ago, so purchases that
+    /// are still open on stripe don't hold up the rest.
+    pub async fn get_unreconciled_purchases(&self, stale_before: i64, limit: u32) -> Result<Vec<UnreconciledPurchase>, DatabaseError> {
+        let result: Vec<MySqlRow> = sqlx::query(r#"
+            SELECT session_id, kerberos_username, quantity, total, succeeded, processed, begin_timestamp
+            FROM user_purchase_sessions
+            WHERE reconciled_at IS NULL AND session_id IS NOT NULL AND (processed=1 OR begin_timestamp < ?)
+            ORDER BY last_reconciled_at, begin_timestamp
+            LIMIT ?
+        "#)
+            .bind(&stale_before)
+            .bind(&limit)
+            .fetch_all(&self.pool).await?;
+
+        Ok(result.iter().map(|row| UnreconciledPurchase {
+            session_id: row.get_unchecked::<String, &str>("session_id"),
+            kerberos_username: row.get_unchecked::<String, &str>("kerberos_username"),
+            quantity: row.get_unchecked::<i64, &str>("quantity"),
+            total: row.get_unchecked::<Option<f32>, &str>("total").map(|total| total as f64),
+            succeeded: row.get_unchecked::<bool, &str>("succeeded"),
+            processed: row.get_unchecked::<bool, &str>("processed"),
+            begin_timestamp: row.get_unchecked::<i64, &str>("begin_timestamp"),
+        }).collect())
+    }
+
+    /// Marks a purchase as checked against stripe, along with whatever didn't match
+    pub async fn mark_purchase_reconciled(&self, session_id: &str, issue: Option<&str>) -> Result<(), DatabaseError> {
+        let issue: Option<String> = issue.map(|issue| issue.chars().take(512).collect());
+        let now = chrono::Local::now().timestamp();
+        sqlx::query("UPDATE user_purchase_sessions SET reconciled_at=?, last_reconciled_at=?, reconciliation_issue=? WHERE session_id=?")
+            .bind(&now)
+            .bind(&now)
+            .bind(&issue)
+            .bind(session_id)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Records that a purchase was looked up on stripe but couldn't be reconciled yet, which
+    /// sends it to the back of [DatabasePool::get_unreconciled_purchases]
+    pub async fn mark_purchase_checked(&self, session_id: &str) -> Result<(), DatabaseError> {
+        sqlx::query("UPDATE user_purchase_sessions SET last_reconciled_at=? WHERE session_id=?")
+            .bind(chrono::Local::now().timestamp())
+            .bind(session_id)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn lock_purchase(conn: &mut MySqlConnection, payment_intent_id: &str) -> Result<Option<LockedPurchase>, DatabaseError> {
+        let result: Option<MySqlRow> = sqlx::query(r#"
+            SELECT session_id, kerberos_username, quantity, credits_refunded, credits_disputed,
+                dispute_id, dispute_closed_at
+            FROM user_purchase_sessions
+            WHERE payment_intent_id=? AND succeeded=1
+            FOR UPDATE
+        "#)
+            .bind(payment_intent_id)
+            .fetch_optional(&mut *conn).await?;
+
+        return Ok(result.map(|row| LockedPurchase {
+            session_id: row.get_unchecked::<String, &str>("session_id"),
+            kerberos_username: row.get_unchecked::<String, &str>("kerberos_username"),
+            quantity: row.get_unchecked::<i64, &str>("quantity"),
+            credits_refunded: row.get_unchecked::<i64, &str>("credits_refunded"),
+            credits_disputed: row.get_unchecked::<i64, &str>("credits_disputed"),
+            dispute_id: row.get_unchecked::<Option<String>, &str>("dispute_id"),
+            dispute_closed_at: row.get_unchecked::<Option<i64>, &str>("dispute_closed_at"),
+        }));
+    }
+
+    /// Takes back the credits of the refunded share of a purchase. Stripe reports the total
+    /// refunded so far, so repeated or partial refunds only handle the share that wasn't handled
+    /// yet. A partial refund covers the credits it fully paid for, a full refund all of them.
+    /// Credits a live session was holding are taken from it, ones that were already spent stay
+    /// spent and are never taken from a later purchase instead. Returns the credits that were
+    /// taken back, `None` if the purchase isn't known.
+    pub async fn refund_purchase(&self, payment_intent_id: &str, amount: i64, amount_refunded: i64, fully_refunded: bool) -> Result<Option<i64>, DatabaseError> {
+        let mut tx = self.pool.begin().await?;
+
+        let purchase = match Self::lock_purchase(&mut tx, payment_intent_id).await? {
+            Some(purchase) => purchase,
+            None => return Ok(None)
+        };
+
+        let refunded_credits = if fully_refunded || amount <= 0 {
+            purchase.quantity
+        } else {
+            purchase.quantity * amount_refunded.min(amount) / amount
+        };
+        // credits held for a dispute were already taken back
+        let to_take = (refunded_credits - purchase.credits_refunded)
+            .min(purchase.quantity - purchase.credits_refunded - purchase.credits_disputed);
+        if to_take <= 0 {
+            return Ok(Some(0));
+        }
+
+        let taken = -Self::record_credit_entry(&mut tx, NewCreditEntry {
+            purchase_session_id: Some(&purchase.session_id),
+            note: Some("Purchase refunded"),
+            ..NewCreditEntry::new(&purchase.kerberos_username, CreditEntryType::Refund, -to_take)
+        }).await?;
+        Self::release_unbacked_reservations(&mut tx, &purchase.kerberos_username).await?;
+
+        // the whole share counts as refunded even if some of it was already spent, otherwise the
+        // next refund event would take the spent credits out of whatever the user bought since
+        sqlx::query(r#"
+            UPDATE user_purchase_sessions
+            SET credits_refunded=credits_refunded+?, credits_clawed_back=credits_clawed_back+?
+            WHERE session_id=?
+        "#)
+            .bind(&to_take)
+            .bind(&taken)
+            .bind(&purchase.session_id)
+            .execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(Some(taken))
+    }
+
+    /// Holds back the credits of a disputed purchase until the dispute is closed. Returns the
+    /// credits that were held, `None` if the purchase isn't known.
+    pub async fn open_purchase_dispute(&self, payment_intent_id: &str, dispute_id: &str) -> Result<Option<i64>, DatabaseError> {
+        let mut tx = self.pool.begin().await?;
+
+        let purchase = match Self::lock_purchase(&mut tx, payment_intent_id).await? {
+            Some(purchase) => purchase,
+            None => return Ok(None)
+        };
+        // stripe may deliver the same event more than once
+        if purchase.dispute_id.as_deref() == Some(dispute_id) {
+            return Ok(Some(purchase.credits_disputed));
+        }
+
+        let to_hold = purchase.quantity - purchase.credits_refunded - purchase.credits_disputed;
+        let held = if to_hold > 0 {
+            -Self::record_credit_entry(&mut tx, NewCreditEntry {
+                purchase_session_id: Some(&purchase.session_id),
+                note: Some("Held while the purchase is disputed"),
+                ..NewCreditEntry::new(&purchase.kerberos_username, CreditEntryType::Dispute, -to_hold)
+            }).await?
+        } else {
+            0
+        };
+        Self::release_unbacked_reservations(&mut tx, &purchase.kerberos_username).await?;
+
+        // only what could actually be taken back is given back if the dispute is won
+        sqlx::query(r#"
+            UPDATE user_purchase_sessions
+            SET dispute_id=?, credits_disputed=credits_disputed+?, dispute_closed_at=NULL, dispute_won=NULL
+            WHERE session_id=?
+        "#)
+            .bind(dispute_id)
+            .bind(&held)
+            .bind(&purchase.session_id)
+            .execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(Some(held))
+    }
+
+    /// Settles a dispute, giving the held credits back if it was won. Returns the credits that
+    /// were given back, `None` if the purchase isn't known.
+    pub async fn close_purchase_dispute(&self, payment_intent_id: &str, won: bool) -> Result<Option<i64>, DatabaseError> {
+        let mut tx = self.pool.begin().await?;
+
+        let purchase = match Self::lock_purchase(&mut tx, payment_intent_id).await? {
+            Some(purchase) => purchase,
+            None => return Ok(None)
+        };
+        if purchase.dispute_id.is_none() || purchase.dispute_closed_at.is_some() {
+            return Ok(Some(0));
+        }
+
+        let returned = if won && purchase.credits_disputed > 0 {
+            Self::record_credit_entry(&mut tx, NewCreditEntry {
+                purchase_session_id: Some(&purchase.session_id),
+                note: Some("Dispute won, held credits returned"),
+                ..NewCreditEntry::new(&purchase.kerberos_username, CreditEntryType::Dispute, purchase.credits_disputed)
+            }).await?
+        } else {
+            0
+        };
+
+        sqlx::query(r#"
+            UPDATE user_purchase_sessions
+            SET credits_disputed=?, dispute_closed_at=?, dispute_won=?
+            WHERE session_id=?
+        "#)
+            .bind(if won { 0 } else { purchase.credits_disputed })
+            .bind(chrono::Local::now().timestamp())
+            .bind(&won)
+            .bind(&purchase.session_id)
+            .execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(Some(returned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use sqlx::{Column, Either, Executor, Row};
+
+    use crate::data_structs::credit_ledger::{CreditEntryType, NewCreditEntry};
+    use crate::database::DatabasePool;
+    use crate::database::test_database::TestDatabase;
+    use crate::stripe_util::CheckoutDiscount;
+
+    /// processed, succeeded, total, coupon and finish_timestamp of a purchase
+    async fn purchase_row(test_database: &TestDatabase, session_id: &str) -> (bool, bool, Option<f32>, Option<String>, Option<i64>) {
+        let row = sqlx::query(r#"
+            SELECT processed, succeeded, total, coupon, finish_timestamp
+            FROM user_purchase_sessions WHERE session_id=?
+        "#)
+            .bind(session_id)
+            .fetch_one(&test_database.database.pool).await.unwrap();
+        return (
+            row.get_unchecked::<bool, &str>("processed"),
+            row.get_unchecked::<bool, &str>("succeeded"),
+            row.get_unchecked::<Option<f32>, &str>("total"),
+            row.get_unchecked::<Option<String>, &str>("coupon"),
+            row.get_unchecked::<Option<i64>, &str>("finish_timestamp"),
+        );
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn closing_a_purchase_only_records_its_own_outcome() {
+        let test_database = TestDatabase::create().await;
+        let database = &test_database.database;
+        let now = chrono::Local::now().timestamp();
+        test_database.insert_user("alice", 0).await;
+        test_database.insert_user("bob", 0).await;
+        test_database.insert_purchase_session("alice", "cs_test_alice", 3, now - 60).await;
+        test_database.insert_purchase_session("bob", "cs_test_bob", 2, now - 60).await;
+
+        let discount = CheckoutDiscount { coupon_id: "SPRING".to_string(), promotion_code: None };
+        database.close_purchase_session("cs_test_alice", true, Some(12.0), Some(&discount), 0).await.unwrap();
+        let finished = chrono::Local::now().timestamp();
+
+        let (processed, succeeded, total, coupon, finish_timestamp) = purchase_row(&test_database, "cs_test_alice").await;
+        assert!(processed);
+        assert!(succeeded);
+        assert_eq!(total, Some(12.0));
+        assert_eq!(coupon.as_deref(), Some("SPRING"));
+        assert!(finish_timestamp.is_some_and(|finish_timestamp| finish_timestamp >= now && finish_timestamp <= finished));
+        assert_eq!(database.get_user(&"alice".to_string()).await.unwrap().current_credits, 3);
+
+        // the WHERE clause only matched the closed session
+        assert_eq!(purchase_row(&test_database, "cs_test_bob").await, (false, false, None, None, None));
+        assert_eq!(database.get_user(&"bob".to_string()).await.unwrap().current_credits, 0);
+
+        // stripe reporting the same outcome again changes nothing
+        database.close_purchase_session("cs_test_alice", true, Some(12.0), Some(&discount), 0).await.unwrap();
+        assert_eq!(database.get_user(&"alice".to_string()).await.unwrap().current_credits, 3);
+
+        database.close_purchase_session("cs_test_bob", false, None, None, 0).await.unwrap();
+        let (processed, succeeded, total, coupon, finish_timestamp) = purchase_row(&test_database, "cs_test_bob").await;
+        assert!(processed);
+        assert!(!succeeded);
+        assert_eq!((total, coupon), (None, None));
+        assert!(finish_timestamp.is_some());
+        assert_eq!(database.get_user(&"bob".to_string()).await.unwrap().current_credits, 0);
+
+        test_database.drop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn open_purchases_rotate_once_checked() {
+        let test_database = TestDatabase::create().await;
+        let database = &test_database.database;
+        let now = chrono::Local::now().timestamp();
+        test_database.insert_user("alice", 0).await;
+        test_database.insert_purchase_session("alice", "cs_test_old", 3, now - 7200).await;
+        test_database.insert_purchase_session("alice", "cs_test_new", 3, now - 3700).await;
+        test_database.insert_purchase_session("alice", "cs_test_recent", 3, now).await;
+
+        let session_ids = |limit: u32| async move {
+            database.get_unreconciled_purchases(now - 3600, limit).await.unwrap().into_iter()
+                .map(|purchase| purchase.session_id)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(session_ids(1).await, vec!["cs_test_old"]);
+
+        // still open on stripe, so the next purchase gets its turn
+        database.mark_purchase_checked("cs_test_old").await.unwrap();
+        assert_eq!(session_ids(1).await, vec!["cs_test_new"]);
+
+        database.mark_purchase_reconciled("cs_test_new", None).await.unwrap();
+        assert_eq!(session_ids(10).await, vec!["cs_test_old"]);
+
+        test_database.drop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn refunding_spent_credits_again_leaves_later_purchases_alone() {
+        let test_database = TestDatabase::create().await;
+        let database = &test_database.database;
+        let now = chrono::Local::now().timestamp();
+        test_database.insert_user("alice", 0).await;
+        test_database.insert_purchase_session("alice", "cs_test_first", 3, now - 120).await;
+        database.close_purchase_session("cs_test_first", true, Some(15.0), None, 0).await.unwrap();
+        database.set_purchase_payment_intent("cs_test_first", "pi_test_first", None).await.unwrap();
+
+        // two of the three credits are spent before the refund comes in
+        let mut conn = database.pool.acquire().await.unwrap();
+        DatabasePool::record_credit_entry(&mut conn, NewCreditEntry::new("alice", CreditEntryType::RegistrationSpend, -2)).await.unwrap();
+        drop(conn);
+
+        assert_eq!(database.refund_purchase("pi_test_first", 1500, 1500, true).await.unwrap(), Some(1));
+        assert_eq!(database.get_user(&"alice".to_string()).await.unwrap().current_credits, 0);
+
+        test_database.insert_purchase_session("alice", "cs_test_second", 4, now - 60).await;
+        database.close_purchase_session("cs_test_second", true, Some(20.0), None, 0).await.unwrap();
+
+        // a redelivered or replayed refund of the first purchase doesn't touch the second
+        assert_eq!(database.refund_purchase("pi_test_first", 1500, 1500, true).await.unwrap(), Some(0));
+        assert_eq!(database.get_user(&"alice".to_string()).await.unwrap().current_credits, 4);
+
+        let row = sqlx::query("SELECT credits_refunded, credits_clawed_back FROM user_purchase_sessions WHERE session_id=?")
+            .bind("cs_test_first")
+            .fetch_one(&database.pool).await.unwrap();
+        assert_eq!(row.get_unchecked::<i64, &str>("credits_refunded"), 3);
+        assert_eq!(row.get_unchecked::<i64, &str>("credits_clawed_back"), 1);
+
+        test_database.drop().await;
+    }
+
+    /// How sqlx prints the type of a column in `.sqlx` metadata, which is as close as a test can
+    /// get to comparing it with what the database describes
+    fn prepared_type_info(type_info: &Value) -> String {
+        let flags = match type_info["flags"].as_str().unwrap() {
+            "" => "0x0",
+            flags => flags
+        };
+        return format!("MySqlTypeInfo {{ type: {}, flags: ColumnFlags({}), char_set: {}, max_size: {:?} }}",
+                       type_info["type"].as_str().unwrap(), flags, type_info["char_set"], type_info["max_size"].as_u64());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn query_metadata_matches_the_schema() {
+        let test_database = TestDatabase::create().await;
+        let mut conn = test_database.pool().acquire().await.unwrap();
+
+        let directory = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(".sqlx");
+        for entry in std::fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let prepared: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            let describe = (&mut *conn).describe(prepared["query"].as_str().unwrap()).await.unwrap();
+            let prepared = &prepared["describe"];
+            let out_of_date = format!("{} doesn't match the schema, regenerate it with cargo sqlx prepare", path.display());
+
+            let columns = prepared["columns"].as_array().unwrap();
+            assert_eq!(describe.columns().len(), columns.len(), "{}", out_of_date);
+            for (i, column) in describe.columns().iter().enumerate() {
+                assert_eq!(column.name(), columns[i]["name"].as_str().unwrap(), "{}", out_of_date);
+                assert_eq!(format!("{:?}", column.type_info()), prepared_type_info(&columns[i]["type_info"]), "{}", out_of_date);
+                assert_eq!(describe.nullable(i), prepared["nullable"][i].as_bool(), "{}", out_of_date);
+            }
+            let parameters = match describe.parameters() {
+                Some(Either::Right(parameters)) => parameters,
+                other => panic!("mysql describes parameters by count, got {:?}", other)
+            };
+            assert_eq!(Some(parameters as u64), prepared["parameters"]["Right"].as_u64(), "{}", out_of_date);
+        }
+
+        drop(conn);
+        test_database.drop().await;
+    }
+}
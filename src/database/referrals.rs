@@ -0,0 +1,235 @@
+use sqlx::{MySqlConnection, Row};
+
+use crate::data_structs::credit_ledger::{CreditEntryType, NewCreditEntry};
+use crate::data_structs::referral::{ReferralStats, ReferralStatus};
+use crate::database::{DatabaseError, DatabasePool};
+use crate::encrypted_signing::generate_random_token;
+
+const REFERRAL_CODE_LENGTH: usize = 10;
+
+/// The address mail to `email` actually ends up at, so two spellings of the same inbox compare
+/// equal. Everything after a `+` in the local part is dropped, and gmail also ignores dots.
+fn canonical_email(email: &str) -> String {
+    let email = email.trim().to_lowercase();
+    let (local_part, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return email
+    };
+    let local_part = local_part.split('+').next().unwrap_or(local_part);
+    if domain == "gmail.com" || domain == "googlemail.com" {
+        return format!("{}@gmail.com", local_part.replace('.', ""));
+    }
+    return format!("{}@{}", local_part, domain);
+}
+
+impl DatabasePool {
+
+    /// The user's referral code, handing them one the first time it's asked for
+    pub async fn get_referral_code(&self, kerberos_username: &str) -> Result<String, DatabaseError> {
+        let result = sqlx::query("SELECT referral_code FROM users WHERE kerberos_username=?")
+            .bind(kerberos_username)
+            .fetch_optional(&self.pool).await?;
+
+        match result {
+            Some(row) => {
+                if let Some(referral_code) = row.get_unchecked::<Option<String>, &str>("referral_code") {
+                    return Ok(referral_code);
+                }
+            },
+            None => return Err(DatabaseError::NotFound(format!("User {} not found", kerberos_username)))
+        }
+
+        // codes are short enough to be typed by hand, so retry the rare collision
+        let mut attempts = 0;
+        loop {
+            let referral_code = generate_random_token(REFERRAL_CODE_LENGTH).to_uppercase();
+            let result = sqlx::query("UPDATE users SET referral_code=? WHERE kerberos_username=? AND referral_code IS NULL")
+                .bind(&referral_code)
+                .bind(kerberos_username)
+                .execute(&self.pool).await;
+
+            match result.map_err(DatabaseError::from) {
+                Ok(_) => break,
+                Err(DatabaseError::Conflict(_)) if attempts < 3 => attempts += 1,
+                Err(err) => return Err(err)
+            }
+        }
+
+        // a concurrent request may have handed out a code first, so read back whichever won
+        let row = sqlx::query("SELECT referral_code FROM users WHERE kerberos_username=?")
+            .bind(kerberos_username)
+            .fetch_one(&self.pool).await?;
+        Ok(row.get_unchecked::<String, &str>("referral_code"))
+    }
+
+    /// Tracks that a newly created user signed up with a referral code. Unknown codes are
+    /// ignored so a bad link can't stop anyone from signing up, and users referring themselves
+    /// are tracked but rejected straight away. Usernames are made up from the email, so
+    /// referrals are told apart by google account and by the inbox of the email instead.
+    pub(super) async fn record_referral(&self, referral_code: &str, referred_username: &str) -> Result<(), DatabaseError> {
+        let result = sqlx::query("SELECT kerberos_username, google_id, email FROM users WHERE referral_code=?")
+            .bind(referral_code.to_uppercase())
+            .fetch_optional(&self.pool).await?;
+
+        let referrer = match result {
+            Some(row) => row,
+            None => {
+                println!("{} signed up with unknown referral code {}", referred_username, referral_code);
+                return Ok(());
+            }
+        };
+        let referrer_username = referrer.get_unchecked::<String, &str>("kerberos_username");
+
+        let referred = sqlx::query("SELECT google_id, email FROM users WHERE kerberos_username=?")
+            .bind(referred_username)
+            .fetch_one(&self.pool).await?;
+
+        let same_google_account = referrer.get_unchecked::<Option<String>, &str>("google_id")
+            .is_some_and(|google_id| referred.get_unchecked::<Option<String>, &str>("google_id") == Some(google_id));
+        let same_inbox = referrer.get_unchecked::<Option<String>, &str>("email")
+            .zip(referred.get_unchecked::<Option<String>, &str>("email"))
+            .is_some_and(|(referrer_email, referred_email)| canonical_email(&referrer_email) == canonical_email(&referred_email));
+
+        let (status, rejected_reason) = if referrer_username == referred_username || same_google_account || same_inbox {
+            (ReferralStatus::Rejected, Some("Self referral"))
+        } else {
+            (ReferralStatus::Pending, None)
+        };
+
+        sqlx::query(r#"
+            INSERT INTO referrals
+            (referred_username, referrer_username, referral_code, status, rejected_reason, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+        "#)
+            .bind(referred_username)
+            .bind(&referrer_username)
+            .bind(referral_code.to_uppercase())
+            .bind(status.as_str())
+            .bind(rejected_reason)
+            .bind(chrono::Local::now().timestamp())
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    /// Settles the referral of a user that just made their first purchase, crediting the
+    /// referrer with the bonus. Referrals where both users ran the application on the same
+    /// device or paid with the same card are rejected instead, since that's most likely one
+    /// person with two accounts. Must be run inside the transaction of the purchase, after its
+    /// payment fingerprint was recorded.
+    pub(super) async fn reward_referral(conn: &mut MySqlConnection, referred_username: &str, purchase_session_id: &str, bonus_credits: u32) -> Result<(), DatabaseError> {
+        let result = sqlx::query("SELECT referrer_username FROM referrals WHERE referred_username=? AND status=? FOR UPDATE")
+            .bind(referred_username)
+            .bind(ReferralStatus::Pending.as_str())
+            .fetch_optional(&mut *conn).await?;
+
+        let referrer_username = match result {
+            Some(row) => row.get_unchecked::<String, &str>("referrer_username"),
+            None => return Ok(())
+        };
+
+        let same_device = sqlx::query(r#"
+            SELECT EXISTS(
+                SELECT 1 FROM application_launch_session referrer_session
+                JOIN application_launch_session referred_session
+                    ON referred_session.device_fingerprint = referrer_session.device_fingerprint
+                WHERE referrer_session.kerberos_username=? AND referred_session.kerberos_username=?
+            ) AS same_device
+        "#)
+            .bind(&referrer_username)
+            .bind(referred_username)
+            .fetch_one(&mut *conn).await?
+            .get_unchecked::<bool, &str>("same_device");
+
+        let same_card = sqlx::query(r#"
+            SELECT EXISTS(
+                SELECT 1 FROM user_purchase_sessions referrer_purchase
+                JOIN user_purchase_sessions referred_purchase
+                    ON referred_purchase.payment_fingerprint = referrer_purchase.payment_fingerprint
+                WHERE referrer_purchase.kerberos_username=? AND referred_purchase.kerberos_username=?
+            ) AS same_card
+        "#)
+            .bind(&referrer_username)
+            .bind(referred_username)
+            .fetch_one(&mut *conn).await?
+            .get_unchecked::<bool, &str>("same_card");
+
+        let now = chrono::Local::now().timestamp();
+        if same_device || same_card {
+            let rejected_reason = if same_device { "Same device" } else { "Same card" };
+            println!("Rejected referral of {} by {}: {}", referred_username, referrer_username, rejected_reason);
+            sqlx::query(r#"
+                UPDATE referrals SET status=?, rejected_reason=?, purchase_session_id=?, resolved_at=?
+                WHERE referred_username=?
+            "#)
+                .bind(ReferralStatus::Rejected.as_str())
+                .bind(rejected_reason)
+                .bind(purchase_session_id)
+                .bind(&now)
+                .bind(referred_username)
+                .execute(&mut *conn).await?;
+            return Ok(());
+        }
+
+        let note = format!("Referred {}", referred_username);
+        Self::record_credit_entry(conn, NewCreditEntry {
+            purchase_session_id: Some(purchase_session_id),
+            note: Some(&note),
+            ..NewCreditEntry::new(&referrer_username, CreditEntryType::ReferralBonus, bonus_credits as i64)
+        }).await?;
+
+        sqlx::query(r#"
+            UPDATE referrals SET status=?, bonus_credits=?, purchase_session_id=?, resolved_at=?
+            WHERE referred_username=?
+        "#)
+            .bind(ReferralStatus::Rewarded.as_str())
+            .bind(&bonus_credits)
+            .bind(purchase_session_id)
+            .bind(&now)
+            .bind(referred_username)
+            .execute(&mut *conn).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_referral_stats(&self, kerberos_username: &str) -> Result<ReferralStats, DatabaseError> {
+        let referral_code = self.get_referral_code(kerberos_username).await?;
+
+        let row = sqlx::query(r#"
+            SELECT
+                CAST(COALESCE(SUM(status='Pending'), 0) AS SIGNED) AS pending,
+                CAST(COALESCE(SUM(status='Rewarded'), 0) AS SIGNED) AS rewarded,
+                CAST(COALESCE(SUM(status='Rejected'), 0) AS SIGNED) AS rejected,
+                CAST(COALESCE(SUM(bonus_credits), 0) AS SIGNED) AS credits_earned
+            FROM referrals WHERE referrer_username=?
+        "#)
+            .bind(kerberos_username)
+            .fetch_one(&self.pool).await?;
+
+        Ok(ReferralStats {
+            referral_code,
+            pending: row.get_unchecked::<i64, &str>("pending") as u32,
+            rewarded: row.get_unchecked::<i64, &str>("rewarded") as u32,
+            rejected: row.get_unchecked::<i64, &str>("rejected") as u32,
+            credits_earned: row.get_unchecked::<i64, &str>("credits_earned"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::referrals::canonical_email;
+
+    #[test]
+    fn canonical_email_drops_tags() {
+        assert_eq!(canonical_email("Terrier+Referral@BU.edu"), "terrier@bu.edu");
+        assert_eq!(canonical_email("terrier@bu.edu"), "terrier@bu.edu");
+    }
+
+    #[test]
+    fn canonical_email_ignores_dots_on_gmail_only() {
+        assert_eq!(canonical_email("t.errier+1@googlemail.com"), "terrier@gmail.com");
+        assert_eq!(canonical_email("te.rrier@gmail.com"), canonical_email("terrier@gmail.com"));
+        assert_ne!(canonical_email("te.rrier@bu.edu"), canonical_email("terrier@bu.edu"));
+    }
+}
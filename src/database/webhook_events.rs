@@ -0,0 +1,191 @@
+use sqlx::mysql::MySqlRow;
+use sqlx::Row;
+
+use crate::data_structs::webhook_event::WebhookEventStatus;
+use crate::database::{DatabaseError, DatabasePool};
+
+// how long an event is left alone while one server instance processes it, after that it's
+// assumed the instance died and another may pick the event up
+const WEBHOOK_EVENT_LEASE_SECONDS: i64 = 5 * 60;
+// failed events are retried with an exponential backoff from one minute up to six hours,
+// and given up on after this many attempts until an admin replays them
+const MAX_WEBHOOK_EVENT_ATTEMPTS: u32 = 10;
+const MAX_WEBHOOK_EVENT_BACKOFF_SECONDS: i64 = 6 * 60 * 60;
+
+impl DatabasePool {
+
+    /// Puts a verified webhook event into the inbox. Stripe delivers events at least once, so
+    /// returns false if the event was already received before.
+    pub async fn store_webhook_event(&self, event_id: &str, event_type: &str, payload: &str) -> Result<bool, DatabaseError> {
+        let now = chrono::Local::now().timestamp();
+        // due straight away, so the retry task finds it should processing it right now fail.
+        // the connection reports found rows rather than changed ones, so a duplicate has to be
+        // ignored outright to come back as no rows affected
+        let result = sqlx::query(r#"
+            INSERT IGNORE INTO stripe_webhook_events (event_id, event_type, payload, received_at, next_attempt_at)
+            VALUES (?, ?, ?, ?, ?)
+        "#)
+            .bind(event_id)
+            .bind(event_type)
+            .bind(payload)
+            .bind(&now)
+            .bind(&now)
+            .execute(&self.pool).await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Takes an unprocessed event out of the inbox for processing and returns its payload.
+    /// Returns `None` if the event was already processed or someone else is processing it.
+    pub async fn claim_webhook_event(&self, event_id: &str) -> Result<Option<String>, DatabaseError> {
+        let now = chrono::Local::now().timestamp();
+        let result = sqlx::query(r#"
+            UPDATE stripe_webhook_events
+            SET attempts=attempts+1, locked_until=?
+            WHERE event_id=? AND processed_at IS NULL AND (locked_until IS NULL OR locked_until < ?)
+        "#)
+            .bind(now + WEBHOOK_EVENT_LEASE_SECONDS)
+            .bind(event_id)
+            .bind(&now)
+            .execute(&self.pool).await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let row: MySqlRow = sqlx::query("SELECT payload FROM stripe_webhook_events WHERE event_id=?")
+            .bind(event_id)
+            .fetch_one(&self.pool).await?;
+        Ok(Some(row.get_unchecked::<String, &str>("payload")))
+    }
+
+    pub async fn finish_webhook_event(&self, event_id: &str) -> Result<(), DatabaseError> {
+        sqlx::query(r#"
+            UPDATE stripe_webhook_events
+            SET processed_at=?, last_error=NULL, next_attempt_at=NULL, locked_until=NULL
+            WHERE event_id=?
+        "#)
+            .bind(chrono::Local::now().timestamp())
+            .bind(event_id)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Records why processing a claimed event failed and when it's retried next
+    pub async fn fail_webhook_event(&self, event_id: &str, error: &str) -> Result<(), DatabaseError> {
+        let error: String = error.chars().take(1024).collect();
+        sqlx::query(r#"
+            UPDATE stripe_webhook_events
+            SET last_error=?, locked_until=NULL,
+                next_attempt_at=IF(attempts >= ?, NULL, ? + CAST(LEAST(60 * POW(2, attempts - 1), ?) AS SIGNED))
+            WHERE event_id=?
+        "#)
+            .bind(&error)
+            .bind(MAX_WEBHOOK_EVENT_ATTEMPTS)
+            .bind(chrono::Local::now().timestamp())
+            .bind(MAX_WEBHOOK_EVENT_BACKOFF_SECONDS)
+            .bind(event_id)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Unprocessed events whose next attempt is due, oldest first
+    pub async fn get_due_webhook_events(&self, limit: u32) -> Result<Vec<String>, DatabaseError> {
+        let now = chrono::Local::now().timestamp();
+        let result: Vec<MySqlRow> = sqlx::query(r#"
+            SELECT event_id FROM stripe_webhook_events
+            WHERE processed_at IS NULL AND next_attempt_at <= ? AND (locked_until IS NULL OR locked_until < ?)
+            ORDER BY received_at
+            LIMIT ?
+        "#)
+            .bind(&now)
+            .bind(&now)
+            .bind(&limit)
+            .fetch_all(&self.pool).await?;
+
+        Ok(result.iter().map(|row| row.get_unchecked::<String, &str>("event_id")).collect())
+    }
+
+    /// Puts a stored event back into the inbox as if it was just received, processed or not
+    pub async fn reset_webhook_event(&self, event_id: &str) -> Result<(), DatabaseError> {
+        let mut tx = self.pool.begin().await?;
+        let now = chrono::Local::now().timestamp();
+
+        let result: Option<MySqlRow> = sqlx::query("SELECT locked_until FROM stripe_webhook_events WHERE event_id=? FOR UPDATE")
+            .bind(event_id)
+            .fetch_optional(&mut *tx).await?;
+        match result {
+            None => return Err(DatabaseError::NotFound(format!("No webhook event {}", event_id))),
+            Some(row) => {
+                if row.get_unchecked::<Option<i64>, &str>("locked_until").is_some_and(|locked_until| locked_until >= now) {
+                    return Err(DatabaseError::Conflict(format!("Webhook event {} is being processed right now", event_id)));
+                }
+            }
+        }
+
+        sqlx::query(r#"
+            UPDATE stripe_webhook_events
+            SET attempts=0, last_error=NULL, processed_at=NULL, next_attempt_at=?
+            WHERE event_id=?
+        "#)
+            .bind(&now)
+            .bind(event_id)
+            .execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Gives every event that ran out of attempts another round of retries. Returns how many
+    /// events that were.
+    pub async fn reset_failed_webhook_events(&self) -> Result<u64, DatabaseError> {
+        let result = sqlx::query(r#"
+            UPDATE stripe_webhook_events
+            SET attempts=0, next_attempt_at=?
+            WHERE processed_at IS NULL AND next_attempt_at IS NULL
+        "#)
+            .bind(chrono::Local::now().timestamp())
+            .execute(&self.pool).await?;
+        Ok(result.rows_affected())
+    }
+
+    /// The most recently received events, or only those whose last attempt failed
+    pub async fn get_webhook_events(&self, failed_only: bool, limit: u32) -> Result<Vec<WebhookEventStatus>, DatabaseError> {
+        let result: Vec<MySqlRow> = sqlx::query(r#"
+            SELECT event_id, event_type, received_at, attempts, last_error, next_attempt_at, processed_at
+            FROM stripe_webhook_events
+            WHERE NOT ? OR (processed_at IS NULL AND last_error IS NOT NULL)
+            ORDER BY received_at DESC
+            LIMIT ?
+        "#)
+            .bind(&failed_only)
+            .bind(&limit)
+            .fetch_all(&self.pool).await?;
+
+        Ok(result.iter().map(|row| WebhookEventStatus {
+            event_id: row.get_unchecked::<String, &str>("event_id"),
+            event_type: row.get_unchecked::<String, &str>("event_type"),
+            received_at: row.get_unchecked::<i64, &str>("received_at"),
+            attempts: row.get_unchecked::<i32, &str>("attempts") as u32,
+            last_error: row.get_unchecked::<Option<String>, &str>("last_error"),
+            next_attempt_at: row.get_unchecked::<Option<i64>, &str>("next_attempt_at"),
+            processed_at: row.get_unchecked::<Option<i64>, &str>("processed_at"),
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::test_database::TestDatabase;
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn redelivered_events_are_only_stored_once() {
+        let test_database = TestDatabase::create().await;
+        let database = &test_database.database;
+
+        assert!(database.store_webhook_event("evt_test", "charge.refunded", "{}").await.unwrap());
+        assert!(!database.store_webhook_event("evt_test", "charge.refunded", "{}").await.unwrap());
+        assert_eq!(database.claim_webhook_event("evt_test").await.unwrap().as_deref(), Some("{}"));
+
+        test_database.drop().await;
+    }
+}
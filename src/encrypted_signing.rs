@@ -4,16 +4,20 @@ use std::io::Read;
 use hmac::Hmac;
 use jwt::{AlgorithmType, Header, SignWithKey, Token, Verified, VerifyWithKey};
 use jwt::token::Signed;
+use rand::Rng;
 use ring::signature::{Ed25519KeyPair, Signature};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha2::digest::KeyInit;
-use sha2::Sha384;
+use sha2::{Digest, Sha256, Sha384};
 
 use crate::data_structs::responses::signable_data::SignableData;
 
 const ED25519_KEY_SIZE: usize = 48;
 
+/// Bumped whenever the layout of [JwtClaims] changes so tokens issued by an older server are rejected
+pub const JWT_TOKEN_VERSION: u8 = 2;
+
 #[derive(Debug)]
 pub struct Ed25519SecretKey {
     key_bytes: [u8; ED25519_KEY_SIZE],
@@ -23,14 +27,103 @@ pub struct Ed25519SecretKey {
 #[derive(Clone)]
 pub struct JWTSecretKey {
     pub secret_key: String,
+    token_lifetime: i64,
+    session_token_lifetime: i64,
+}
+
+/// The claims carried by the tokens handed out to the web dashboard
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct JwtClaims {
+    /// kerberos username of the user the token was issued to
+    pub sub: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+    pub ver: u8,
+    /// the user's token generation at the time the token was issued
+    pub gen: u32,
+}
+
+/// The claims carried by the session tokens handed to the desktop app by `/app-started`. A
+/// session token only grants access to the one session it was issued for.
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct SessionTokenClaims {
+    /// kerberos username of the user the session belongs to
+    pub sub: String,
+    /// the session id of the application launch session
+    pub sid: i64,
+    pub iat: i64,
+    pub exp: i64,
+    pub ver: u8,
 }
 
 impl JWTSecretKey {
 
-    pub fn new(secret_key: String) -> JWTSecretKey {
+    /// `token_lifetime` is how many seconds an issued web token stays valid for and
+    /// `session_token_lifetime` how long an app session token does without being renewed
+    pub fn new(secret_key: String, token_lifetime: i64, session_token_lifetime: i64) -> JWTSecretKey {
         return JWTSecretKey {
-            secret_key
+            secret_key,
+            token_lifetime,
+            session_token_lifetime
+        };
+    }
+
+    pub fn token_lifetime(&self) -> i64 {
+        self.token_lifetime
+    }
+
+    /// Issues a new signed token for the user which expires after the configured lifetime
+    pub fn issue_token(&self, kerberos_username: &str, token_generation: u32) -> String {
+        let issued_at = chrono::Local::now().timestamp();
+        let claims = JwtClaims {
+            sub: kerberos_username.to_string(),
+            iat: issued_at,
+            exp: issued_at + self.token_lifetime,
+            jti: generate_random_token(32),
+            ver: JWT_TOKEN_VERSION,
+            gen: token_generation,
+        };
+        return self.encrypt_jwt_token(claims).as_str().to_string();
+    }
+
+    /// Verifies the signature of the token and returns its claims, or None if the
+    /// token is invalid, expired or was issued with a different token version.
+    /// This does not check whether the token was revoked.
+    pub fn verify_token(&self, str_token: &str) -> Option<JwtClaims> {
+        let token = self.decrypt_jwt_token::<JwtClaims>(str_token)?;
+        let claims = token.claims();
+        if claims.ver != JWT_TOKEN_VERSION || claims.exp <= chrono::Local::now().timestamp() {
+            return None;
+        }
+        return Some(claims.clone());
+    }
+
+    /// Issues a token that authenticates the desktop app for a single launch session
+    pub fn issue_session_token(&self, kerberos_username: &str, session_id: i64) -> String {
+        let issued_at = chrono::Local::now().timestamp();
+        let claims = SessionTokenClaims {
+            sub: kerberos_username.to_string(),
+            sid: session_id,
+            iat: issued_at,
+            exp: issued_at + self.session_token_lifetime,
+            ver: JWT_TOKEN_VERSION,
         };
+        return self.encrypt_jwt_token(claims).as_str().to_string();
+    }
+
+    /// Verifies the signature and expiry of a session token and returns its claims
+    pub fn verify_session_token(&self, str_token: &str) -> Option<SessionTokenClaims> {
+        let token = self.decrypt_jwt_token::<SessionTokenClaims>(str_token)?;
+        let claims = token.claims();
+        if claims.ver != JWT_TOKEN_VERSION || claims.exp <= chrono::Local::now().timestamp() {
+            return None;
+        }
+        return Some(claims.clone());
     }
 
     pub fn encrypt_jwt_token<T: Serialize>(&self, data: T) -> Token<Header, T, Signed> {
@@ -53,6 +146,26 @@ impl JWTSecretKey {
 
 }
 
+/// A random hex string of the given length, for use as an opaque token or identifier
+pub fn generate_random_token(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..length).map(|_| format!("{:x}", rng.gen_range(0..16))).collect()
+}
+
+/// Hex encoded sha256 digest, used to store tokens without keeping the token itself around
+pub fn sha256_hex(data: &str) -> String {
+    let digest = Sha256::digest(data.as_bytes());
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compares two secrets without short-circuiting on the first differing byte
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 impl Ed25519SecretKey {
     pub fn new(priv_key_path: &str) -> Ed25519SecretKey {
         let mut priv_key: Vec<u8> = Vec::new();
@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, RwLock};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ring::signature::{RSA_PKCS1_2048_8192_SHA256, RsaPublicKeyComponents};
+use serde::Deserialize;
+
+use crate::google_oauth::GoogleUserInfo;
+
+pub const GOOGLE_JWKS_URI: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const GOOGLE_ISSUERS: [&str; 2] = ["accounts.google.com", "https://accounts.google.com"];
+
+// google rotates its keys every few days, so an unknown kid usually means a new key was
+// published. this limits how often an unknown kid can make us refetch the key set.
+const MIN_REFETCH_INTERVAL: i64 = 60;
+
+/// Where the json web key set used to verify id tokens is loaded from
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum JwksSource {
+    Remote(String),
+    /// a key set on disk, for testing against locally signed tokens
+    File(String),
+}
+
+impl JwksSource {
+
+    /// `file://` uris are read from disk, anything else is fetched over http
+    pub fn from_uri(uri: &str) -> JwksSource {
+        return match uri.strip_prefix("file://") {
+            Some(path) => JwksSource::File(path.to_string()),
+            None => JwksSource::Remote(uri.to_string())
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum IdTokenError {
+    /// The token isn't a well formed RS256 jwt
+    Malformed(String),
+    /// The token was signed with a key that isn't in the key set
+    UnknownKey(String),
+    InvalidSignature,
+    /// The signature is valid but the token wasn't meant for us, expired or has an unverified email
+    InvalidClaims(String),
+    /// The key set couldn't be loaded
+    KeySet(String),
+}
+
+impl Display for IdTokenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdTokenError::Malformed(message) => write!(f, "Malformed id token: {}", message),
+            IdTokenError::UnknownKey(kid) => write!(f, "Id token signed with unknown key {}", kid),
+            IdTokenError::InvalidSignature => write!(f, "Id token signature is invalid"),
+            IdTokenError::InvalidClaims(message) => write!(f, "Invalid id token claims: {}", message),
+            IdTokenError::KeySet(message) => write!(f, "Error loading the google key set: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for IdTokenError {}
+
+#[derive(Deserialize)]
+#[derive(Debug)]
+struct JwtHeader {
+    alg: String,
+    kid: String,
+}
+
+#[derive(Deserialize)]
+#[derive(Debug)]
+struct JsonWebKeySet {
+    keys: Vec<JsonWebKey>,
+}
+
+#[derive(Deserialize)]
+#[derive(Debug)]
+#[derive(Clone)]
+struct JsonWebKey {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+/// The claims of a google id token that we care about
+#[derive(Deserialize)]
+#[derive(Debug)]
+struct GoogleIdTokenClaims {
+    iss: String,
+    aud: String,
+    exp: i64,
+    sub: String,
+    email: String,
+    #[serde(default)]
+    email_verified: bool,
+    name: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+    picture: Option<String>,
+    locale: Option<String>,
+    hd: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct KeyCache {
+    keys: HashMap<String, JsonWebKey>,
+    fetched_at: i64,
+}
+
+/// Verifies the id tokens google hands out alongside an access token, so the user's profile can
+/// be read straight from the signed token instead of asking the userinfo endpoint for it.
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct GoogleIdTokenVerifier {
+    client_id: String,
+    source: JwksSource,
+    cache: Arc<RwLock<KeyCache>>,
+}
+
+impl GoogleIdTokenVerifier {
+
+    pub fn new(client_id: String, source: JwksSource) -> GoogleIdTokenVerifier {
+        return GoogleIdTokenVerifier {
+            client_id,
+            source,
+            cache: Arc::new(RwLock::new(KeyCache::default()))
+        };
+    }
+
+    /// Checks the signature, audience, issuer, expiry and email verification of the token and
+    /// returns the user it was issued for
+    pub async fn verify(&self, id_token: &str) -> Result<GoogleUserInfo, IdTokenError> {
+        let parts: Vec<&str> = id_token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(IdTokenError::Malformed("expected 3 segments".to_string()));
+        }
+
+        let header: JwtHeader = decode_segment(parts[0])?;
+        if header.alg != "RS256" {
+            return Err(IdTokenError::Malformed(format!("unsupported algorithm {}", header.alg)));
+        }
+
+        let key = self.get_key(&header.kid).await?;
+        let signature = URL_SAFE_NO_PAD.decode(parts[2])
+            .map_err(|err| IdTokenError::Malformed(err.to_string()))?;
+        let public_key = RsaPublicKeyComponents {
+            n: URL_SAFE_NO_PAD.decode(&key.n).map_err(|err| IdTokenError::KeySet(err.to_string()))?,
+            e: URL_SAFE_NO_PAD.decode(&key.e).map_err(|err| IdTokenError::KeySet(err.to_string()))?,
+        };
+        let signed_message = &id_token[..parts[0].len() + 1 + parts[1].len()];
+        public_key.verify(&RSA_PKCS1_2048_8192_SHA256, signed_message.as_bytes(), &signature)
+            .map_err(|_| IdTokenError::InvalidSignature)?;
+
+        let claims: GoogleIdTokenClaims = decode_segment(parts[1])?;
+        if !GOOGLE_ISSUERS.contains(&claims.iss.as_str()) {
+            return Err(IdTokenError::InvalidClaims(format!("unexpected issuer {}", claims.iss)));
+        }
+        if claims.aud != self.client_id {
+            return Err(IdTokenError::InvalidClaims(format!("unexpected audience {}", claims.aud)));
+        }
+        if claims.exp <= chrono::Local::now().timestamp() {
+            return Err(IdTokenError::InvalidClaims("token has expired".to_string()));
+        }
+        if !claims.email_verified {
+            return Err(IdTokenError::InvalidClaims("email address is not verified".to_string()));
+        }
+
+        return Ok(GoogleUserInfo {
+            id: claims.sub,
+            email: claims.email,
+            verified_email: claims.email_verified,
+            name: claims.name.unwrap_or_default(),
+            given_name: claims.given_name.unwrap_or_default(),
+            family_name: claims.family_name.unwrap_or_default(),
+            picture: claims.picture.unwrap_or_default(),
+            locale: claims.locale,
+            hd: claims.hd,
+        });
+    }
+
+    /// Looks the key up in the cache, reloading the key set if the kid isn't known yet
+    async fn get_key(&self, kid: &str) -> Result<JsonWebKey, IdTokenError> {
+        let fetched_at = {
+            let cache = self.cache.read().unwrap();
+            if let Some(key) = cache.keys.get(kid) {
+                return Ok(key.clone());
+            }
+            cache.fetched_at
+        };
+
+        let now = chrono::Local::now().timestamp();
+        if now - fetched_at < MIN_REFETCH_INTERVAL {
+            return Err(IdTokenError::UnknownKey(kid.to_string()));
+        }
+
+        let key_set = self.load_key_set().await?;
+        let mut cache = self.cache.write().unwrap();
+        cache.keys = key_set.keys.into_iter().map(|key| (key.kid.clone(), key)).collect();
+        cache.fetched_at = now;
+
+        return cache.keys.get(kid).cloned()
+            .ok_or_else(|| IdTokenError::UnknownKey(kid.to_string()));
+    }
+
+    async fn load_key_set(&self) -> Result<JsonWebKeySet, IdTokenError> {
+        let key_set = match &self.source {
+            JwksSource::Remote(uri) => {
+                reqwest::get(uri).await
+                    .map_err(|err| IdTokenError::KeySet(err.to_string()))?
+                    .text().await
+                    .map_err(|err| IdTokenError::KeySet(err.to_string()))?
+            },
+            JwksSource::File(path) => {
+                std::fs::read_to_string(path)
+                    .map_err(|err| IdTokenError::KeySet(err.to_string()))?
+            }
+        };
+        return serde_json::from_str::<JsonWebKeySet>(&key_set)
+            .map_err(|err| IdTokenError::KeySet(err.to_string()));
+    }
+}
+
+fn decode_segment<T: serde::de::DeserializeOwned>(segment: &str) -> Result<T, IdTokenError> {
+    let bytes = URL_SAFE_NO_PAD.decode(segment)
+        .map_err(|err| IdTokenError::Malformed(err.to_string()))?;
+    return serde_json::from_slice::<T>(&bytes)
+        .map_err(|err| IdTokenError::Malformed(err.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use ring::signature::{RSA_PKCS1_SHA256, RsaKeyPair};
+    use serde_json::{json, Value};
+
+    use crate::encrypted_signing::generate_random_token;
+
+    use super::*;
+
+    const CLIENT_ID: &str = "test-client.apps.googleusercontent.com";
+    // a throwaway 2048 bit key, along with its modulus and exponent for the key set
+    const TEST_KEY: &[u8] = include_bytes!("../test_data/google_test_key.pk8");
+    const TEST_KEY_COMPONENTS: &str = include_str!("../test_data/google_test_key.json");
+
+    /// A key set on disk that is removed again when the test is done with it
+    struct TestKeySet {
+        path: std::path::PathBuf,
+    }
+
+    impl TestKeySet {
+        fn new(kids: &[&str]) -> TestKeySet {
+            let path = std::env::temp_dir().join(format!("google_jwks_{}.json", generate_random_token(8)));
+            let key_set = TestKeySet { path };
+            key_set.publish(kids);
+            key_set
+        }
+
+        /// Replaces the key set with the test key under each of the given kids
+        fn publish(&self, kids: &[&str]) {
+            let components: Value = serde_json::from_str(TEST_KEY_COMPONENTS).unwrap();
+            let keys: Vec<Value> = kids.iter().map(|kid| json!({
+                "kid": kid,
+                "kty": "RSA",
+                "alg": "RS256",
+                "use": "sig",
+                "n": components["n"],
+                "e": components["e"],
+            })).collect();
+            std::fs::write(&self.path, json!({ "keys": keys }).to_string()).unwrap();
+        }
+
+        fn verifier(&self) -> GoogleIdTokenVerifier {
+            GoogleIdTokenVerifier::new(CLIENT_ID.to_string(), JwksSource::from_uri(&format!("file://{}", self.path.display())))
+        }
+    }
+
+    impl Drop for TestKeySet {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn encode(bytes: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn sign_token(kid: &str, claims: &Value) -> String {
+        let header = json!({ "alg": "RS256", "kid": kid, "typ": "JWT" });
+        let message = format!("{}.{}", encode(header.to_string().as_bytes()), encode(claims.to_string().as_bytes()));
+
+        let key_pair = RsaKeyPair::from_pkcs8(TEST_KEY).unwrap();
+        let mut signature = vec![0; key_pair.public().modulus_len()];
+        key_pair.sign(&RSA_PKCS1_SHA256, &SystemRandom::new(), message.as_bytes(), &mut signature).unwrap();
+        format!("{}.{}", message, encode(&signature))
+    }
+
+    fn valid_claims() -> Value {
+        json!({
+            "iss": "https://accounts.google.com",
+            "aud": CLIENT_ID,
+            "exp": chrono::Local::now().timestamp() + 3600,
+            "sub": "110169484474386276334",
+            "email": "rhett@bu.edu",
+            "email_verified": true,
+            "given_name": "Rhett",
+            "family_name": "Terrier",
+            "hd": "bu.edu",
+        })
+    }
+
+    #[actix_web::test]
+    async fn accepts_a_valid_token() {
+        let key_set = TestKeySet::new(&["key-1"]);
+        let user = key_set.verifier().verify(&sign_token("key-1", &valid_claims())).await.unwrap();
+
+        assert_eq!(user.id, "110169484474386276334");
+        assert_eq!(user.email, "rhett@bu.edu");
+        assert!(user.verified_email);
+        assert_eq!(user.hd.as_deref(), Some("bu.edu"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_a_tampered_token() {
+        let key_set = TestKeySet::new(&["key-1"]);
+        let token = sign_token("key-1", &valid_claims());
+        let parts: Vec<&str> = token.split('.').collect();
+        let mut claims = valid_claims();
+        claims["email"] = json!("someone.else@bu.edu");
+        let tampered = format!("{}.{}.{}", parts[0], encode(claims.to_string().as_bytes()), parts[2]);
+
+        let result = key_set.verifier().verify(&tampered).await;
+        assert!(matches!(result, Err(IdTokenError::InvalidSignature)), "{:?}", result);
+    }
+
+    #[actix_web::test]
+    async fn rejects_a_token_for_another_audience() {
+        let key_set = TestKeySet::new(&["key-1"]);
+        let mut claims = valid_claims();
+        claims["aud"] = json!("someone-else.apps.googleusercontent.com");
+
+        let result = key_set.verifier().verify(&sign_token("key-1", &claims)).await;
+        assert!(matches!(result, Err(IdTokenError::InvalidClaims(_))), "{:?}", result);
+    }
+
+    #[actix_web::test]
+    async fn rejects_a_token_from_another_issuer() {
+        let key_set = TestKeySet::new(&["key-1"]);
+        let mut claims = valid_claims();
+        claims["iss"] = json!("https://accounts.example.com");
+
+        let result = key_set.verifier().verify(&sign_token("key-1", &claims)).await;
+        assert!(matches!(result, Err(IdTokenError::InvalidClaims(_))), "{:?}", result);
+    }
+
+    #[actix_web::test]
+    async fn rejects_an_expired_token() {
+        let key_set = TestKeySet::new(&["key-1"]);
+        let mut claims = valid_claims();
+        claims["exp"] = json!(chrono::Local::now().timestamp() - 1);
+
+        let result = key_set.verifier().verify(&sign_token("key-1", &claims)).await;
+        assert!(matches!(result, Err(IdTokenError::InvalidClaims(_))), "{:?}", result);
+    }
+
+    #[actix_web::test]
+    async fn refetches_the_key_set_for_an_unknown_kid_at_most_once_per_interval() {
+        let key_set = TestKeySet::new(&["key-1"]);
+        let verifier = key_set.verifier();
+        verifier.verify(&sign_token("key-1", &valid_claims())).await.unwrap();
+
+        // google published a new key right after we loaded the key set
+        key_set.publish(&["key-1", "key-2"]);
+        let token = sign_token("key-2", &valid_claims());
+        let result = verifier.verify(&token).await;
+        assert!(matches!(result, Err(IdTokenError::UnknownKey(_))), "{:?}", result);
+
+        // once the interval passed, the unknown kid makes the verifier load the key set again
+        verifier.cache.write().unwrap().fetched_at -= MIN_REFETCH_INTERVAL;
+        let user = verifier.verify(&token).await.unwrap();
+        assert_eq!(user.email, "rhett@bu.edu");
+    }
+}
@@ -1,4 +1,14 @@
+use std::fmt::{Display, Formatter};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::encrypted_signing::generate_random_token;
+
+// how long the user has to complete the google consent screen
+const OAUTH_STATE_LIFETIME: i64 = 60 * 10;
 
 #[derive(Deserialize)]
 #[derive(Serialize)]
@@ -11,8 +21,51 @@ pub struct GoogleUserInfo {
     pub given_name: String,
     pub family_name: String,
     pub picture: String,
-    pub locale: String,
-    pub hd: String, // pretty sure this only exists for workspace email addresses (like @bu.edu)
+    pub locale: Option<String>,
+    pub hd: Option<String>, // only present for workspace email addresses (like @bu.edu)
+}
+
+impl GoogleUserInfo {
+
+    /// Whether the user signed in with a verified address of one of the allowed workspace domains
+    pub fn is_allowed_domain(&self, allowed_domains: &[String]) -> bool {
+        let hd = match &self.hd {
+            Some(hd) => hd.to_lowercase(),
+            None => return false
+        };
+        return self.verified_email
+            && allowed_domains.iter().any(|domain| domain.to_lowercase() == hd)
+            && self.email.to_lowercase().ends_with(&format!("@{}", hd));
+    }
+}
+
+/// Why exchanging an authorization or refresh token with google failed
+#[derive(Debug)]
+pub enum TokenExchangeError {
+    /// Google turned the token down, e.g. an authorization code that is invalid, expired or
+    /// was already used
+    Rejected(String),
+    /// Google couldn't be reached or answered with something we don't understand
+    Unavailable(String),
+}
+
+impl Display for TokenExchangeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenExchangeError::Rejected(message) => write!(f, "Google rejected the token: {}", message),
+            TokenExchangeError::Unavailable(message) => write!(f, "Error exchanging the token with google: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for TokenExchangeError {}
+
+/// The body google answers a failed token exchange with
+#[derive(Deserialize)]
+#[derive(Debug)]
+struct GoogleTokenError {
+    error: String,
+    error_description: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -30,11 +83,53 @@ pub struct GoogleAccessToken {
 #[derive(serde::Deserialize)]
 pub struct GoogleAuthCode {
     pub code: String,
+    pub state: String,
     pub scope: String,
     pub authuser: String,
     pub prompt: String,
 }
 
+/// Generated for every login attempt and kept by the browser in a signed cookie until google
+/// redirects back. The `state` nonce ties the returned code to the browser that started the
+/// login, and the PKCE `code_verifier` ties it to this server.
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[derive(Clone)]
+pub struct OAuthState {
+    pub state: String,
+    pub code_verifier: String,
+    /// the referral code the user followed to the login, if any
+    #[serde(default)]
+    pub referral_code: Option<String>,
+    pub exp: i64,
+}
+
+impl OAuthState {
+
+    pub fn generate(referral_code: Option<String>) -> OAuthState {
+        return OAuthState {
+            state: generate_random_token(32),
+            // pkce verifiers must be between 43 and 128 characters long
+            code_verifier: generate_random_token(64),
+            referral_code,
+            exp: chrono::Local::now().timestamp() + OAUTH_STATE_LIFETIME,
+        };
+    }
+
+    /// The S256 PKCE challenge sent to google in place of the verifier
+    pub fn code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.exp <= chrono::Local::now().timestamp()
+    }
+
+    pub fn lifetime() -> i64 {
+        OAUTH_STATE_LIFETIME
+    }
+}
+
 #[derive(Deserialize)]
 #[derive(Debug)]
 #[derive(Clone)]
@@ -50,32 +145,34 @@ pub struct GoogleClientSecret {
 
 impl GoogleClientSecret {
 
-    pub fn create_oauth_uri(&self) -> String {
-        let uri = format!("{}?redirect_uri={}&prompt=consent&response_type=code&client_id={}&scope={}&access_type=offline",
+    pub fn create_oauth_uri(&self, oauth_state: &OAuthState) -> String {
+        let uri = format!("{}?redirect_uri={}&prompt=consent&response_type=code&client_id={}&scope={}&access_type=offline&state={}&code_challenge={}&code_challenge_method=S256",
                               self.auth_uri,
                               self.redirect_uris[1],
                               self.client_id,
-                              "openid+https://www.googleapis.com/auth/userinfo.email+https://www.googleapis.com/auth/userinfo.profile");
+                              "openid+https://www.googleapis.com/auth/userinfo.email+https://www.googleapis.com/auth/userinfo.profile",
+                              oauth_state.state,
+                              oauth_state.code_challenge());
         return uri;
     }
 
-    pub async fn get_access_token(&self, code: &str) -> GoogleAccessToken {
+    pub async fn get_access_token(&self, code: &str, code_verifier: &str) -> Result<GoogleAccessToken, TokenExchangeError> {
         let client = reqwest::Client::new();
         let response = client.post(&self.token_uri)
             .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
             .form(&[("code", code),
+                    ("code_verifier", code_verifier),
                     ("redirect_uri", self.redirect_uris[1].as_str()),
                     ("client_id", self.client_id.as_str()),
                     ("client_secret", self.client_secret.as_str()),
                     ("scope", ""),
                     ("grant_type", "authorization_code")]
             )
-            .send().await.unwrap().text().await.unwrap();
-        let response = serde_json::from_str::<GoogleAccessToken>(response.as_str()).unwrap(); //todo error handling
-        return response;
+            .send().await;
+        return Self::read_token_response(response).await;
     }
 
-    pub async fn refresh_access_token(&self, refresh_token: &str) -> GoogleAccessToken {
+    pub async fn refresh_access_token(&self, refresh_token: &str) -> Result<GoogleAccessToken, TokenExchangeError> {
         let client = reqwest::Client::new();
         let response = client.post(&self.token_uri)
             .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
@@ -83,16 +180,31 @@ impl GoogleClientSecret {
                     ("client_id", self.client_id.as_str()),
                     ("client_secret", self.client_secret.as_str()),
                     ("grant_type", "refresh_token")])
-            .send().await.unwrap().json::<GoogleAccessToken>().await.unwrap();
-        return response;
+            .send().await;
+        return Self::read_token_response(response).await;
     }
 
-    pub async fn get_user_info(&self, access_token: &str) -> GoogleUserInfo {
-        let client = reqwest::Client::new();
-        let response = client.get("https://www.googleapis.com/oauth2/v1/userinfo")
-            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", access_token))
-            .send().await.unwrap().json::<GoogleUserInfo>().await.unwrap();
-        return response;
+    async fn read_token_response(response: reqwest::Result<reqwest::Response>) -> Result<GoogleAccessToken, TokenExchangeError> {
+        let response = response.map_err(|err| TokenExchangeError::Unavailable(err.to_string()))?;
+        let status = response.status();
+        let body = response.text().await
+            .map_err(|err| TokenExchangeError::Unavailable(err.to_string()))?;
+
+        if status.is_client_error() {
+            // google answers invalid_grant for codes that are wrong, expired or were already used
+            return Err(match serde_json::from_str::<GoogleTokenError>(&body) {
+                Ok(error) => TokenExchangeError::Rejected(match error.error_description {
+                    Some(description) => format!("{} ({})", error.error, description),
+                    None => error.error
+                }),
+                Err(_) => TokenExchangeError::Rejected(format!("status {}", status))
+            });
+        }
+        if !status.is_success() {
+            return Err(TokenExchangeError::Unavailable(format!("status {}", status)));
+        }
+        return serde_json::from_str::<GoogleAccessToken>(&body)
+            .map_err(|err| TokenExchangeError::Unavailable(format!("unexpected token response: {}", err)));
     }
 
 }
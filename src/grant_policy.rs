@@ -0,0 +1,293 @@
+use crate::data_structs::grant_level::{GrantLevel, SessionCapabilities};
+use crate::data_structs::user::TrialAllowance;
+
+/// How users that haven't bought any credits may try the application, from the `demo-policy`
+/// section of the config
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct DemoPolicy {
+    /// how many sections a user may register for real before buying credits
+    pub trial_credits: u32,
+    /// how long after signing up the trial ends, `None` if it never runs out
+    pub trial_length_days: Option<u32>,
+    /// whether planner sessions are held to the trial's registration count as well. planner
+    /// registrations don't use up the trial either way, but when this is off every section may
+    /// be planned for as long as the trial lasts.
+    pub limit_planner_sessions: bool,
+}
+
+impl Default for DemoPolicy {
+    fn default() -> Self {
+        return DemoPolicy {
+            trial_credits: 1,
+            trial_length_days: None,
+            limit_planner_sessions: true
+        };
+    }
+}
+
+/// Where a user is in their trial
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct TrialState {
+    pub registration_timestamp: i64,
+    /// how many real registrations the user made during the trial
+    pub registrations_used: u32,
+    /// set once the trial was ended early, by buying credits
+    pub demo_expired_at: Option<i64>,
+}
+
+impl DemoPolicy {
+
+    pub fn trial_ends_at(&self, trial: &TrialState) -> Option<i64> {
+        return self.trial_length_days
+            .map(|days| trial.registration_timestamp + days as i64 * 60 * 60 * 24);
+    }
+
+    /// How many more sections the user may register for real during their trial
+    pub fn remaining_trial_registrations(&self, trial: &TrialState, now: i64) -> u32 {
+        if trial.demo_expired_at.is_some() || self.trial_ends_at(trial).is_some_and(|ends_at| now >= ends_at) {
+            return 0;
+        }
+        return self.trial_credits.saturating_sub(trial.registrations_used);
+    }
+
+    pub fn trial_allowance(&self, trial: &TrialState, now: i64) -> TrialAllowance {
+        return TrialAllowance {
+            registrations_remaining: self.remaining_trial_registrations(trial, now),
+            ends_at: self.trial_ends_at(trial)
+        };
+    }
+}
+
+/// Everything the grant of a new application session is decided on
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct GrantRequest {
+    /// the user's credits that aren't already held by another live session
+    pub available_credits: i64,
+    pub trial: TrialState,
+    /// planner sessions only simulate registrations, so they never cost credits
+    pub planner_session: bool,
+    /// how many course sections the session targets
+    pub section_count: u32,
+    pub now: i64,
+}
+
+/// The outcome of [decide_grant]
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy)]
+pub struct Grant {
+    pub level: GrantLevel,
+    pub capabilities: SessionCapabilities,
+    /// how many of the user's credits the session holds until it ends
+    pub reserved_credits: u32,
+}
+
+/// Decides what a new application session may do. This is the only place grants are decided,
+/// it doesn't touch the database so it can be reasoned about (and tested) on its own.
+///
+/// - A session without any target sections has nothing to do and is granted `Error`.
+/// - With credits, a planner session is granted `Full`. A real session is granted `Full` if
+///   there is a credit for every targeted section, otherwise `Partial`, which may only register
+///   as many sections as there are credits. A real session holds one credit per registration
+///   it is allowed.
+/// - Without credits, a user with trial registrations left is granted `Demo`, which may
+///   register as many sections as the trial has left. Once the trial is used up or over the
+///   session is `Expired` and may do nothing.
+/// - Unless the demo policy limits planner sessions, planner sessions without credits may plan
+///   every section while the trial lasts. Once it's over they are `Expired` like any other.
+pub fn decide_grant(policy: &DemoPolicy, request: &GrantRequest) -> Grant {
+    let section_count = request.section_count;
+
+    if section_count == 0 {
+        return Grant {
+            level: GrantLevel::Error,
+            capabilities: SessionCapabilities::none(),
+            reserved_credits: 0
+        };
+    }
+
+    if request.available_credits > 0 {
+        if request.planner_session {
+            return Grant {
+                level: GrantLevel::Full,
+                capabilities: SessionCapabilities::planner(section_count),
+                reserved_credits: 0
+            };
+        }
+
+        let affordable = request.available_credits.min(section_count as i64) as u32;
+        return Grant {
+            level: if affordable == section_count { GrantLevel::Full } else { GrantLevel::Partial },
+            capabilities: SessionCapabilities {
+                max_sections: section_count,
+                real_registrations: true,
+                allowed_registrations: affordable
+            },
+            reserved_credits: affordable
+        };
+    }
+
+    let trial_remaining = policy.remaining_trial_registrations(&request.trial, request.now);
+
+    if trial_remaining == 0 {
+        return Grant {
+            level: GrantLevel::Expired,
+            capabilities: SessionCapabilities::none(),
+            reserved_credits: 0
+        };
+    }
+
+    let capabilities = if request.planner_session && !policy.limit_planner_sessions {
+        SessionCapabilities::planner(section_count)
+    } else if request.planner_session {
+        SessionCapabilities::planner(section_count.min(trial_remaining))
+    } else {
+        SessionCapabilities {
+            max_sections: section_count,
+            real_registrations: true,
+            allowed_registrations: section_count.min(trial_remaining)
+        }
+    };
+    return Grant {
+        level: GrantLevel::Demo,
+        capabilities,
+        reserved_credits: 0
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_structs::grant_level::{GrantLevel, SessionCapabilities};
+    use crate::grant_policy::{decide_grant, DemoPolicy, Grant, GrantRequest, TrialState};
+
+    const NOW: i64 = 1_700_000_000;
+    const DAY: i64 = 60 * 60 * 24;
+
+    fn trial(registrations_used: u32) -> TrialState {
+        return TrialState {
+            registration_timestamp: NOW - DAY,
+            registrations_used,
+            demo_expired_at: None
+        };
+    }
+
+    fn request(available_credits: i64, trial: TrialState, planner_session: bool, section_count: u32) -> GrantRequest {
+        return GrantRequest {
+            available_credits,
+            trial,
+            planner_session,
+            section_count,
+            now: NOW
+        };
+    }
+
+    fn real(max_sections: u32, allowed_registrations: u32) -> SessionCapabilities {
+        return SessionCapabilities {
+            max_sections,
+            real_registrations: true,
+            allowed_registrations
+        };
+    }
+
+    fn expired() -> Grant {
+        return Grant {
+            level: GrantLevel::Expired,
+            capabilities: SessionCapabilities::none(),
+            reserved_credits: 0
+        };
+    }
+
+    fn unlimited_planning() -> DemoPolicy {
+        return DemoPolicy {
+            limit_planner_sessions: false,
+            ..DemoPolicy::default()
+        };
+    }
+
+    #[test]
+    fn no_sections_is_an_error() {
+        let grant = decide_grant(&DemoPolicy::default(), &request(5, trial(0), false, 0));
+        assert_eq!(grant, Grant { level: GrantLevel::Error, capabilities: SessionCapabilities::none(), reserved_credits: 0 });
+    }
+
+    #[test]
+    fn planner_session_with_credits_is_full_and_reserves_nothing() {
+        let grant = decide_grant(&DemoPolicy::default(), &request(1, trial(1), true, 4));
+        assert_eq!(grant, Grant { level: GrantLevel::Full, capabilities: SessionCapabilities::planner(4), reserved_credits: 0 });
+    }
+
+    #[test]
+    fn enough_credits_is_full() {
+        let grant = decide_grant(&DemoPolicy::default(), &request(5, trial(1), false, 3));
+        assert_eq!(grant, Grant { level: GrantLevel::Full, capabilities: real(3, 3), reserved_credits: 3 });
+    }
+
+    #[test]
+    fn too_few_credits_is_partial() {
+        let grant = decide_grant(&DemoPolicy::default(), &request(2, trial(1), false, 3));
+        assert_eq!(grant, Grant { level: GrantLevel::Partial, capabilities: real(3, 2), reserved_credits: 2 });
+    }
+
+    #[test]
+    fn trial_left_is_demo() {
+        let policy = DemoPolicy { trial_credits: 2, ..DemoPolicy::default() };
+
+        let grant = decide_grant(&policy, &request(0, trial(0), false, 3));
+        assert_eq!(grant, Grant { level: GrantLevel::Demo, capabilities: real(3, 2), reserved_credits: 0 });
+
+        let grant = decide_grant(&policy, &request(0, trial(1), false, 3));
+        assert_eq!(grant, Grant { level: GrantLevel::Demo, capabilities: real(3, 1), reserved_credits: 0 });
+    }
+
+    #[test]
+    fn limited_planner_session_is_held_to_the_trial() {
+        let policy = DemoPolicy { trial_credits: 2, ..DemoPolicy::default() };
+        let grant = decide_grant(&policy, &request(0, trial(0), true, 3));
+        assert_eq!(grant, Grant { level: GrantLevel::Demo, capabilities: SessionCapabilities::planner(2), reserved_credits: 0 });
+    }
+
+    #[test]
+    fn unlimited_planner_session_plans_every_section_during_the_trial() {
+        let grant = decide_grant(&unlimited_planning(), &request(0, trial(0), true, 3));
+        assert_eq!(grant, Grant { level: GrantLevel::Demo, capabilities: SessionCapabilities::planner(3), reserved_credits: 0 });
+    }
+
+    #[test]
+    fn used_up_trial_is_expired() {
+        assert_eq!(decide_grant(&DemoPolicy::default(), &request(0, trial(1), false, 3)), expired());
+        assert_eq!(decide_grant(&DemoPolicy::default(), &request(0, trial(1), true, 3)), expired());
+        // credits held by another session don't count
+        assert_eq!(decide_grant(&DemoPolicy::default(), &request(-1, trial(1), false, 3)), expired());
+    }
+
+    #[test]
+    fn unlimited_planner_session_is_expired_without_capabilities_after_the_trial() {
+        assert_eq!(decide_grant(&unlimited_planning(), &request(0, trial(1), true, 3)), expired());
+    }
+
+    #[test]
+    fn trial_runs_out_after_its_length() {
+        let policy = DemoPolicy { trial_length_days: Some(7), ..DemoPolicy::default() };
+        let started_long_ago = TrialState { registration_timestamp: NOW - 7 * DAY, ..trial(0) };
+        let started_recently = TrialState { registration_timestamp: NOW - 7 * DAY + 1, ..trial(0) };
+
+        assert_eq!(decide_grant(&policy, &request(0, started_long_ago, false, 1)), expired());
+        assert_eq!(decide_grant(&policy, &request(0, started_recently, false, 1)).level, GrantLevel::Demo);
+    }
+
+    #[test]
+    fn trial_ends_early_once_expired() {
+        let ended = TrialState { demo_expired_at: Some(NOW - DAY), ..trial(0) };
+        assert_eq!(decide_grant(&DemoPolicy::default(), &request(0, ended, false, 1)), expired());
+    }
+
+    #[test]
+    fn trial_allowance_never_goes_negative() {
+        let policy = DemoPolicy { trial_length_days: Some(7), ..DemoPolicy::default() };
+        let allowance = policy.trial_allowance(&trial(3), NOW);
+        assert_eq!(allowance.registrations_remaining, 0);
+        assert_eq!(allowance.ends_at, Some(NOW - DAY + 7 * DAY));
+    }
+}
@@ -4,7 +4,7 @@ use std::time::{Duration, Instant};
 
 use actix_cors::Cors;
 use actix_web::{App, Handler, HttpServer, Responder, web};
-use actix_web::middleware::Logger;
+use actix_web::middleware::{from_fn, Logger};
 use actix_web::rt::time;
 use env_logger::Env;
 use futures::FutureExt;
@@ -15,22 +15,31 @@ use untrusted::{self};
 use yaml_rust::{Yaml, YamlLoader};
 use yaml_rust::yaml::Array;
 
+use api::admin_api;
 use api::app_api;
 use api::web_api;
 use database::DatabasePool;
 use encrypted_signing::Ed25519SecretKey;
 use google_oauth::GoogleClientSecretWrapper;
 
+use crate::api::api_error;
+use crate::api::replay_guard::{self, ReplayGuard, ReplayProtectionMode};
+use crate::api::session_cookies::SessionCookies;
 use crate::api::stripe_hook;
 use crate::encrypted_signing::JWTSecretKey;
+use crate::google_id_token::{GOOGLE_JWKS_URI, GoogleIdTokenVerifier, JwksSource};
 use crate::google_oauth::GoogleClientSecret;
+use crate::grant_policy::DemoPolicy;
 use crate::stripe_util::{StripeHandler, TieredPrice};
 
 pub mod database;
 mod encrypted_signing;
 mod smtp_mailing_util;
 mod google_oauth;
+mod google_id_token;
+mod grant_policy;
 mod stripe_util;
+mod stripe_reconciliation;
 mod course_list_scraper;
 
 pub mod data_structs {
@@ -38,17 +47,25 @@ pub mod data_structs {
     pub mod semester;
     pub mod device_meta;
     pub mod bu_course;
+    pub mod credit_ledger;
     pub mod grant_level;
+    pub mod license_key;
+    pub mod referral;
     pub mod app_config;
+    pub mod webhook_event;
     pub mod requests {
         pub mod application_start;
         pub mod application_stopped;
         pub mod email_send_request;
+        pub mod promotion_request;
         pub mod registration_notification;
         pub mod session_ping;
     }
     pub mod responses {
         pub mod app_start_permission;
+        pub mod campaign_report;
+        pub mod error_response;
+        pub mod new_license_key_response;
         pub mod signable_data;
         pub mod status_response;
         pub mod web_register_response;
@@ -64,7 +81,16 @@ pub struct SharedResources {
     database: DatabasePool,
     smtp_transport: SmtpTransport,
     google_client_secret: GoogleClientSecret,
+    google_id_token_verifier: GoogleIdTokenVerifier,
+    allowed_google_domains: Vec<String>,
     jwt_secret: JWTSecretKey,
+    session_cookies: SessionCookies,
+    replay_guard: ReplayGuard,
+    demo_policy: DemoPolicy,
+    /// credits a user gets once someone they referred makes their first purchase
+    referral_bonus_credits: u32,
+    /// kerberos usernames that may manage coupons and promotion codes
+    admin_users: Vec<String>,
     base_url: String,
     stripe_handler: StripeHandler,
 }
@@ -95,8 +121,38 @@ async fn load() -> Result<SharedResources, std::io::Error> {
     let user: &str = creds["username"].as_str().expect("mysql.user not found!");
     let pass: &str = creds["password"].as_str().expect("mysql.password not found!");
     let database: &str = creds["database"].as_str().expect("mysql.database not found!");
-    let database: DatabasePool = DatabasePool::new(host, port, user, pass, database).await;
-    database.init().await;
+    let database: DatabasePool = match DatabasePool::new(host, port, user, pass, database).await {
+        Ok(database) => database,
+        Err(err) => {
+            eprintln!("Unable to connect to the database: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // lists applied and pending migrations without applying anything
+    if std::env::args().any(|arg| arg == "--list-migrations") {
+        let migrations = database.list_migrations().await
+            .expect("Error listing the database migrations");
+        for migration in migrations {
+            let status = match migration.applied_at {
+                Some(applied_at) => format!("applied at {}", applied_at),
+                None => "pending".to_string()
+            };
+            println!("{:04} {} [{}] {}", migration.version, migration.name, &migration.checksum[..12], status);
+        }
+        std::process::exit(0);
+    }
+
+    database.init().await.expect("Error applying the database migrations");
+
+    // the cached balances should always match the credit ledger, if not something bypassed it
+    match database.reconcile_credit_balances().await {
+        Ok(mismatched) if !mismatched.is_empty() => {
+            eprintln!("Warning: credit balance doesn't match the credit ledger for {}", mismatched.join(", "));
+        },
+        Ok(_) => {},
+        Err(err) => eprintln!("Error reconciling credit balances: {}", err)
+    }
 
     println!("Loading Google OAuth2 Secrets");
     let oauth_config_location = &config["google-client-secret"].as_str()
@@ -105,6 +161,19 @@ async fn load() -> Result<SharedResources, std::io::Error> {
     let oauth_creds: GoogleClientSecretWrapper = serde_json::from_str::<GoogleClientSecretWrapper>(&mut buf)
         .expect("Error parsing google-client-secret file!");
     let google_client_secret: GoogleClientSecret = oauth_creds.web;
+    // where google's signing keys are loaded from, a file:// uri can be used to test with local keys
+    let google_jwks_uri: &str = config["google-jwks-uri"].as_str().unwrap_or(GOOGLE_JWKS_URI);
+    let google_id_token_verifier = GoogleIdTokenVerifier::new(
+        google_client_secret.client_id.clone(),
+        JwksSource::from_uri(google_jwks_uri)
+    );
+    // only google workspace accounts of these domains can sign up
+    let allowed_google_domains: Vec<String> = match config["google-allowed-domains"].as_vec() {
+        Some(domains) => domains.iter()
+            .map(|domain| domain.as_str().expect("google-allowed-domains must be a list of domains").to_string())
+            .collect(),
+        None => vec!["bu.edu".to_string()]
+    };
 
     println!("Loading encryption keys");
 
@@ -114,7 +183,46 @@ async fn load() -> Result<SharedResources, std::io::Error> {
 
     let jwt_secret: &str = config["jwt-secret-key"].as_str()
         .expect("jwt-secret-key not found!");
-    let jwt_secret: JWTSecretKey = JWTSecretKey::new(jwt_secret.to_string());
+    // how long a web dashboard access token stays valid for before it has to be refreshed
+    let jwt_token_lifetime: i64 = config["jwt-token-lifetime-minutes"].as_i64().unwrap_or(15) * 60;
+    // how long a desktop app session token stays valid for, it's renewed on every ping
+    let session_token_lifetime: i64 = config["session-token-lifetime-minutes"].as_i64().unwrap_or(10) * 60;
+    let jwt_secret: JWTSecretKey = JWTSecretKey::new(jwt_secret.to_string(), jwt_token_lifetime, session_token_lifetime);
+
+    // how long a web dashboard login lasts without being used, defaults to 30 days
+    let refresh_token_lifetime: i64 = config["refresh-token-lifetime-days"].as_i64().unwrap_or(30) * 60 * 60 * 24;
+    // only meant to be turned off when developing locally over plain http
+    let secure_cookies: bool = config["secure-cookies"].as_bool().unwrap_or(true);
+    let session_cookies = SessionCookies::new(secure_cookies, refresh_token_lifetime);
+
+    // replay protection of the app api, off by default until every client sends the headers
+    let replay_config: &Yaml = &config["replay-protection"];
+    let replay_mode: &str = replay_config["mode"].as_str().unwrap_or("off");
+    let replay_mode = ReplayProtectionMode::parse(replay_mode)
+        .expect("replay-protection.mode must be one of off, report or enforce!");
+    let replay_window: i64 = replay_config["window-seconds"].as_i64().unwrap_or(300);
+    let replay_guard = ReplayGuard::new(replay_mode, replay_window);
+
+    // what users without credits may do, the defaults match the original single free registration
+    let demo_config: &Yaml = &config["demo-policy"];
+    let default_demo_policy = DemoPolicy::default();
+    let demo_policy = DemoPolicy {
+        trial_credits: demo_config["trial-credits"].as_i64()
+            .map(|credits| credits as u32)
+            .unwrap_or(default_demo_policy.trial_credits),
+        trial_length_days: demo_config["trial-length-days"].as_i64().map(|days| days as u32),
+        limit_planner_sessions: demo_config["limit-planner-sessions"].as_bool()
+            .unwrap_or(default_demo_policy.limit_planner_sessions)
+    };
+
+    let referral_bonus_credits: u32 = config["referral-bonus-credits"].as_i64().unwrap_or(1) as u32;
+
+    let admin_users: Vec<String> = match config["admin-users"].as_vec() {
+        Some(admin_users) => admin_users.iter()
+            .map(|admin_user| admin_user.as_str().expect("admin-users must be a list of usernames!").to_string())
+            .collect(),
+        None => Vec::new()
+    };
 
     println!("Loading SMTP configuration");
     let smtp_config: &Yaml = &config["smtp"];
@@ -127,6 +235,8 @@ async fn load() -> Result<SharedResources, std::io::Error> {
     println!("Loading Stripe configurations");
     let stripe_config: &Yaml = &config["stripe"];
     let stripe_secret: &str = stripe_config["secret-key"].as_str().expect("stripe.secret-key not found!");
+    // only set when running against a local stand-in of the stripe api
+    let stripe_api_base: Option<String> = stripe_config["api-base"].as_str().map(|api_base| api_base.to_string());
     let stripe_webhook_secret: &str = stripe_config["webhook-signing-secret"].as_str().expect("stripe.webhook-signing-secret not found!");
     let product_id: &str = stripe_config["product-id"].as_str().expect("stripe.product-id not found!");
     let tiered_pricing: &Array = stripe_config["pricing"].as_vec().expect("stripe.pricing not found!");
@@ -136,18 +246,24 @@ async fn load() -> Result<SharedResources, std::io::Error> {
         TieredPrice::new(required_quantity as u64, price)
     }).collect();
     assert_ne!(tiered_pricing.len(), 0, "stripe.pricing must have at least 1 pricing!");
-    let stripe_handler = StripeHandler::new(stripe_secret.to_string(), stripe_webhook_secret.to_string(), product_id.parse().unwrap(), tiered_pricing);
+    let stripe_handler = StripeHandler::new(stripe_secret.to_string(), stripe_api_base, stripe_webhook_secret.to_string(), product_id.parse().unwrap(), tiered_pricing);
 
     let shared_resources = SharedResources {
         private_key,
         database,
         smtp_transport,
         google_client_secret,
+        google_id_token_verifier,
+        allowed_google_domains,
         jwt_secret,
+        session_cookies,
+        replay_guard,
+        demo_policy,
+        referral_bonus_credits,
+        admin_users,
         base_url,
         stripe_handler
     };
-    // todo: add a referral program
     return Ok(shared_resources);
 }
 
@@ -159,13 +275,21 @@ async fn main() -> std::io::Result<()> {
     let shared_resources = load().await.unwrap();
     let copied_resource_1 = shared_resources.clone();
     let copied_resource_2 = shared_resources.clone();
+    let copied_resource_3 = shared_resources.clone();
+    let copied_resource_4 = shared_resources.clone();
 
     println!("Starting cleanup task");
     tokio::spawn(async move {
         let mut interval = time::interval(Duration::from_millis(5000));
         loop {
             let cleanup_start_time = Instant::now();
-            copied_resource_1.database.cleanup_dead_sessions().await;
+            if let Err(err) = copied_resource_1.database.cleanup_dead_sessions().await {
+                eprintln!("Error cleaning up dead sessions: {}", err);
+            }
+            if let Err(err) = copied_resource_1.database.prune_revoked_tokens().await {
+                eprintln!("Error pruning revoked tokens: {}", err);
+            }
+            copied_resource_1.replay_guard.prune_nonces();
             let task_time = cleanup_start_time.elapsed().as_millis();
             // as the database grows, this task will take longer to complete
             // if it takes longer than 9 seconds, we should warn ourselves
@@ -176,6 +300,28 @@ async fn main() -> std::io::Result<()> {
         }
     });
 
+    println!("Starting purchase reconciliation task");
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(60 * 10)); //10 mins
+        loop {
+            if let Err(err) = stripe_reconciliation::reconcile_purchases(&copied_resource_4.database, &copied_resource_4.stripe_handler, copied_resource_4.referral_bonus_credits).await {
+                eprintln!("Error reconciling purchases with stripe: {}", err);
+            }
+            interval.tick().await;
+        }
+    });
+
+    println!("Starting webhook retry task");
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(30));
+        loop {
+            if let Err(err) = stripe_hook::retry_webhook_events(&copied_resource_3).await {
+                eprintln!("Error retrying webhook events: {}", err);
+            }
+            interval.tick().await;
+        }
+    });
+
     println!("Starting course scraping task");
     tokio::spawn(async move {
         let mut interval = time::interval(Duration::from_secs(60 * 60 * 3)); //3 hrs
@@ -194,12 +340,16 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new( move || {
         App::new()
             .app_data(web::Data::new(shared_resources.clone()))
+            .app_data(web::JsonConfig::default().error_handler(api_error::json_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(api_error::query_error_handler))
             .wrap(Logger::new("%a \"%r\" %s %b \"%{User-Agent}i\" %T"))
             // Enable CORS
             .wrap(
                 Cors::permissive()
             )
             .service(web::scope("/api/app/v1")
+                .wrap(from_fn(replay_guard::reject_replayed_requests))
+                .wrap(from_fn(api_error::sign_error_responses))
                 .service(app_api::app_start)
                 .service(app_api::app_stop)
                 .service(app_api::course_registered)
@@ -210,8 +360,17 @@ async fn main() -> std::io::Result<()> {
                 .service(web_api::debug_ping)
                 .service(web_api::oauth_register)
                 .service(web_api::oauth_url)
+                .service(web_api::refresh)
+                .service(web_api::logout)
+                .service(web_api::logout_everywhere)
                 .service(web_api::profile_info)
                 .service(web_api::reset_app_token)
+                .service(web_api::get_license_keys)
+                .service(web_api::create_license_key)
+                .service(web_api::rename_license_key)
+                .service(web_api::revoke_license_key)
+                .service(web_api::credit_history)
+                .service(web_api::referral_stats)
                 .service(web_api::update_user_app_settings)
                 .service(web_api::get_user_app_settings)
                 .service(web_api::add_course)
@@ -222,6 +381,16 @@ async fn main() -> std::io::Result<()> {
                 .service(web_api::pricing)
                 .service(web_api::create_checkout_session)
                 .service(web_api::payment_status)
+                .service(admin_api::create_coupon)
+                .service(admin_api::list_coupons)
+                .service(admin_api::deactivate_coupon)
+                .service(admin_api::create_promotion_code)
+                .service(admin_api::list_promotion_codes)
+                .service(admin_api::deactivate_promotion_code)
+                .service(admin_api::campaign_reports)
+                .service(admin_api::webhook_events)
+                .service(admin_api::replay_failed_webhook_events)
+                .service(admin_api::replay_webhook_event)
             )
             .service(web::scope("/api/stripe/v1")
                 .service(stripe_hook::webhook_handler)
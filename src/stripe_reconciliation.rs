@@ -0,0 +1,370 @@
+use stripe::{CheckoutSession, CheckoutSessionPaymentStatus, CheckoutSessionStatus, StripeError};
+
+use crate::api::api_error::ApiError;
+use crate::api::stripe_hook::{handle_checkout_complete, handle_checkout_expired};
+use crate::database::DatabasePool;
+use crate::database::purchases::UnreconciledPurchase;
+use crate::stripe_util::StripeHandler;
+
+// an open purchase is only looked up once the webhook had this long to report it
+const STALE_PURCHASE_SECONDS: i64 = 60 * 60;
+// how many purchases are looked up on stripe per run, so a backlog doesn't hit the rate limit
+const RECONCILIATION_BATCH_SIZE: u32 = 25;
+
+/// Checks purchases against stripe. Open purchases the webhook never reported are finished or
+/// expired the same way the webhook would have, and closed purchases whose amount or credits
+/// don't match what stripe charged for are flagged on the purchase. A purchase that couldn't
+/// be reconciled is tried again after the others had their turn.
+pub async fn reconcile_purchases(database: &DatabasePool, stripe_handler: &StripeHandler, referral_bonus_credits: u32) -> Result<(), ApiError> {
+    let stale_before = chrono::Local::now().timestamp() - STALE_PURCHASE_SECONDS;
+    for purchase in database.get_unreconciled_purchases(stale_before, RECONCILIATION_BATCH_SIZE).await? {
+        if let Err(err) = reconcile_purchase(database, stripe_handler, &purchase, referral_bonus_credits).await {
+            eprintln!("Error reconciling purchase session {}: {}", purchase.session_id, err);
+            // otherwise it would be first in line again next time and hold up the rest
+            if let Err(err) = database.mark_purchase_checked(&purchase.session_id).await {
+                eprintln!("Error marking purchase session {} as checked: {}", purchase.session_id, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn reconcile_purchase(database: &DatabasePool, stripe_handler: &StripeHandler, purchase: &UnreconciledPurchase, referral_bonus_credits: u32) -> Result<(), ApiError> {
+    let session = match stripe_handler.get_checkout_session(&purchase.session_id).await {
+        Ok(session) => session,
+        Err(StripeError::Stripe(err)) if err.http_status == 404 => {
+            // nothing can have been paid for a session stripe doesn't know
+            if !purchase.processed {
+                database.close_purchase_session(&purchase.session_id, false, None, None, referral_bonus_credits).await?;
+            }
+            return flag_purchase(database, purchase, vec!["Checkout session is unknown to stripe".to_string()]).await;
+        }
+        Err(err) => return Err(err.into())
+    };
+
+    if !purchase.processed {
+        match unreported_outcome(&session) {
+            UnreportedOutcome::Completed => {
+                println!("Finishing purchase session {} the webhook never reported", purchase.session_id);
+                handle_checkout_complete(database, stripe_handler, session.clone(), referral_bonus_credits).await?;
+            }
+            UnreportedOutcome::Expired => {
+                println!("Expiring purchase session {} the webhook never reported", purchase.session_id);
+                handle_checkout_expired(database, session.clone(), referral_bonus_credits).await?;
+            }
+            UnreportedOutcome::StillOpen => {
+                // looked at again once every other purchase had its turn
+                database.mark_purchase_checked(&purchase.session_id).await?;
+                return Ok(());
+            }
+        }
+    }
+
+    return flag_purchase(database, purchase, purchase_issues(purchase, &session)).await;
+}
+
+/// How a checkout session the webhook never reported on has ended on stripe
+#[derive(Debug, PartialEq, Eq)]
+enum UnreportedOutcome {
+    Completed,
+    Expired,
+    /// still open, or the payment is still on its way
+    StillOpen,
+}
+
+fn unreported_outcome(session: &CheckoutSession) -> UnreportedOutcome {
+    let paid = session.payment_status == CheckoutSessionPaymentStatus::Paid;
+    return match session.status {
+        Some(CheckoutSessionStatus::Complete) if paid => UnreportedOutcome::Completed,
+        Some(CheckoutSessionStatus::Expired) => UnreportedOutcome::Expired,
+        _ => UnreportedOutcome::StillOpen
+    };
+}
+
+/// Everything about a purchase that doesn't match its checkout session on stripe. A purchase
+/// that was only just finished from the session was recorded with stripe's amount, so only
+/// the credits are compared for it.
+fn purchase_issues(purchase: &UnreconciledPurchase, session: &CheckoutSession) -> Vec<String> {
+    let paid = session.payment_status == CheckoutSessionPaymentStatus::Paid;
+    let mut issues: Vec<String> = Vec::new();
+
+    if purchase.processed {
+        if purchase.succeeded != paid {
+            issues.push(format!("Recorded as {} but stripe reports the payment as {}",
+                if purchase.succeeded { "succeeded" } else { "failed" }, session.payment_status.as_str()));
+        }
+        if purchase.succeeded && paid {
+            let stripe_total = session.amount_total.map(|amount_total| amount_total as f64 / 100.0);
+            match (purchase.total, stripe_total) {
+                (Some(total), Some(stripe_total)) if (total - stripe_total).abs() < 0.005 => {}
+                (total, stripe_total) => issues.push(format!("Recorded a total of {:?} but stripe charged {:?}", total, stripe_total))
+            }
+        }
+    }
+
+    if paid {
+        let credits: u64 = session.line_items.data.iter()
+            .map(|item| item.quantity.unwrap_or(0))
+            .sum();
+        if credits as i64 != purchase.quantity {
+            issues.push(format!("Recorded {} credits but stripe charged for {}", purchase.quantity, credits));
+        }
+    }
+
+    return issues;
+}
+
+async fn flag_purchase(database: &DatabasePool, purchase: &UnreconciledPurchase, issues: Vec<String>) -> Result<(), ApiError> {
+    if issues.is_empty() {
+        database.mark_purchase_reconciled(&purchase.session_id, None).await?;
+        return Ok(());
+    }
+
+    let issue = issues.join("; ");
+    eprintln!("Purchase session {} of {} (begun at {}) doesn't match stripe: {}",
+        purchase.session_id, purchase.kerberos_username, purchase.begin_timestamp, issue);
+    database.mark_purchase_reconciled(&purchase.session_id, Some(&issue)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::{App, HttpResponse, HttpServer, web};
+    use actix_web::dev::ServerHandle;
+    use serde_json::{json, Value};
+    use sqlx::Row;
+    use stripe::ProductId;
+
+    use crate::database::purchases::UnreconciledPurchase;
+    use crate::database::test_database::TestDatabase;
+    use crate::stripe_reconciliation::{purchase_issues, reconcile_purchases, unreported_outcome, UnreportedOutcome};
+    use crate::stripe_util::StripeHandler;
+
+    /// Serves checkout sessions the way the stripe api does, on a local port. Looking up one of
+    /// the failing sessions is answered with a 500.
+    struct StandInStripe {
+        api_base: String,
+        handle: ServerHandle,
+    }
+
+    impl StandInStripe {
+
+        async fn start(sessions: Vec<Value>) -> StandInStripe {
+            return StandInStripe::start_failing(sessions, Vec::new()).await;
+        }
+
+        async fn start_failing(sessions: Vec<Value>, failing_sessions: Vec<&str>) -> StandInStripe {
+            let sessions: HashMap<String, Value> = sessions.into_iter()
+                .map(|session| (session["id"].as_str().unwrap().to_string(), session))
+                .collect();
+            let sessions = web::Data::new(sessions);
+            let failing_sessions = web::Data::new(failing_sessions.into_iter().map(String::from).collect::<Vec<String>>());
+
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(sessions.clone())
+                    .app_data(failing_sessions.clone())
+                    .route("/v1/checkout/sessions/{id}", web::get().to(get_checkout_session))
+            })
+                .workers(1)
+                .bind(("127.0.0.1", 0)).unwrap();
+            let api_base = format!("http://{}", server.addrs()[0]);
+            let server = server.run();
+            let handle = server.handle();
+            actix_web::rt::spawn(server);
+
+            StandInStripe { api_base, handle }
+        }
+
+        fn handler(&self) -> StripeHandler {
+            return StripeHandler::new("sk_test_stand_in".to_string(), Some(self.api_base.clone()),
+                                      "whsec_stand_in".to_string(), "prod_stand_in".parse::<ProductId>().unwrap(), Vec::new());
+        }
+    }
+
+    async fn get_checkout_session(sessions: web::Data<HashMap<String, Value>>, failing_sessions: web::Data<Vec<String>>, id: web::Path<String>) -> HttpResponse {
+        if failing_sessions.contains(&id) {
+            return HttpResponse::InternalServerError().json(json!({
+                "error": { "type": "api_error", "message": "Something went wrong on stripe's end" }
+            }));
+        }
+        return match sessions.get(id.as_str()) {
+            Some(session) => HttpResponse::Ok().json(session),
+            None => HttpResponse::NotFound().json(json!({
+                "error": { "type": "invalid_request_error", "code": "resource_missing", "message": "No such checkout.session" }
+            }))
+        };
+    }
+
+    fn checkout_session(id: &str, status: &str, payment_status: &str, amount_total: i64, quantity: u64) -> Value {
+        return json!({
+            "id": id,
+            "object": "checkout.session",
+            "amount_subtotal": amount_total,
+            "amount_total": amount_total,
+            "automatic_tax": { "enabled": false, "status": null },
+            "created": 1700000000,
+            "currency": "usd",
+            "custom_fields": [],
+            "custom_text": { "after_submit": null, "shipping_address": null, "submit": null, "terms_of_service_acceptance": null },
+            "expires_at": 1700086400,
+            "line_items": {
+                "object": "list",
+                "data": [{
+                    "id": "li_stand_in",
+                    "object": "item",
+                    "amount_discount": 0,
+                    "amount_subtotal": amount_total,
+                    "amount_tax": 0,
+                    "amount_total": amount_total,
+                    "currency": "usd",
+                    "description": "Registration credits",
+                    "price": null,
+                    "quantity": quantity
+                }],
+                "has_more": false,
+                "url": format!("/v1/checkout/sessions/{}/line_items", id)
+            },
+            "livemode": false,
+            "mode": "payment",
+            "payment_method_types": ["card"],
+            "payment_status": payment_status,
+            "shipping_options": [],
+            "status": status
+        });
+    }
+
+    fn purchase(session_id: &str, processed: bool, succeeded: bool, total: Option<f64>, quantity: i64) -> UnreconciledPurchase {
+        return UnreconciledPurchase {
+            session_id: session_id.to_string(),
+            kerberos_username: "terrier".to_string(),
+            quantity,
+            total,
+            succeeded,
+            processed,
+            begin_timestamp: 1700000000
+        };
+    }
+
+    #[actix_web::test]
+    async fn unreported_sessions_are_finished_the_way_stripe_ended_them() {
+        let stripe = StandInStripe::start(vec![
+            checkout_session("cs_test_complete", "complete", "paid", 1500, 3),
+            checkout_session("cs_test_expired", "expired", "unpaid", 1500, 3),
+            checkout_session("cs_test_open", "open", "unpaid", 1500, 3),
+            checkout_session("cs_test_processing", "complete", "unpaid", 1500, 3),
+        ]).await;
+        let handler = stripe.handler();
+
+        let outcome = |session_id: &'static str| {
+            let handler = &handler;
+            async move { unreported_outcome(&handler.get_checkout_session(session_id).await.unwrap()) }
+        };
+        assert_eq!(outcome("cs_test_complete").await, UnreportedOutcome::Completed);
+        assert_eq!(outcome("cs_test_expired").await, UnreportedOutcome::Expired);
+        assert_eq!(outcome("cs_test_open").await, UnreportedOutcome::StillOpen);
+        assert_eq!(outcome("cs_test_processing").await, UnreportedOutcome::StillOpen);
+
+        stripe.handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn matching_purchases_have_no_issues() {
+        let stripe = StandInStripe::start(vec![checkout_session("cs_test_paid", "complete", "paid", 1500, 3)]).await;
+        let session = stripe.handler().get_checkout_session("cs_test_paid").await.unwrap();
+
+        assert!(purchase_issues(&purchase("cs_test_paid", true, true, Some(15.0), 3), &session).is_empty());
+        // finished from the session just now, there's no total of our own to compare yet
+        assert!(purchase_issues(&purchase("cs_test_paid", false, false, None, 3), &session).is_empty());
+
+        stripe.handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn mismatched_purchases_are_flagged() {
+        let stripe = StandInStripe::start(vec![
+            checkout_session("cs_test_paid", "complete", "paid", 1500, 3),
+            checkout_session("cs_test_unpaid", "expired", "unpaid", 1500, 3),
+        ]).await;
+        let handler = stripe.handler();
+        let paid = handler.get_checkout_session("cs_test_paid").await.unwrap();
+        let unpaid = handler.get_checkout_session("cs_test_unpaid").await.unwrap();
+
+        let issues = purchase_issues(&purchase("cs_test_paid", true, true, Some(12.0), 3), &paid);
+        assert_eq!(issues, vec!["Recorded a total of Some(12.0) but stripe charged Some(15.0)".to_string()]);
+
+        let issues = purchase_issues(&purchase("cs_test_paid", true, true, Some(15.0), 5), &paid);
+        assert_eq!(issues, vec!["Recorded 5 credits but stripe charged for 3".to_string()]);
+
+        let issues = purchase_issues(&purchase("cs_test_paid", true, false, None, 3), &paid);
+        assert_eq!(issues, vec!["Recorded as failed but stripe reports the payment as paid".to_string()]);
+
+        let issues = purchase_issues(&purchase("cs_test_unpaid", true, true, Some(15.0), 3), &unpaid);
+        assert_eq!(issues, vec!["Recorded as succeeded but stripe reports the payment as unpaid".to_string()]);
+
+        stripe.handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn unknown_sessions_are_a_stripe_404() {
+        let stripe = StandInStripe::start(Vec::new()).await;
+        let result = stripe.handler().get_checkout_session("cs_test_missing").await;
+        assert!(matches!(result, Err(stripe::StripeError::Stripe(err)) if err.http_status == 404));
+
+        stripe.handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn failing_lookups_are_not_mistaken_for_unknown_sessions() {
+        let stripe = StandInStripe::start_failing(Vec::new(), vec!["cs_test_error"]).await;
+        let result = stripe.handler().get_checkout_session("cs_test_error").await;
+        assert!(matches!(result, Err(stripe::StripeError::Stripe(err)) if err.http_status == 500));
+
+        stripe.handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mysql server in TEST_DATABASE_URL"]
+    async fn reconciliation_finishes_unreported_purchases_and_moves_failed_lookups_back() {
+        let stripe = StandInStripe::start_failing(vec![
+            checkout_session("cs_test_paid", "complete", "paid", 1500, 3),
+            checkout_session("cs_test_expired", "expired", "unpaid", 1500, 3),
+        ], vec!["cs_test_error"]).await;
+        let handler = stripe.handler();
+        let test_database = TestDatabase::create().await;
+        let database = &test_database.database;
+        let now = chrono::Local::now().timestamp();
+        test_database.insert_user("terrier", 0).await;
+        // the lookup that fails is first in line
+        test_database.insert_purchase_session("terrier", "cs_test_error", 3, now - 7300).await;
+        test_database.insert_purchase_session("terrier", "cs_test_paid", 3, now - 7200).await;
+        test_database.insert_purchase_session("terrier", "cs_test_expired", 3, now - 7100).await;
+
+        reconcile_purchases(database, &handler, 0).await.unwrap();
+
+        let pool = test_database.pool();
+        let outcome = |session_id: &'static str| async move {
+            let row = sqlx::query("SELECT processed, succeeded, reconciled_at FROM user_purchase_sessions WHERE session_id=?")
+                .bind(session_id)
+                .fetch_one(pool).await.unwrap();
+            (row.get_unchecked::<bool, &str>("processed"), row.get_unchecked::<bool, &str>("succeeded"),
+             row.get_unchecked::<Option<i64>, &str>("reconciled_at").is_some())
+        };
+        assert_eq!(outcome("cs_test_paid").await, (true, true, true));
+        assert_eq!(outcome("cs_test_expired").await, (true, false, true));
+        assert_eq!(outcome("cs_test_error").await, (false, false, false));
+        assert_eq!(database.get_user(&"terrier".to_string()).await.unwrap().current_credits, 3);
+
+        // the failed lookup waits behind a purchase that wasn't looked up yet
+        test_database.insert_purchase_session("terrier", "cs_test_later", 2, now - 3700).await;
+        let session_ids: Vec<String> = database.get_unreconciled_purchases(now - 3600, 10).await.unwrap().into_iter()
+            .map(|purchase| purchase.session_id)
+            .collect();
+        assert_eq!(session_ids, vec!["cs_test_later", "cs_test_error"]);
+
+        test_database.drop().await;
+        stripe.handle.stop(false).await;
+    }
+}
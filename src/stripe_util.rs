@@ -1,10 +1,17 @@
 use serde::Serialize;
-use stripe::{CheckoutSession, CheckoutSessionMode, Client, Coupon, CreateCheckoutSession, CreateCheckoutSessionLineItems, CreateCheckoutSessionLineItemsPriceData, CreateCoupon, CreateCouponAppliesTo, CreateCustomer, Currency, Customer, CustomerId, ProductId, Timestamp, UpdateCustomer};
+use stripe::{CheckoutSession, CheckoutSessionId, CheckoutSessionMode, Client, Coupon, CouponDuration, CouponId, CreateCheckoutSession, CreateCheckoutSessionLineItems, CreateCheckoutSessionLineItemsPriceData, CreateCoupon, CreateCouponAppliesTo, CreateCustomer, Currency, Customer, CustomerId, Expandable, ListCoupons, ListPromotionCodes, Metadata, PaymentIntent, PaymentIntentId, ProductId, PromotionCode, PromotionCodeId, StripeError, UpdateCustomer, UpdatePromotionCode};
 
+use crate::data_structs::requests::promotion_request::{NewCoupon, NewPromotionCode};
 use crate::data_structs::user::User;
 
+/// The promotion code metadata key the campaign of a code is kept under
+const CAMPAIGN_METADATA_KEY: &str = "campaign";
+
 pub struct StripeHandler {
     stripe_secret_key: String,
+    /// where the stripe api is reached, `None` for stripe itself. Lets the server run against
+    /// a local stand-in of the stripe api.
+    stripe_api_base: Option<String>,
     webhook_signing_secret: String,
     stripe_client: Client,
     product_id: ProductId,
@@ -17,6 +24,49 @@ pub struct TieredPrice {
     unit_price: f64
 }
 
+/// The coupon, and the promotion code if one was typed in, that discounted a checkout session
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct CheckoutDiscount {
+    pub coupon_id: String,
+    pub promotion_code: Option<RedeemedPromotionCode>,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct RedeemedPromotionCode {
+    pub promotion_code_id: String,
+    pub code: String,
+    pub coupon_id: String,
+    pub campaign: Option<String>,
+    pub active: bool,
+}
+
+impl From<PromotionCode> for RedeemedPromotionCode {
+    fn from(promotion_code: PromotionCode) -> Self {
+        return RedeemedPromotionCode {
+            promotion_code_id: promotion_code.id.to_string(),
+            code: promotion_code.code,
+            coupon_id: promotion_code.coupon.id.to_string(),
+            campaign: promotion_code.metadata
+                .and_then(|metadata| metadata.get(CAMPAIGN_METADATA_KEY).cloned()),
+            active: promotion_code.active
+        };
+    }
+}
+
+#[derive(Serialize)]
+struct CreatePromotionCodeForm<'a> {
+    coupon: &'a str,
+    code: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_redemptions: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+    #[serde(skip_serializing_if = "Metadata::is_empty")]
+    metadata: Metadata,
+}
+
 impl TieredPrice {
     pub fn new(required_quantity: u64, unit_price: f64) -> TieredPrice {
         return TieredPrice {
@@ -26,12 +76,20 @@ impl TieredPrice {
     }
 }
 
+fn create_client(stripe_secret_key: &str, stripe_api_base: Option<&str>) -> Client {
+    return match stripe_api_base {
+        Some(stripe_api_base) => Client::from_url(stripe_api_base, stripe_secret_key),
+        None => Client::new(stripe_secret_key)
+    };
+}
+
 impl Clone for StripeHandler {
     fn clone(&self) -> Self {
         return StripeHandler {
             stripe_secret_key: self.stripe_secret_key.clone(),
+            stripe_api_base: self.stripe_api_base.clone(),
             webhook_signing_secret: self.webhook_signing_secret.to_string(),
-            stripe_client: Client::new(self.stripe_secret_key.clone()),
+            stripe_client: create_client(&self.stripe_secret_key, self.stripe_api_base.as_deref()),
             product_id: self.product_id.clone(),
             tiered_prices: self.tiered_prices.clone()
         }
@@ -40,11 +98,12 @@ impl Clone for StripeHandler {
 
 impl StripeHandler {
 
-    pub fn new(stripe_secret_key: String, webhook_signing_secret: String, product_id: ProductId, tiered_prices: Vec<TieredPrice>) -> StripeHandler {
+    pub fn new(stripe_secret_key: String, stripe_api_base: Option<String>, webhook_signing_secret: String, product_id: ProductId, tiered_prices: Vec<TieredPrice>) -> StripeHandler {
         let mut handler =  StripeHandler {
+            stripe_client: create_client(&stripe_secret_key, stripe_api_base.as_deref()),
             stripe_secret_key: stripe_secret_key.to_owned(),
+            stripe_api_base,
             webhook_signing_secret,
-            stripe_client: Client::new(stripe_secret_key.to_owned()),
             product_id,
             tiered_prices
         };
@@ -75,13 +134,13 @@ impl StripeHandler {
         return self.webhook_signing_secret.to_owned();
     }
 
-    pub async fn create_new_stripe_customer(&self, customer_full_name: &str, customer_email: &str) -> CustomerId {
+    pub async fn create_new_stripe_customer(&self, customer_full_name: &str, customer_email: Option<&str>) -> CustomerId {
 
         let customer = Customer::create(
             &self.stripe_client,
             CreateCustomer {
                 name: Some(customer_full_name),
-                email: Some(customer_email),
+                email: customer_email,
                 ..Default::default()
             },
         )
@@ -91,6 +150,15 @@ impl StripeHandler {
         customer.id
     }
 
+    /// The email address a customer was created with. Only the name is ever updated on stripe,
+    /// so for older accounts this is the google address they first signed up with.
+    pub async fn get_customer_email(&self, customer_id: &str) -> Result<Option<String>, StripeError> {
+        let customer_id: CustomerId = customer_id.parse()
+            .map_err(|_| StripeError::ClientError(format!("Invalid customer id {}", customer_id)))?;
+        let customer = Customer::retrieve(&self.stripe_client, &customer_id, &[]).await?;
+        return Ok(customer.email);
+    }
+
     pub async fn update_stripe_customer(&self, user: &User) -> CustomerId {
 
         let full_name = user.given_name.as_str().to_owned() + " " + user.family_name.as_str();
@@ -136,25 +204,112 @@ impl StripeHandler {
         return checkout_session;
     }
 
-    pub async fn create_coupon(&self, products: Vec<ProductId>, redeem_by: i64, percent_off: f64) {
-        let coupon = Coupon::create(
+    pub async fn create_coupon(&self, coupon: &NewCoupon) -> Result<Coupon, StripeError> {
+        return Coupon::create(
             &self.stripe_client,
             CreateCoupon {
                 applies_to: Option::from(CreateCouponAppliesTo {
-                    products: Option::from(
-                        products.into_iter()
-                        .map(|p: ProductId| p.as_str().to_string())
-                            .collect::<Vec<String>>()
-                    ),
+                    products: Option::from(vec![self.product_id.as_str().to_string()]),
                 }),
-                currency: Option::from(Currency::USD),
-                max_redemptions: Option::from(1),
-                percent_off: Option::from(percent_off),
-                redeem_by: Option::from(Timestamp::from(redeem_by)),
+                name: coupon.name.as_deref(),
+                currency: coupon.amount_off.map(|_| Currency::USD),
+                amount_off: coupon.amount_off,
+                percent_off: coupon.percent_off,
+                duration: Option::from(CouponDuration::Once),
+                max_redemptions: coupon.max_redemptions,
+                redeem_by: coupon.redeem_by,
                 ..Default::default()
             }
-        ).await.unwrap();
-        // todo: no endpoint for promotion codes yet but one is wip
+        ).await;
+    }
+
+    pub async fn list_coupons(&self) -> Result<Vec<Coupon>, StripeError> {
+        let mut params = ListCoupons::new();
+        params.limit = Option::from(100);
+        let coupons = Coupon::list(&self.stripe_client, &params).await?;
+        return Ok(coupons.data);
+    }
+
+    /// Stripe coupons can't be switched off, deleting one stops it from being redeemed again
+    /// but leaves the discounts it already gave alone
+    pub async fn delete_coupon(&self, coupon_id: &str) -> Result<(), StripeError> {
+        let coupon_id: CouponId = coupon_id.parse()
+            .map_err(|_| StripeError::ClientError(format!("Invalid coupon id {}", coupon_id)))?;
+        Coupon::delete(&self.stripe_client, &coupon_id).await?;
+        return Ok(());
+    }
+
+    pub async fn create_promotion_code(&self, promotion_code: &NewPromotionCode) -> Result<PromotionCode, StripeError> {
+        // this version of the stripe client can't create promotion codes, so post the form ourselves
+        let mut metadata = Metadata::new();
+        if let Some(campaign) = &promotion_code.campaign {
+            metadata.insert(CAMPAIGN_METADATA_KEY.to_string(), campaign.clone());
+        }
+        return self.stripe_client.post_form("/promotion_codes", CreatePromotionCodeForm {
+            coupon: &promotion_code.coupon_id,
+            code: &promotion_code.code,
+            max_redemptions: promotion_code.max_redemptions,
+            expires_at: promotion_code.expires_at,
+            metadata
+        }).await;
+    }
+
+    pub async fn list_promotion_codes(&self) -> Result<Vec<PromotionCode>, StripeError> {
+        let mut params = ListPromotionCodes::new();
+        params.limit = Option::from(100);
+        let promotion_codes = PromotionCode::list(&self.stripe_client, &params).await?;
+        return Ok(promotion_codes.data);
+    }
+
+    pub async fn deactivate_promotion_code(&self, promotion_code_id: &str) -> Result<PromotionCode, StripeError> {
+        let promotion_code_id: PromotionCodeId = promotion_code_id.parse()
+            .map_err(|_| StripeError::ClientError(format!("Invalid promotion code id {}", promotion_code_id)))?;
+        let mut params = UpdatePromotionCode::new();
+        params.active = Option::from(false);
+        return PromotionCode::update(&self.stripe_client, &promotion_code_id, params).await;
+    }
+
+    /// Identifies the card a payment intent was paid with, the same card always has the same
+    /// fingerprint no matter which customer uses it. `None` for payments made without a card.
+    pub async fn get_payment_fingerprint(&self, payment_intent_id: &str) -> Result<Option<String>, StripeError> {
+        let payment_intent_id: PaymentIntentId = payment_intent_id.parse()
+            .map_err(|_| StripeError::ClientError(format!("Invalid payment intent id {}", payment_intent_id)))?;
+        let payment_intent = PaymentIntent::retrieve(&self.stripe_client, &payment_intent_id, &["payment_method"]).await?;
+
+        return Ok(match payment_intent.payment_method {
+            Some(Expandable::Object(payment_method)) => payment_method.card.and_then(|card| card.fingerprint),
+            _ => None
+        });
+    }
+
+    /// A checkout session along with the line items that were bought in it
+    pub async fn get_checkout_session(&self, session_id: &str) -> Result<CheckoutSession, StripeError> {
+        let session_id: CheckoutSessionId = session_id.parse()
+            .map_err(|_| StripeError::ClientError(format!("Invalid checkout session id {}", session_id)))?;
+        return CheckoutSession::retrieve(&self.stripe_client, &session_id, &["line_items"]).await;
+    }
+
+    /// The discount that was applied to a checkout session, if any. Webhook events don't
+    /// include the discount breakdown so the session is fetched again with it expanded.
+    pub async fn get_checkout_discount(&self, session_id: &CheckoutSessionId) -> Result<Option<CheckoutDiscount>, StripeError> {
+        let session = CheckoutSession::retrieve(
+            &self.stripe_client,
+            session_id,
+            &["total_details.breakdown.discounts.discount.promotion_code"]
+        ).await?;
+
+        let discount = session.total_details
+            .and_then(|total_details| total_details.breakdown)
+            .and_then(|breakdown| breakdown.discounts.into_iter().next())
+            .map(|discount_amount| discount_amount.discount);
+
+        return Ok(discount.map(|discount| CheckoutDiscount {
+            coupon_id: discount.coupon.id.to_string(),
+            promotion_code: match discount.promotion_code {
+                Some(Expandable::Object(promotion_code)) => Some(RedeemedPromotionCode::from(*promotion_code)),
+                _ => None
+            }
+        }));
     }
 
 }